@@ -0,0 +1,154 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, NaiveDate, TimeZone};
+use futures::channel::mpsc::Sender;
+use futures::executor::block_on;
+use futures::SinkExt;
+use thiserror::Error;
+use zip::ZipArchive;
+
+use crate::walk;
+
+/// Extensions of the sidecars read next to photos. They're unpacked ahead of the photos, so
+/// they're in place by the time the photos are dated.
+const SIDECAR_EXTENSIONS: &[&str] = &["json", "xmp"];
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("Could not open {}: {1}", .0.display())]
+    Open(PathBuf, std::io::Error),
+    #[error("Could not read zip archive {}: {1}", .0.display())]
+    Zip(PathBuf, zip::result::ZipError),
+    #[error("Entry {1:?} in {} would be unpacked outside the --extract-to directory", .0.display())]
+    UnsafePath(PathBuf, String),
+    #[error("Could not unpack {} from {}: {2}", .1.display(), .0.display())]
+    Extract(PathBuf, PathBuf, std::io::Error),
+    #[error("zip archives are only sorted with --extract-to")]
+    NoExtractDir(PathBuf),
+}
+
+/// Whether `path` names a zip archive, going by its extension.
+pub fn is_archive(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+/// A zip archive and the entries in it worth unpacking.
+pub struct Listing {
+    path: PathBuf,
+    archive: ZipArchive<BufReader<File>>,
+    sidecars: Vec<usize>,
+    /// Photos and videos, in the order they're stored.
+    media: Vec<usize>,
+}
+
+impl Listing {
+    /// Reads the archive's central directory, which lists its entries without unpacking any.
+    pub fn open(path: &Path) -> Result<Listing, ArchiveError> {
+        let file = File::open(path).map_err(|e| ArchiveError::Open(path.to_path_buf(), e))?;
+        let archive = ZipArchive::new(BufReader::new(file))
+            .map_err(|e| ArchiveError::Zip(path.to_path_buf(), e))?;
+        let (mut sidecars, mut media) = (Vec::new(), Vec::new());
+        for index in 0..archive.len() {
+            let name = match archive.name_for_index(index) {
+                Some(name) if !name.ends_with('/') => Path::new(name),
+                _ => continue,
+            };
+            let is_sidecar = name
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| SIDECAR_EXTENSIONS.iter().any(|s| s.eq_ignore_ascii_case(ext)));
+            if is_sidecar {
+                sidecars.push(index);
+            } else if walk::is_supported(name) {
+                media.push(index);
+            }
+        }
+        Ok(Listing { path: path.to_path_buf(), archive, sidecars, media })
+    }
+
+    /// Number of photos and videos in the archive.
+    pub fn len(&self) -> usize {
+        self.media.len()
+    }
+
+    /// Unpacks the sidecars into `dir`, then the photos and videos one at a time, sending each
+    /// one's path (or why it couldn't be unpacked) on `tx` once it's written. This blocks: a send
+    /// waits for room in the channel, so only as many files are unpacked ahead of being sorted as
+    /// it holds, and unpacking stops if the receiver goes away. Returns every file written.
+    pub fn extract(
+        mut self,
+        dir: &Path,
+        mut tx: Sender<Result<PathBuf, ArchiveError>>,
+    ) -> Vec<PathBuf> {
+        let mut written = Vec::new();
+        for index in std::mem::take(&mut self.sidecars) {
+            // A sidecar that can't be unpacked only costs the date it might have given.
+            if let Ok(path) = self.extract_entry(index, dir) {
+                written.push(path);
+            }
+        }
+        for index in std::mem::take(&mut self.media) {
+            let result = self.extract_entry(index, dir);
+            if let Ok(path) = &result {
+                written.push(path.clone());
+            }
+            if block_on(tx.send(result)).is_err() {
+                break;
+            }
+        }
+        written
+    }
+
+    fn extract_entry(&mut self, index: usize, dir: &Path) -> Result<PathBuf, ArchiveError> {
+        let archive_path = &self.path;
+        let mut entry = self
+            .archive
+            .by_index(index)
+            .map_err(|e| ArchiveError::Zip(archive_path.clone(), e))?;
+        let name = entry.enclosed_name().ok_or_else(|| {
+            ArchiveError::UnsafePath(archive_path.clone(), entry.name().to_string())
+        })?;
+        let dest = dir.join(name);
+        let mut write = || {
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut out = File::create(&dest)?;
+            std::io::copy(&mut entry, &mut out)?;
+            // Otherwise the mtime date source would see when the file was unpacked.
+            if let Some(modified) = entry.last_modified().and_then(to_system_time) {
+                out.set_modified(modified)?;
+            }
+            Ok(())
+        };
+        if let Err(e) = write() {
+            let _ = std::fs::remove_file(&dest);
+            return Err(ArchiveError::Extract(archive_path.clone(), dest, e));
+        }
+        Ok(dest)
+    }
+}
+
+/// A zip entry's modification time, which is in local time with no zone recorded.
+fn to_system_time(time: zip::DateTime) -> Option<std::time::SystemTime> {
+    let naive = NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?
+        .and_hms_opt(time.hour().into(), time.minute().into(), time.second().into())?;
+    Some(Local.from_local_datetime(&naive).earliest()?.into())
+}
+
+/// Removes files unpacked into `dir`, and the directories they were unpacked into once those
+/// are empty.
+pub fn remove_extracted(dir: &Path, files: &[PathBuf]) {
+    for file in files {
+        let _ = std::fs::remove_file(file);
+        let mut parent = file.parent();
+        while let Some(p) = parent.filter(|p| p.starts_with(dir) && *p != dir) {
+            if std::fs::remove_dir(p).is_err() {
+                break;
+            }
+            parent = p.parent();
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use std::convert::TryFrom;
+
+use thiserror::Error;
+
+/// Brands of HEIF-based image formats, which keep EXIF in an `Exif` item: AVIF, and the HEIC
+/// files phones write.
+pub const HEIF_BRANDS: &[&[u8; 4]] = &[b"avif", b"avis", b"heic", b"heix", b"mif1", b"msf1"];
+
+/// Types of the boxes an MP4 or QuickTime movie can start with. Older QuickTime files have no
+/// `ftyp` box and go straight to the movie data.
+const MOVIE_START_BOXES: &[&[u8; 4]] = &[b"ftyp", b"moov", b"mdat", b"wide", b"free", b"skip"];
+
+/// QuickTime metadata key for when a video was recorded, in local time with a UTC offset.
+pub const QUICKTIME_CREATION_DATE: &str = "com.apple.quicktime.creationdate";
+/// QuickTime metadata keys for the make and model of the phone or camera that recorded a video.
+pub const QUICKTIME_MAKE: &str = "com.apple.quicktime.make";
+pub const QUICKTIME_MODEL: &str = "com.apple.quicktime.model";
+
+/// Seconds from the QuickTime epoch (1904-01-01) to the Unix epoch.
+const QUICKTIME_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+#[derive(Error, Debug)]
+pub enum BmffError {
+    #[error("Malformed {0} box")]
+    BadBox(String),
+    #[error("No Exif item in the meta box")]
+    NoExifItem,
+    #[error("Exif item is stored in an unsupported way (construction method {0})")]
+    UnsupportedConstruction(u16),
+    #[error("Exif item's TIFF header offset points past its end")]
+    BadExifItem,
+    #[error("No movie header (mvhd box) found")]
+    NoMovieHeader,
+    #[error("Movie header has no creation time set")]
+    NoCreationTime,
+}
+
+/// Reads big-endian fields out of a box body, front to back.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = (self.data.get(..len)?, self.data.get(len..)?);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.uint(2).map(|n| n as u16)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.uint(4).map(|n| n as u32)
+    }
+
+    /// Unsigned integer of `len` bytes (0 to 8), as used by `iloc` for its variable size fields.
+    fn uint(&mut self, len: usize) -> Option<u64> {
+        if len > 8 {
+            return None;
+        }
+        Some(self.take(len)?.iter().fold(0, |n, &b| n << 8 | u64::from(b)))
+    }
+
+    /// Version and flags at the start of a full box. The flags aren't needed for anything here.
+    fn version(&mut self) -> Option<u8> {
+        let version = self.u8()?;
+        self.take(3)?;
+        Some(version)
+    }
+}
+
+/// Size of a box header and of the whole box, from the first 16 bytes (or fewer) of it. `None`
+/// for a box that runs to the end of the file, which ISO-BMFF writes as a size of 0.
+pub fn box_size(head: &[u8]) -> Option<(u64, Option<u64>)> {
+    let mut r = Reader { data: head };
+    match r.u32()? {
+        0 => Some((8, None)),
+        1 => {
+            r.take(4)?;
+            Some((16, Some(r.uint(8)?)))
+        }
+        size => Some((8, Some(u64::from(size)))),
+    }
+}
+
+/// Iterates over the boxes packed in `data`, yielding each one's type and body. Stops at the
+/// first box that doesn't fit.
+pub fn boxes(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        let (header, size) = box_size(rest)?;
+        let size = size.unwrap_or(rest.len() as u64);
+        let (header, size) = (usize::try_from(header).ok()?, usize::try_from(size).ok()?);
+        let kind = rest.get(4..8)?;
+        let body = rest.get(header..size)?;
+        rest = &rest[size..];
+        Some((kind, body))
+    })
+}
+
+/// Whether `header` is an ISO-BMFF file whose `ftyp` box lists one of `brands`.
+pub fn has_brand(header: &[u8], brands: &[&[u8; 4]]) -> bool {
+    let ftyp = match boxes(header).next() {
+        Some((b"ftyp", body)) => body,
+        _ => return false,
+    };
+    // The major brand, then a minor version, then the compatible brands.
+    let major = ftyp.chunks_exact(4).take(1);
+    let compatible = ftyp.get(8..).unwrap_or_default().chunks_exact(4);
+    major.chain(compatible).any(|brand| brands.iter().any(|b| b[..] == *brand))
+}
+
+/// Whether `header` looks like an ISO-BMFF file such as an MP4 or QuickTime movie.
+pub fn is_bmff(header: &[u8]) -> bool {
+    header.get(4..8).is_some_and(|kind| MOVIE_START_BOXES.iter().any(|b| b[..] == *kind))
+}
+
+/// Body of the first child of `parent` with the given type.
+fn child<'a>(parent: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    boxes(parent).find(|&(k, _)| k == kind).map(|(_, body)| body)
+}
+
+/// When a movie was created according to the `mvhd` box in its `moov` box, as a Unix timestamp.
+/// Cameras set this in UTC, though some older ones wrote local time instead.
+pub fn movie_creation_time(moov: &[u8]) -> Result<i64, BmffError> {
+    let mvhd = child(moov, b"mvhd").ok_or(BmffError::NoMovieHeader)?;
+    let mut r = Reader { data: mvhd };
+    let created = match r.version() {
+        Some(0) => r.u32().map(u64::from),
+        Some(1) => r.uint(8),
+        _ => None,
+    };
+    match created.ok_or_else(|| BmffError::BadBox("mvhd".into()))? {
+        0 => Err(BmffError::NoCreationTime),
+        secs => Ok(i64::try_from(secs).unwrap_or(i64::MAX).saturating_sub(QUICKTIME_EPOCH_OFFSET)),
+    }
+}
+
+/// Text value of a QuickTime metadata `key` like [`QUICKTIME_CREATION_DATE`] (which phones
+/// write in local time, e.g. `2020-02-01T14:32:14+0100`) from the metadata in a `moov` box.
+pub fn quicktime_value<'a>(moov: &'a [u8], key: &str) -> Option<&'a str> {
+    let meta = child(moov, b"meta")?;
+    // QuickTime's meta box has no version and flags, unlike the MP4 one.
+    let meta = if meta.get(4..8) == Some(b"hdlr") { meta } else { meta.get(4..)? };
+
+    let mut keys = Reader { data: child(meta, b"keys")? };
+    keys.version()?;
+    let count = keys.u32()?;
+    // Keys are numbered from 1, and the items in ilst are boxes named after those numbers.
+    let mut index = None;
+    for i in 1..=count {
+        let size = keys.u32()? as usize;
+        // Each key is its namespace, then the name.
+        let entry = keys.take(size.checked_sub(4)?)?;
+        if entry.get(4..) == Some(key.as_bytes()) {
+            index = Some(i);
+            break;
+        }
+    }
+    let index = index?.to_be_bytes();
+    let item = child(child(meta, b"ilst")?, &index)?;
+    let mut data = Reader { data: child(item, b"data")? };
+    // Type 1 is UTF-8 text; the locale that follows doesn't matter here.
+    if data.u32()? != 1 {
+        return None;
+    }
+    data.take(4)?;
+    std::str::from_utf8(data.data).ok()
+}
+
+/// Where in the file the `Exif` item of a HEIF `meta` box is stored, as an offset and length.
+pub fn exif_location(meta: &[u8]) -> Result<(u64, u64), BmffError> {
+    let bad = |kind: &str| BmffError::BadBox(kind.into());
+    let mut r = Reader { data: meta };
+    r.version().ok_or_else(|| bad("meta"))?;
+    let item = child(r.data, b"iinf").and_then(exif_item_id).ok_or(BmffError::NoExifItem)?;
+    let iloc = child(r.data, b"iloc").ok_or_else(|| bad("meta"))?;
+    match item_location(iloc, item).ok_or_else(|| bad("iloc"))? {
+        (0, offset, length) => Ok((offset, length)),
+        (construction, ..) => Err(BmffError::UnsupportedConstruction(construction)),
+    }
+}
+
+/// ID of the `Exif` item listed in an `iinf` box, if there is one.
+fn exif_item_id(iinf: &[u8]) -> Option<u32> {
+    let mut r = Reader { data: iinf };
+    // Entry count, which the boxes that follow make redundant.
+    let count_size = if r.version()? == 0 { 2 } else { 4 };
+    r.take(count_size)?;
+    for (kind, body) in boxes(r.data) {
+        if kind != b"infe" {
+            continue;
+        }
+        let mut r = Reader { data: body };
+        // Versions before 2 predate item types, so can't describe an Exif item.
+        let id = match r.version()? {
+            2 => u32::from(r.u16()?),
+            3 => r.u32()?,
+            _ => continue,
+        };
+        r.u16()?;
+        if r.take(4)? == b"Exif" {
+            return Some(id);
+        }
+    }
+    None
+}
+
+/// Construction method, offset and length of the first extent of `item` in an `iloc` box.
+/// Construction method 0 means the offset is into the file itself. `None` when the box is
+/// malformed or doesn't list the item.
+fn item_location(iloc: &[u8], item: u32) -> Option<(u16, u64, u64)> {
+    let mut r = Reader { data: iloc };
+    let version = r.version()?;
+    let sizes = r.u8()?;
+    let (offset_size, length_size) = (usize::from(sizes >> 4), usize::from(sizes & 0xf));
+    let sizes = r.u8()?;
+    let base_offset_size = usize::from(sizes >> 4);
+    // Only versions 1 and 2 have extent indexes; in version 0 these bits are reserved.
+    let index_size = if version == 0 { 0 } else { usize::from(sizes & 0xf) };
+    let item_count = if version < 2 { u32::from(r.u16()?) } else { r.u32()? };
+    for _ in 0..item_count {
+        let id = if version < 2 { u32::from(r.u16()?) } else { r.u32()? };
+        let construction = if version == 0 { 0 } else { r.u16()? & 0xf };
+        r.u16()?;
+        let base_offset = r.uint(base_offset_size)?;
+        let extent_count = r.u16()?;
+        let mut first = None;
+        for _ in 0..extent_count {
+            r.uint(index_size)?;
+            let extent = (r.uint(offset_size)?, r.uint(length_size)?);
+            first.get_or_insert(extent);
+        }
+        if id == item {
+            let (offset, length) = first?;
+            return Some((construction, base_offset.checked_add(offset)?, length));
+        }
+    }
+    None
+}
+
+/// The TIFF structure in the data of an `Exif` item, which starts with the offset of the TIFF
+/// header from the end of that field (usually just past an `Exif\0\0` signature).
+pub fn exif_item_tiff(item: &[u8]) -> Result<&[u8], BmffError> {
+    let mut r = Reader { data: item };
+    let offset = r.u32().ok_or(BmffError::BadExifItem)?;
+    r.take(offset as usize).ok_or(BmffError::BadExifItem)?;
+    Ok(r.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A box of type `kind` around `body`.
+    fn bx(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    /// A full box: version and flags ahead of `body`.
+    fn full_box(kind: &[u8; 4], version: u8, body: &[u8]) -> Vec<u8> {
+        bx(kind, &[&[version, 0, 0, 0], body].concat())
+    }
+
+    /// A QuickTime `moov` box whose metadata holds `key` as a value of `kind` (1 for text).
+    fn quicktime_moov(key: &str, kind: u32, value: &str) -> Vec<u8> {
+        let mut keys = 2u32.to_be_bytes().to_vec();
+        for key in ["com.apple.quicktime.make", key] {
+            keys.extend_from_slice(&(key.len() as u32 + 8).to_be_bytes());
+            keys.extend_from_slice(b"mdta");
+            keys.extend_from_slice(key.as_bytes());
+        }
+        let data = [&kind.to_be_bytes()[..], &[0; 4], value.as_bytes()].concat();
+        let ilst = bx(b"ilst", &bx(&2u32.to_be_bytes(), &bx(b"data", &data)));
+        let hdlr = full_box(b"hdlr", 0, &[0; 8]);
+        let meta = bx(b"meta", &[hdlr, full_box(b"keys", 0, &keys), ilst].concat());
+        bx(b"moov", &meta)
+    }
+
+    /// An `iloc` box of the given version listing items 1 and 2, with 4 byte offsets and
+    /// lengths, 2 byte base offsets and (from version 1) 2 byte extent indexes.
+    fn iloc(version: u8) -> Vec<u8> {
+        let mut body = vec![0x44, if version == 0 { 0x20 } else { 0x22 }];
+        let id = |id: u32| match version {
+            0 | 1 => (id as u16).to_be_bytes().to_vec(),
+            _ => id.to_be_bytes().to_vec(),
+        };
+        body.extend(id(2));
+        for (item, base, offset, len) in [(1, 0u16, 100u32, 10u32), (2, 1000, 200, 20)] {
+            body.extend(id(item));
+            if version > 0 {
+                body.extend_from_slice(&[0, 0]);
+            }
+            body.extend_from_slice(&[0, 0]);
+            body.extend_from_slice(&base.to_be_bytes());
+            body.extend_from_slice(&1u16.to_be_bytes());
+            if version > 0 {
+                body.extend_from_slice(&[0, 0]);
+            }
+            body.extend_from_slice(&offset.to_be_bytes());
+            body.extend_from_slice(&len.to_be_bytes());
+        }
+        full_box(b"iloc", version, &body)[8..].to_vec()
+    }
+
+    #[test]
+    fn boxes_stop_at_the_first_that_doesnt_fit() {
+        let data = [bx(b"ftyp", b"isom"), bx(b"free", b""), bx(b"mdat", &[0; 8])].concat();
+        let kinds: Vec<&[u8]> = boxes(&data).map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [&b"ftyp"[..], b"free", b"mdat"]);
+        // Cut short inside the last box.
+        let kinds: Vec<&[u8]> = boxes(&data[..data.len() - 1]).map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [&b"ftyp"[..], b"free"]);
+        // Sizes smaller than the box header, and a 64-bit size past the end.
+        let mut small = bx(b"free", b"");
+        small[3] = 4;
+        assert_eq!(boxes(&small).count(), 0);
+        let large = [&1u32.to_be_bytes()[..], b"mdat", &u64::MAX.to_be_bytes()].concat();
+        assert_eq!(boxes(&large).count(), 0);
+        for len in 0..data.len() {
+            boxes(&data[..len]).for_each(drop);
+        }
+    }
+
+    #[test]
+    fn boxes_of_size_zero_run_to_the_end() {
+        let mut data = bx(b"mdat", b"frames");
+        data[..4].copy_from_slice(&[0; 4]);
+        assert_eq!(boxes(&data).collect::<Vec<_>>(), [(&b"mdat"[..], &b"frames"[..])]);
+    }
+
+    #[test]
+    fn item_location_finds_the_first_extent() {
+        for version in 0..=2 {
+            let iloc = iloc(version);
+            assert_eq!(item_location(&iloc, 2), Some((0, 1200, 20)), "version {}", version);
+            assert_eq!(item_location(&iloc, 1), Some((0, 100, 10)), "version {}", version);
+            assert_eq!(item_location(&iloc, 3), None);
+            for len in 0..iloc.len() {
+                assert_eq!(item_location(&iloc[..len], 2), None, "cut to {} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn item_location_refuses_field_sizes_over_eight_bytes() {
+        let mut iloc = iloc(1);
+        iloc[4] = 0xf4;
+        assert_eq!(item_location(&iloc, 2), None);
+    }
+
+    #[test]
+    fn exif_location_finds_the_exif_item() {
+        let infe = |id: u16, kind: &[u8; 4]| {
+            full_box(b"infe", 2, &[&id.to_be_bytes()[..], &[0, 0], kind, b"\0"].concat())
+        };
+        let entries = [&[0, 2][..], &infe(1, b"hvc1"), &infe(2, b"Exif")].concat();
+        let iinf = full_box(b"iinf", 0, &entries);
+        let meta = [&[0; 4][..], &iinf, &bx(b"iloc", &iloc(1))].concat();
+        assert_eq!(exif_location(&meta).unwrap(), (1200, 20));
+        for len in 0..meta.len() {
+            assert!(exif_location(&meta[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn quicktime_value_reads_text_items() {
+        let moov = quicktime_moov(QUICKTIME_CREATION_DATE, 1, "2020-02-01T14:32:14+0100");
+        let body = &moov[8..];
+        let date = quicktime_value(body, QUICKTIME_CREATION_DATE);
+        assert_eq!(date, Some("2020-02-01T14:32:14+0100"));
+        // The make is listed, but has no item.
+        assert_eq!(quicktime_value(body, QUICKTIME_MAKE), None);
+        assert_eq!(quicktime_value(body, QUICKTIME_MODEL), None);
+        for len in 0..body.len() {
+            assert_eq!(quicktime_value(&body[..len], QUICKTIME_CREATION_DATE), None);
+        }
+    }
+
+    #[test]
+    fn quicktime_value_skips_values_that_arent_text() {
+        let moov = quicktime_moov(QUICKTIME_CREATION_DATE, 21, "\0\0\0\x01");
+        assert_eq!(quicktime_value(&moov[8..], QUICKTIME_CREATION_DATE), None);
+    }
+
+    #[test]
+    fn movie_creation_time_reads_either_version() {
+        let v0 = bx(b"mvhd", &[&[0; 4][..], &3_786_825_600u32.to_be_bytes()].concat());
+        let v1 = bx(b"mvhd", &[&[1, 0, 0, 0][..], &3_786_825_600u64.to_be_bytes()].concat());
+        for mvhd in [&v0, &v1] {
+            assert_eq!(movie_creation_time(mvhd).unwrap(), 1_703_980_800);
+            for len in 0..mvhd.len() {
+                assert!(movie_creation_time(&mvhd[..len]).is_err());
+            }
+        }
+        let unset = bx(b"mvhd", &[0; 8]);
+        assert!(matches!(movie_creation_time(&unset), Err(BmffError::NoCreationTime)));
+    }
+}
@@ -0,0 +1,684 @@
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use chrono_tz::Tz;
+use clap::{ArgAction, Args, ColorChoice, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+use crate::perms::{self, Owner};
+use crate::template::Template;
+use crate::Date;
+
+const EXIT_STATUS: &str = "\
+Exit status:
+  0  every file was sorted or deliberately skipped
+  1  photosort couldn't run (bad config, unreadable file list, ...), or verify found a misplaced file
+  2  invalid command line
+  3  some files in the batch could not be sorted
+  4  no file in the batch could be sorted";
+
+/// Sort photos into a dated directory tree based on when they were taken.
+#[derive(Parser, Debug)]
+#[command(name = "photosort", version, about, after_help = EXIT_STATUS)]
+pub struct Cli {
+    /// Root of the photo library [default: ~/annex/photos]
+    #[arg(long, global = true, env = "PHOTOSORT_DEST", value_name = "DIR")]
+    pub dest: Option<PathBuf>,
+
+    /// Config file to read profiles from [default: ~/.config/photosort/config.toml, or
+    /// %APPDATA%\photosort\config.toml on Windows]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Named profile from the config file to take settings from
+    #[arg(long, global = true, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Log more about what photosort is doing (-v, -vv, -vvv). RUST_LOG overrides this
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Log less (-q only logs errors, -qq logs nothing)
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// If another photosort run has the library locked, wait for it to finish rather than
+    /// giving up
+    #[arg(long, global = true)]
+    pub wait: bool,
+
+    /// Take the library's lock even though another run holds it, for a lock left behind by a
+    /// run that was killed. Make sure no other photosort is using the library first
+    #[arg(long, global = true)]
+    pub force_unlock: bool,
+
+    /// When to color output. "auto" colors terminals unless NO_COLOR is set
+    #[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Move photos into the library based on their capture date
+    Sort(SortCommand),
+    /// Work out where photos belong and save the moves to a file, to review before applying
+    Plan(PlanCommand),
+    /// Make the moves saved by `photosort plan`
+    Apply(ApplyCommand),
+    /// Pick up an interrupted `photosort sort` or `photosort apply` run where it left off
+    Resume(ResumeCommand),
+    /// Put back the files a `photosort sort` or `photosort apply` run put in the library
+    Undo(UndoCommand),
+    /// Check that a photo is already where photosort would put it
+    Verify(VerifyCommand),
+    /// Watch a directory and sort photos as they show up in it
+    Watch(WatchCommand),
+    /// Print the metadata photosort reads from a file, to see why it was sorted where it was
+    Exif(ExifCommand),
+    /// Save the JPEG preview embedded in a RAW or HEIF file
+    Thumb(ThumbCommand),
+    /// Print a shell completion script
+    Completions(CompletionsCommand),
+}
+
+#[derive(Args, Debug)]
+pub struct SortCommand {
+    /// Photos to sort. Directories are sorted recursively and glob patterns like "IMG_*.CR2"
+    /// are expanded. Zip archives are unpacked into --extract-to
+    #[arg(required_unless_present = "files_from")]
+    pub inputs: Vec<PathBuf>,
+
+    /// Unpack zip archives given as inputs into DIR a file at a time, sorting each photo into
+    /// the library as soon as it's unpacked. Files that aren't sorted stay in DIR, along with
+    /// the sidecars unpacked to date photos by; with --dry-run everything unpacked is removed
+    #[arg(long, value_name = "DIR")]
+    pub extract_to: Option<PathBuf>,
+
+    /// Also sort the paths listed in FILE, one per line ("-" reads standard input)
+    #[arg(long, value_name = "FILE")]
+    pub files_from: Option<PathBuf>,
+
+    /// Paths in --files-from are separated by NUL bytes, as written by `find -print0`
+    #[arg(short = '0', long = "null", requires = "files_from")]
+    pub null_separated: bool,
+
+    /// Ask before moving each file: y(es), n(o), a(ll remaining) or q(uit)
+    #[arg(short, long)]
+    pub interactive: bool,
+
+    /// Leave out files in input directories that an earlier run with this flag already listed,
+    /// going by the newest modification time it saw in each. For nightly imports from a dump
+    /// folder that keeps growing. Files that failed then aren't tried again; run without this
+    /// to pick them up
+    #[arg(long)]
+    pub since_last_run: bool,
+
+    #[command(flatten)]
+    pub options: SortOptions,
+}
+
+#[derive(Args, Debug)]
+pub struct PlanCommand {
+    /// Photos to plan moves for. Directories are walked recursively and glob patterns like
+    /// "IMG_*.CR2" are expanded
+    #[arg(required_unless_present = "files_from")]
+    pub inputs: Vec<PathBuf>,
+
+    /// Where to save the plan, as JSON
+    #[arg(short, long, value_name = "FILE")]
+    pub manifest: PathBuf,
+
+    /// Also plan the paths listed in FILE, one per line ("-" reads standard input)
+    #[arg(long, value_name = "FILE")]
+    pub files_from: Option<PathBuf>,
+
+    /// Paths in --files-from are separated by NUL bytes, as written by `find -print0`
+    #[arg(short = '0', long = "null", requires = "files_from")]
+    pub null_separated: bool,
+
+    #[command(flatten)]
+    pub options: SortOptions,
+}
+
+#[derive(Args, Debug)]
+pub struct ResumeCommand {
+    /// Journal of the run to resume, from the journal directory in photosort's data directory
+    /// (~/.local/share/photosort/journal on Linux) [default: the run started last]
+    pub journal: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct UndoCommand {
+    /// Journal of the run to undo, as for `photosort resume` [default: the run started last]
+    pub journal: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ApplyCommand {
+    /// Plan written by `photosort plan`. Files that have changed since are left where they are
+    pub manifest: PathBuf,
+
+    #[command(flatten)]
+    pub options: SortOptions,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyCommand {
+    /// Photo to check
+    pub input: PathBuf,
+
+    #[command(flatten)]
+    pub layout: LayoutOptions,
+
+    #[command(flatten)]
+    pub dates: DateOptions,
+}
+
+#[derive(Args, Debug)]
+pub struct ExifCommand {
+    /// Photo or video to read
+    pub input: PathBuf,
+
+    /// How to print what was read
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    pub output: OutputFormat,
+
+    #[command(flatten)]
+    pub dates: DateOptions,
+}
+
+#[derive(Args, Debug)]
+pub struct ThumbCommand {
+    /// RAW or HEIF file to take the preview from
+    pub input: PathBuf,
+
+    /// Where to write the preview
+    #[arg(short, long, value_name = "FILE")]
+    pub output: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct WatchCommand {
+    /// Directory to watch (recursively) for new photos
+    pub dir: PathBuf,
+
+    /// Seconds to wait between scans of the directory
+    #[arg(long, default_value_t = 5)]
+    pub interval: u64,
+
+    #[command(flatten)]
+    pub options: SortOptions,
+}
+
+#[derive(Args, Debug)]
+pub struct CompletionsCommand {
+    /// Shell to generate completions for
+    pub shell: clap_complete::Shell,
+}
+
+/// Options shared by every command that moves files into the library.
+#[derive(Args, Debug)]
+pub struct SortOptions {
+    #[command(flatten)]
+    pub layout: LayoutOptions,
+
+    /// How files are moved into the library [default: file]
+    #[arg(long, value_enum)]
+    pub backend: Option<Backend>,
+
+    /// rclone remote to sort into with the rclone backend, like "myremote:photos". It takes the
+    /// place of --dest
+    #[arg(long, value_name = "REMOTE")]
+    pub remote: Option<String>,
+
+    /// Command to run for each file with the exec backend, like "stage-to-tape {src} {dest}".
+    /// {src} is replaced with the file and {dest} with where it belongs in the library. It's run
+    /// directly rather than through a shell, though quotes and backslashes work as in one
+    #[arg(long, value_name = "CMD")]
+    pub exec_cmd: Option<String>,
+
+    /// With the git or git-annex backend, commit the files moved by each run (or each scan, when
+    /// watching)
+    #[arg(long)]
+    pub git_commit: bool,
+
+    /// What to do when a file's destination already holds a different file [default: skip]
+    #[arg(long, value_enum)]
+    pub on_conflict: Option<ConflictPolicy>,
+
+    /// Copy files into the library instead of moving them, keeping their modification times
+    #[arg(long)]
+    pub copy: bool,
+
+    /// Copy even when the library's filesystem doesn't look to have room for every file, with
+    /// 5% to spare. Without it, a copy that wouldn't fit fails before anything is copied
+    #[arg(long)]
+    pub no_space_check: bool,
+
+    /// Sort sidecars with these extensions along with the photo they're named after, like
+    /// IMG_1234.xmp or IMG_1234.CR2.xmp next to IMG_1234.CR2 [default: xmp,aae,gpx]
+    #[arg(long, value_name = "EXTS", value_delimiter = ',', conflicts_with = "no_sidecars")]
+    pub sidecars: Vec<String>,
+
+    /// Leave sidecars where they are
+    #[arg(long)]
+    pub no_sidecars: bool,
+
+    /// What to do with the AppleDouble files macOS leaves next to photos on memory cards
+    /// (._IMG_1234.JPG). They're never sorted as photos [default: skip]
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub apple_double: Option<AppleDoublePolicy>,
+
+    /// Sort a RAW file and the JPEG shot with it (DSC_0001.NEF and DSC_0001.JPG) as one, dated
+    /// by the RAW file and named alike, or sort the RAW file alone
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub pair: Option<Pairing>,
+
+    /// Sort the video half of each Live Photo (IMG_1234.MOV next to IMG_1234.HEIC) by its own
+    /// date, rather than along with its still
+    #[arg(long)]
+    pub split_live_photos: bool,
+
+    /// Checksum each file before and after copying it (with --copy, the reflink backend, or when
+    /// moving across filesystems), and only keep the copy, or remove the original, if they match
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Flush each file and the directories it moved between to disk before going on to the
+    /// next, so a finished run survives a power cut
+    #[arg(long)]
+    pub fsync: bool,
+
+    /// Set each sorted file's modification time to when it was taken, so apps that order by
+    /// mtime agree with the library's folders. Symlinks, and files that stay remote, are left alone
+    #[arg(long)]
+    pub set_mtime: bool,
+
+    /// Give sorted files this mode, in octal like 640. Directories made for them get it too, with
+    /// search permission wherever it gives read permission. Unix only
+    #[arg(long, value_name = "MODE", value_parser = perms::parse_mode)]
+    pub chmod: Option<u32>,
+
+    /// Give sorted files, and directories made for them, to USER, USER:GROUP or :GROUP, by name
+    /// or ID, e.g. to keep a shared library readable by the family's group. Changing the user
+    /// takes root. Unix only
+    #[arg(long, value_name = "OWNER", value_parser = perms::parse_owner)]
+    pub chown: Option<Owner>,
+
+    /// Retry a move that fails up to N more times, for destinations that fail now and then, like
+    /// network shares and remotes
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+
+    /// Seconds to wait before the first retry, doubling for each one after
+    #[arg(long, value_name = "SECONDS", default_value_t = 1)]
+    pub retry_backoff: u64,
+
+    /// Which errors are worth retrying [default: io,command,http]
+    #[arg(long, value_enum, value_name = "KINDS", value_delimiter = ',')]
+    pub retry_on: Vec<RetryOn>,
+
+    /// Limit uploads to RATE bytes per second, with the webdav and rclone backends. Accepts K, M
+    /// and G suffixes (powers of 1024). The object-store backend's CLIs take no limit on the
+    /// command line; set s3.max_bandwidth in the AWS CLI's config for s3:// URLs instead
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    pub bwlimit: Option<u64>,
+
+    /// Print where each file would go without creating directories or moving anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// How results are written to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    pub output: OutputFormat,
+
+    /// Number of files to process at once [default: number of CPUs]
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Number of files to move or copy at once, separately from the ones being read. Fewer can
+    /// be faster for a slow disk or network backend [default: --jobs]
+    #[arg(long, value_name = "N")]
+    pub transfer_jobs: Option<NonZeroUsize>,
+
+    #[command(flatten)]
+    pub traversal: TraversalOptions,
+
+    #[command(flatten)]
+    pub dates: DateOptions,
+
+    /// Only sort files taken on or after this date (YYYY-MM-DD, optionally with HH:MM:SS)
+    #[arg(long, value_name = "DATE", value_parser = |s: &str| Date::parse_arg(s, "00:00:00"))]
+    pub since: Option<Date>,
+
+    /// Only sort files taken on or before this date (YYYY-MM-DD, optionally with HH:MM:SS)
+    #[arg(long, value_name = "DATE", value_parser = |s: &str| Date::parse_arg(s, "23:59:59"))]
+    pub until: Option<Date>,
+
+    /// Only sort files rated at least this many stars (1-5), in an XMP sidecar or the file's
+    /// own metadata
+    #[arg(long, value_name = "STARS", value_parser = clap::value_parser!(u8).range(1..=5))]
+    pub min_rating: Option<u8>,
+
+    /// Only sort files tagged with KEYWORD (ignoring case), in an XMP sidecar or the file's own
+    /// metadata. Given more than once, files with any of them are sorted
+    #[arg(long, value_name = "KEYWORD")]
+    pub keyword: Vec<String>,
+}
+
+/// Options that control which files are picked up when walking directories.
+#[derive(Args, Debug, Default)]
+pub struct TraversalOptions {
+    /// Skip files and directories matching PATTERN (gitignore syntax, e.g. "cache/" or "*.tmp").
+    /// May be given more than once
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<String>,
+
+    /// Don't read .photosortignore files in the directories being walked
+    #[arg(long)]
+    pub no_ignore_files: bool,
+
+    /// Descend at most N directories below each input (0 only looks at the input itself)
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+
+    /// Follow symbolic links while walking. Links that loop back to a directory already being
+    /// walked are reported and skipped
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Skip files smaller than SIZE bytes. Accepts K, M and G suffixes (powers of 1024)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_size: Option<u64>,
+
+    /// Only pick up files with these extensions, e.g. "jpg,cr2" [default: every format
+    /// photosort can read]
+    #[arg(long, value_name = "EXTS", value_delimiter = ',')]
+    pub only: Vec<String>,
+
+    /// Also walk hidden files and directories (names starting with '.')
+    #[arg(long)]
+    pub include_hidden: bool,
+}
+
+/// Options that decide where a file's capture date comes from.
+#[derive(Args, Debug)]
+pub struct DateOptions {
+    /// Where to look for a file's date, in order, e.g. "exif,xmp,filename". The first source
+    /// with a date wins [default: xmp,exif,gps,json,filename]
+    #[arg(long, value_enum, value_name = "SOURCES", value_delimiter = ',')]
+    pub date_sources: Vec<DateSource>,
+
+    /// Date to go by for files none of the date sources have a date for. Without one, those
+    /// files are reported as errors
+    #[arg(long, value_enum, value_name = "SOURCE")]
+    pub fallback: Option<Fallback>,
+
+    /// Ask exiftool (which must be installed) for the date of files whose metadata photosort
+    /// can't find one in itself. Same as adding "exiftool" after "exif" and "gps" in
+    /// --date-sources
+    #[arg(long)]
+    pub use_exiftool: bool,
+
+    /// Don't trust dates before this one (YYYY-MM-DD, optionally with HH:MM:SS), such as the
+    /// 2000-01-01 some cameras reset to, and go on to the next date source instead
+    #[arg(long, value_name = "DATE", value_parser = |s: &str| Date::parse_arg(s, "00:00:00"))]
+    pub suspect_before: Option<Date>,
+
+    /// Time zone to file photos by, e.g. "Europe/Paris". Dates that know their UTC offset (from
+    /// OffsetTimeOriginal, GPS time or the like) are converted to it. Without this, dates are
+    /// taken as the camera's clock wrote them
+    #[arg(long, value_name = "ZONE", value_parser = parse_tz)]
+    pub tz: Option<Tz>,
+
+    /// Time zone the camera's clock was set to, for dates that don't record their UTC offset.
+    /// Needs --tz or --tz-from-gps
+    #[arg(long, value_name = "ZONE", value_parser = parse_tz)]
+    pub assume_tz: Option<Tz>,
+
+    /// File geotagged photos by the local time where they were taken, using the time zone at
+    /// their GPS position. Other files are converted to --tz, if given
+    #[arg(long)]
+    pub tz_from_gps: bool,
+}
+
+fn parse_tz(arg: &str) -> Result<Tz, String> {
+    arg.parse()
+        .map_err(|_| format!("expected a time zone like \"America/New_York\", found {:?}", arg))
+}
+
+fn parse_size(arg: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match arg.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&arg[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&arg[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&arg[..i], 1 << 30),
+        _ => (arg, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("expected a size like 4096, 100K or 2M, found {:?}", arg))
+}
+
+/// Options that decide where in the library a file belongs.
+#[derive(Args, Debug)]
+pub struct LayoutOptions {
+    /// Destination path within the library, e.g. "{year}/{year}-{month}/{filename}". Available
+    /// variables: {year}, {month}, {month_name}, {day}, {iso_year}, {iso_week}, {hour},
+    /// {minute}, {second}, {subsec} (fraction of a second, empty if not recorded),
+    /// {camera_make}, {camera_model}, {lens}, {focal_length} (in mm), {iso} ("Unknown" if not
+    /// recorded), {filename} (the name from --name-template) and {original}, one of which it
+    /// must use. Use {{ and }} for literal braces
+    /// [default: {year}/{month}/{day}/{filename}]
+    #[arg(long, conflicts_with = "layout")]
+    pub template: Option<Template>,
+
+    /// Use a predefined directory layout instead of spelling out a --template
+    #[arg(long, value_enum)]
+    pub layout: Option<LayoutPreset>,
+
+    /// File name to give sorted files, e.g. "{year}{month}{day}_{hour}{minute}{second}_{original}".
+    /// Takes the same variables as --template except {filename} [default: {original}]
+    #[arg(long, value_parser = Template::parse_name)]
+    pub name_template: Option<Template>,
+
+    /// How {month} is written in directory names
+    #[arg(long, value_enum)]
+    pub month_style: Option<MonthStyle>,
+
+    /// Language for month names in --month-style and {month_name} [default: en]
+    #[arg(long, value_enum, value_name = "LANG")]
+    pub month_locale: Option<MonthLocale>,
+
+    /// Lowercase file extensions and use one spelling per format (.JPEG -> .jpg, .TIF -> .tiff)
+    #[arg(long)]
+    pub normalize_ext: bool,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayoutPreset {
+    /// 2020/IMG_0001.CR2
+    Year,
+    /// 2020/02/IMG_0001.CR2
+    YearMonth,
+    /// 2020/02/01/IMG_0001.CR2
+    YearMonthDay,
+    /// 2020/W05/IMG_0001.CR2, by ISO 8601 week
+    IsoWeek,
+}
+
+impl LayoutPreset {
+    pub fn template(self) -> &'static str {
+        match self {
+            LayoutPreset::Year => "{year}/{filename}",
+            LayoutPreset::YearMonth => "{year}/{month}/{filename}",
+            LayoutPreset::YearMonthDay => "{year}/{month}/{day}/{filename}",
+            LayoutPreset::IsoWeek => "{iso_year}/W{iso_week}/{filename}",
+        }
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MonthStyle {
+    /// 02
+    Number,
+    /// 02-February, which still sorts in calendar order
+    NumberName,
+    /// February
+    Name,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MonthLocale {
+    En,
+    De,
+    Fr,
+    Es,
+    It,
+    Nl,
+    Pt,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DateSource {
+    /// An XMP sidecar next to the file (IMG_0001.CR2.xmp or IMG_0001.xmp)
+    Xmp,
+    /// The file's own metadata: EXIF DateTimeOriginal, or the format's equivalent
+    Exif,
+    /// The EXIF GPS timestamp, in UTC
+    Gps,
+    /// Whatever date exiftool can find, for formats photosort can't read itself
+    Exiftool,
+    /// A Google Takeout metadata file next to the file, in UTC
+    Json,
+    /// A date in the file name, like IMG-20200101-WA0001.jpg
+    Filename,
+    /// The file's last modification time, in local time
+    Mtime,
+}
+
+impl DateSource {
+    /// Sources tried when none are given.
+    pub const DEFAULT: &'static [DateSource] = &[
+        DateSource::Xmp,
+        DateSource::Exif,
+        DateSource::Gps,
+        DateSource::Json,
+        DateSource::Filename,
+    ];
+
+    /// How the source is named on the command line and in output.
+    pub fn name(self) -> &'static str {
+        match self {
+            DateSource::Xmp => "xmp",
+            DateSource::Exif => "exif",
+            DateSource::Gps => "gps",
+            DateSource::Exiftool => "exiftool",
+            DateSource::Json => "json",
+            DateSource::Filename => "filename",
+            DateSource::Mtime => "mtime",
+        }
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fallback {
+    /// The file's last modification time, in local time
+    Mtime,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryOn {
+    /// I/O errors, other than a missing file, a taken destination or a permission problem
+    Io,
+    /// Permission denied
+    Permission,
+    /// A tool a backend runs (git, rclone, curl and the like) failing
+    Command,
+    /// A WebDAV server answering with a 5xx status, 408 or 429
+    Http,
+    /// A copy that doesn't match the original under --verify
+    Checksum,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Progress bar and a summary table
+    Human,
+    /// One JSON object per file (JSON Lines), for other tools to consume
+    Json,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Leave the file where it is and report it as skipped
+    Skip,
+    /// Replace the existing file, moving it to the trash (or the library's .photosort-trash).
+    /// Files in the same run that would land on the same name are renamed instead
+    Overwrite,
+    /// Add a numeric suffix to the new file's name, e.g. IMG_0001_1.CR2
+    Rename,
+    /// Leave the file where it is and report it as an error
+    Error,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AppleDoublePolicy {
+    /// Leave them where they are
+    Skip,
+    /// Move (or copy) each along with the file it belongs to, renamed to match
+    Follow,
+}
+
+/// What `--pair` does with a RAW file and a JPEG of the same name in the same directory.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pairing {
+    /// Sort both into the same directory, dated by the RAW file and named alike
+    #[value(name = "raw+jpeg")]
+    #[serde(rename = "raw+jpeg")]
+    RawJpeg,
+    /// Sort the RAW file and leave the JPEG where it is, unless the RAW file can't be sorted
+    PreferRaw,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Plain filesystem rename
+    File,
+    /// `git mv`, for libraries kept in a git repository
+    Git,
+    /// `git annex add` for new files and `git mv` for ones already in the repository, for
+    /// libraries kept in git-annex
+    #[serde(rename = "git-annex")]
+    GitAnnex,
+    /// Leave files where they are and link to them from the library with relative symlinks
+    Symlink,
+    /// Copy files as copy-on-write clones, which are instant and take no space, on filesystems
+    /// that support them (btrfs, XFS, APFS). Files are copied as usual elsewhere
+    Reflink,
+    /// Upload files to an s3:// or gs:// --dest with the AWS or Google Cloud CLI, keyed the way
+    /// they'd be laid out in the library. Needs photosort built with the object-store feature
+    #[serde(rename = "object-store")]
+    ObjectStore,
+    /// Move or copy files to an rclone --remote with `rclone moveto` or `rclone copyto`, for
+    /// remotes the other backends don't cover
+    Rclone,
+    /// Upload files to an http:// or https:// --dest on a WebDAV server (Nextcloud, ownCloud)
+    /// with curl, making date folders as needed. Credentials are read from ~/.netrc
+    Webdav,
+    /// Run --exec-cmd for each file, for storage none of the others cover
+    Exec,
+}
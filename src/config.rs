@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use chrono_tz::Tz;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::cli::{
+    AppleDoublePolicy, Backend, ConflictPolicy, DateSource, Fallback, LayoutPreset, MonthLocale,
+    MonthStyle, Pairing,
+};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse config file {0:?}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("No profile named {0:?} in config file")]
+    UnknownProfile(String),
+}
+
+/// Contents of `~/.config/photosort/config.toml` (`%APPDATA%\photosort\config.toml` on
+/// Windows).
+///
+/// ```toml
+/// default_profile = "family"
+///
+/// [profiles.family]
+/// dest = "~/annex/photos"
+/// backend = "git"
+///
+/// [profiles.work]
+/// dest = "/mnt/nas/work-photos"
+/// template = "{year}/{year}-{month}/{filename}"
+/// on_conflict = "rename"
+/// date_sources = ["exif", "filename"]
+/// suspect_before = "2001-01-01"
+/// tz = "Europe/London"
+/// filename_patterns = ['^scan_(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})']
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Profile used when `--profile` isn't given.
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// Settings for one library. Anything left unset falls back to photosort's defaults, and any
+/// flag given on the command line wins over what's here.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub dest: Option<PathBuf>,
+    pub backend: Option<Backend>,
+    /// The rclone remote when `backend` is `rclone`, as with `--remote`.
+    pub remote: Option<String>,
+    /// The command to run when `backend` is `exec`, as with `--exec-cmd`.
+    pub exec_cmd: Option<String>,
+    /// Commit each batch when `backend` is `git` or `git-annex`, as with `--git-commit`.
+    pub git_commit: Option<bool>,
+    /// Checksum copies against their originals, as with `--verify`.
+    pub verify: Option<bool>,
+    /// Flush sorted files to disk, as with `--fsync`.
+    pub fsync: Option<bool>,
+    /// Set sorted files' modification times, as with `--set-mtime`.
+    pub set_mtime: Option<bool>,
+    /// Mode for sorted files, in octal, as with `--chmod`.
+    pub chmod: Option<String>,
+    /// `USER`, `USER:GROUP` or `:GROUP` for sorted files, as with `--chown`.
+    pub chown: Option<String>,
+    /// Extensions of sidecars sorted along with their photos, as with `--sidecars`. An empty
+    /// list leaves them where they are.
+    pub sidecars: Option<Vec<String>>,
+    /// `raw+jpeg` or `prefer-raw`, as with `--pair`.
+    pub pair: Option<Pairing>,
+    /// Sort Live Photos' videos along with their stills. On unless set to false, which is like
+    /// `--split-live-photos`.
+    pub live_photos: Option<bool>,
+    pub apple_double: Option<AppleDoublePolicy>,
+    pub template: Option<String>,
+    pub layout: Option<LayoutPreset>,
+    pub name_template: Option<String>,
+    pub month_style: Option<MonthStyle>,
+    pub month_locale: Option<MonthLocale>,
+    pub normalize_ext: Option<bool>,
+    pub on_conflict: Option<ConflictPolicy>,
+    /// Extra regexes for reading dates out of file names, tried before the built-in ones. They
+    /// need `year`, `month` and `day` named groups, and may have `hour`, `minute` and `second`.
+    pub filename_patterns: Option<Vec<String>>,
+    pub date_sources: Option<Vec<DateSource>>,
+    pub fallback: Option<Fallback>,
+    pub use_exiftool: Option<bool>,
+    /// Dates before this one (`YYYY-MM-DD`, optionally with `HH:MM:SS`) are ignored, as with
+    /// `--suspect-before`.
+    pub suspect_before: Option<String>,
+    pub tz: Option<Tz>,
+    /// Needs `tz` or `tz_from_gps`, as with `--assume-tz`.
+    pub assume_tz: Option<Tz>,
+    pub tz_from_gps: Option<bool>,
+}
+
+impl Config {
+    /// Loads the config file at `path`, or from the default location when no path is given. A
+    /// missing file at the default location is not an error and yields an empty config.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let (path, required) = match path {
+            Some(p) => (p.to_path_buf(), true),
+            None => match default_path() {
+                Some(p) => (p, false),
+                None => return Ok(Config::default()),
+            },
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok(Config::default())
+            }
+            Err(e) => return Err(ConfigError::Read(path, e)),
+        };
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    /// Selects the named profile, falling back to `default_profile`. With neither set an empty
+    /// profile is returned so everything comes from flags and defaults.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, ConfigError> {
+        let name = match name.or(self.default_profile.as_deref()) {
+            Some(n) => n,
+            None => return Ok(Profile::default()),
+        };
+        let mut profile = self
+            .profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ConfigError::UnknownProfile(name.into()))?;
+        profile.dest = profile.dest.map(|d| expand_home(&d));
+        Ok(profile)
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None if cfg!(windows) => dirs::config_dir()?,
+        // Not dirs::config_dir(), which on macOS would move the file to ~/Library.
+        None => dirs::home_dir()?.join(".config"),
+    };
+    Some(config_dir.join("photosort").join("config.toml"))
+}
+
+/// Where photosort keeps what it remembers from one run to the next: `~/.local/share/photosort`
+/// (or under `$XDG_DATA_HOME`), `~/Library/Application Support/photosort` on macOS and
+/// `%APPDATA%\photosort` on Windows.
+pub fn data_dir() -> Option<PathBuf> {
+    Some(dirs::data_dir()?.join("photosort"))
+}
+
+/// Expands a leading `~` since paths in the config file don't go through a shell.
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
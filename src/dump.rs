@@ -0,0 +1,175 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::cli::{ExifCommand, OutputFormat};
+use crate::sort::DateReader;
+use crate::Date;
+
+/// What `photosort exif --output json` prints.
+#[derive(Serialize)]
+struct Record<'a> {
+    file: String,
+    /// The date the file is sorted by, and the source it came from.
+    date: Option<String>,
+    extractor: Option<&'a str>,
+    error: Option<String>,
+    sources: Vec<SourceRecord>,
+    metadata_error: Option<String>,
+    /// What holds the metadata, and where it and its IFDs start in the file.
+    container: Option<&'a str>,
+    offset: Option<u64>,
+    ifd_offsets: Vec<u64>,
+    camera_make: Option<&'a str>,
+    camera_model: Option<&'a str>,
+    lens: Option<&'a str>,
+    focal_length: Option<f64>,
+    iso: Option<u32>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    rating: Option<i32>,
+    keywords: &'a [String],
+}
+
+/// One date source's answer in a [`Record`].
+#[derive(Serialize)]
+struct SourceRecord {
+    source: &'static str,
+    date: Option<String>,
+    /// Whether the source is one dates are read from.
+    used: bool,
+    implausible: bool,
+    error: Option<String>,
+}
+
+/// A date with whatever precision and UTC offset it was recorded with, like
+/// `2021-07-04T12:30:00.25+02:00`.
+pub fn describe(date: &Date) -> String {
+    let mut text = date.to_string();
+    if !date.subsec().is_empty() {
+        text = format!("{}.{}", text, date.subsec());
+    }
+    if let Some(offset) = date.offset() {
+        let sign = if offset < 0 { '-' } else { '+' };
+        let minutes = offset.abs() / 60;
+        text = format!("{}{}{:02}:{:02}", text, sign, minutes / 60, minutes % 60);
+    }
+    text
+}
+
+/// Prints every date photosort finds for `cmd.input`, the one it goes by, and the rest of what
+/// it reads from the file's metadata, down to where in the file that was.
+pub async fn dump(cmd: &ExifCommand, dates: &DateReader) -> Result<()> {
+    let capture = dates.read(&cmd.input).await;
+    let (candidates, metadata) = dates.candidates(&cmd.input).await;
+    let (metadata, metadata_error) = match metadata {
+        Some(Ok(metadata)) => (Some(metadata), None),
+        Some(Err(e)) => (None, Some(e.to_string())),
+        None => (None, None),
+    };
+    let location = metadata.as_ref().and_then(|m| m.location.as_ref());
+    let ifd_offsets = match (&metadata, location) {
+        (Some(metadata), Some(location)) => {
+            metadata.ifd_offsets.iter().map(|&ifd| location.offset + ifd as u64).collect()
+        }
+        _ => Vec::new(),
+    };
+    let camera = metadata.as_ref().map(|m| &m.camera);
+    let tags = metadata.as_ref().map(|m| &m.tags);
+    let record = Record {
+        file: cmd.input.display().to_string(),
+        date: capture.as_ref().ok().map(|c| describe(&c.date)),
+        extractor: capture.as_ref().ok().map(|c| c.extractor),
+        error: capture.as_ref().err().map(|e| format!("{:#}", e)),
+        sources: candidates
+            .into_iter()
+            .map(|c| SourceRecord {
+                source: c.source,
+                date: c.date.as_ref().ok().and_then(Option::as_ref).map(describe),
+                used: c.used,
+                implausible: c.suspect,
+                error: c.date.err().map(|e| format!("{:#}", e)),
+            })
+            .collect(),
+        metadata_error,
+        container: location.map(|l| l.container),
+        offset: location.map(|l| l.offset),
+        ifd_offsets,
+        camera_make: camera.and_then(|c| c.make.as_deref()),
+        camera_model: camera.and_then(|c| c.model.as_deref()),
+        lens: camera.and_then(|c| c.lens.as_deref()),
+        focal_length: camera.and_then(|c| c.focal_length),
+        iso: camera.and_then(|c| c.iso),
+        latitude: metadata.as_ref().and_then(|m| m.position).map(|(latitude, _)| latitude),
+        longitude: metadata.as_ref().and_then(|m| m.position).map(|(_, longitude)| longitude),
+        rating: tags.and_then(|t| t.rating),
+        keywords: tags.map_or(&[], |t| &t.keywords),
+    };
+    match cmd.output {
+        OutputFormat::Human => print_human(&record),
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string(&record).expect("records always serialize"))
+        }
+    }
+    Ok(())
+}
+
+fn print_human(record: &Record) {
+    println!("{}", record.file);
+    match (&record.date, record.extractor, &record.error) {
+        (Some(date), Some(extractor), _) => {
+            println!("  date:         {} (from {})", date, extractor)
+        }
+        (_, _, Some(error)) => println!("  date:         none ({})", error),
+        _ => println!("  date:         none"),
+    }
+    println!("  sources:");
+    for source in &record.sources {
+        let mut line = match (&source.date, &source.error) {
+            (Some(date), _) => date.clone(),
+            (None, Some(error)) => format!("error: {}", error),
+            (None, None) => "-".to_string(),
+        };
+        if source.implausible {
+            line.push_str(" (implausible)");
+        }
+        if !source.used {
+            line.push_str(" (not used)");
+        }
+        println!("    {:<10}{}", source.source, line);
+    }
+    match (record.container, record.offset, &record.metadata_error) {
+        (Some(container), Some(offset), _) => {
+            print!("  metadata:     {} at byte {}", container, offset);
+            if !record.ifd_offsets.is_empty() {
+                let ifds: Vec<_> = record.ifd_offsets.iter().map(u64::to_string).collect();
+                print!(", IFDs at bytes {}", ifds.join(", "));
+            }
+            println!();
+        }
+        (_, _, Some(error)) => println!("  metadata:     error: {}", error),
+        _ => (),
+    }
+    let camera: Vec<_> =
+        [record.camera_make, record.camera_model].iter().flatten().copied().collect();
+    if !camera.is_empty() {
+        println!("  camera:       {}", camera.join(" "));
+    }
+    if let Some(lens) = record.lens {
+        println!("  lens:         {}", lens);
+    }
+    if let Some(focal_length) = record.focal_length {
+        println!("  focal length: {} mm", focal_length);
+    }
+    if let Some(iso) = record.iso {
+        println!("  iso:          {}", iso);
+    }
+    if let (Some(latitude), Some(longitude)) = (record.latitude, record.longitude) {
+        println!("  gps:          {:.6}, {:.6}", latitude, longitude);
+    }
+    if let Some(rating) = record.rating {
+        println!("  rating:       {}", rating);
+    }
+    if !record.keywords.is_empty() {
+        println!("  keywords:     {}", record.keywords.join(", "));
+    }
+}
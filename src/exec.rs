@@ -0,0 +1,168 @@
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+use async_trait::async_trait;
+
+use crate::renamer::{run, RenameError, Renamer};
+use crate::report::Transfer;
+
+/// Runs a command of the user's for each file, for storage photosort has no backend for, like
+/// a tape staging area or a deduplicating store. `{src}` and `{dest}` in its arguments are
+/// replaced with the file and where it belongs in the library. The command is run directly,
+/// not through a shell, so names needn't be quoted; it's up to it to move or copy the file.
+pub struct ExecRenamer {
+    program: String,
+    args: Vec<String>,
+    copy: bool,
+}
+
+impl ExecRenamer {
+    /// Splits `command` into words the way a shell would, with quotes and backslashes.
+    pub fn new(command: &str, copy: bool) -> Result<Self, String> {
+        let mut words = split_words(command)?.into_iter();
+        let program = words.next().ok_or("the command is empty")?;
+        let args: Vec<String> = words.collect();
+        for placeholder in ["{src}", "{dest}"] {
+            if !args.iter().any(|arg| arg.contains(placeholder)) {
+                return Err(format!("the command has no {} argument", placeholder));
+            }
+        }
+        Ok(Self { program, args, copy })
+    }
+}
+
+#[async_trait]
+impl Renamer for ExecRenamer {
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError> {
+        let args: Vec<OsString> = self.args.iter().map(|arg| fill(arg, source, dest)).collect();
+        let args: Vec<&OsStr> = args.iter().map(OsString::as_os_str).collect();
+        run(&self.program, &args, dest).await?;
+        Ok(())
+    }
+
+    fn transfer(&self) -> Transfer {
+        if self.copy {
+            Transfer::Copy
+        } else {
+            Transfer::Move
+        }
+    }
+}
+
+/// `arg` with `{src}` and `{dest}` replaced, keeping paths that aren't UTF-8 as they are.
+fn fill(arg: &str, source: &Path, dest: &Path) -> OsString {
+    let mut filled = OsString::new();
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        let (path, len) = if rest[start..].starts_with("{src}") {
+            (source, "{src}".len())
+        } else if rest[start..].starts_with("{dest}") {
+            (dest, "{dest}".len())
+        } else {
+            filled.push(&rest[..=start]);
+            rest = &rest[start + 1..];
+            continue;
+        };
+        filled.push(&rest[..start]);
+        filled.push(path);
+        rest = &rest[start + len..];
+    }
+    filled.push(rest);
+    filled
+}
+
+/// Splits `command` at whitespace, except inside single or double quotes or after a backslash.
+/// Inside double quotes a backslash only escapes `"` and `\`.
+fn split_words(command: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated ' quote".into()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some(c) => word.extend(['\\', c]),
+                            None => return Err("unterminated \" quote".into()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated \" quote".into()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("trailing backslash".into()),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_words_follows_shell_quoting() {
+        assert_eq!(split_words("  cp  {src}\t{dest} ").unwrap(), ["cp", "{src}", "{dest}"]);
+        assert_eq!(split_words("a 'b c' \"d e\" f\\ g").unwrap(), ["a", "b c", "d e", "f g"]);
+        assert_eq!(split_words("'it''s' x\"y\"z").unwrap(), ["its", "xyz"]);
+        assert_eq!(split_words(r#""a \"b\" \\ \n" 'c\d'"#).unwrap(), [r#"a "b" \ \n"#, r"c\d"]);
+        // Empty quotes are still a word.
+        assert_eq!(split_words("a '' \"\"").unwrap(), ["a", "", ""]);
+        assert!(split_words("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_words_refuses_unfinished_quoting() {
+        assert_eq!(split_words("a 'b").unwrap_err(), "unterminated ' quote");
+        assert_eq!(split_words("a \"b").unwrap_err(), "unterminated \" quote");
+        assert_eq!(split_words("a \"b\\").unwrap_err(), "unterminated \" quote");
+        assert_eq!(split_words("a b\\").unwrap_err(), "trailing backslash");
+    }
+
+    #[test]
+    fn fill_replaces_placeholders_and_keeps_other_braces() {
+        let (source, dest) = (Path::new("in/a.jpg"), Path::new("lib/2020/a.jpg"));
+        assert_eq!(fill("{src}", source, dest), "in/a.jpg");
+        assert_eq!(fill("--to={dest}", source, dest), "--to=lib/2020/a.jpg");
+        assert_eq!(fill("{src}:{dest}{", source, dest), "in/a.jpg:lib/2020/a.jpg{");
+        assert_eq!(fill("{{src}} {other} {", source, dest), "{in/a.jpg} {other} {");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fill_keeps_paths_that_arent_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let source = Path::new(OsStr::from_bytes(b"in/\xff.jpg"));
+        let filled = fill("src={src}", source, Path::new("lib"));
+        assert_eq!(filled.as_bytes(), b"src=in/\xff.jpg");
+    }
+
+    #[test]
+    fn commands_need_both_placeholders() {
+        assert!(ExecRenamer::new("cp {src} {dest}", false).is_ok());
+        assert_eq!(ExecRenamer::new("  ", false).err().unwrap(), "the command is empty");
+        let err = ExecRenamer::new("cp {src} lib", false).err().unwrap();
+        assert_eq!(err, "the command has no {dest} argument");
+    }
+}
@@ -0,0 +1,853 @@
+use thiserror::Error;
+
+/// Tag in IFD0 pointing at the Exif sub-IFD.
+const TAG_EXIF_IFD: u16 = 0x8769;
+/// Tag in IFD0 pointing at the GPS IFD.
+const TAG_GPS_IFD: u16 = 0x8825;
+/// Tags in the GPS IFD for latitude and longitude (`N`/`S` or `E`/`W`, then degrees, minutes
+/// and seconds as three RATIONALs).
+const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+const TAG_GPS_LATITUDE: u16 = 0x0002;
+const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+const TAG_GPS_LONGITUDE: u16 = 0x0004;
+/// Tags in the GPS IFD for the UTC time of day (three RATIONALs) and date (`YYYY:MM:DD`).
+const TAG_GPS_TIME_STAMP: u16 = 0x0007;
+const TAG_GPS_DATE_STAMP: u16 = 0x001d;
+/// Tag pointing at child IFDs, which RAW formats like NEF use for the full size image.
+const TAG_SUB_IFDS: u16 = 0x014a;
+/// Tags saying how an IFD's image is compressed and what its samples mean.
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_PHOTOMETRIC_INTERPRETATION: u16 = 0x0106;
+/// Tags locating an IFD's image data, when it's stored in strips.
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+/// Tags locating a JPEG stored whole, like the thumbnail in IFD1.
+const TAG_JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+const TAG_JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+/// Compression values for JPEG: the original TIFF 6.0 scheme and its replacement.
+const COMPRESSION_OLD_JPEG: u32 = 6;
+const COMPRESSION_JPEG: u32 = 7;
+/// Photometric interpretations of raw sensor data, which DNG also compresses as (lossless) JPEG.
+const PHOTOMETRIC_CFA: u32 = 32803;
+const PHOTOMETRIC_LINEAR_RAW: u32 = 34892;
+/// Stop following pointers after this many IFDs, in case a damaged file points in circles.
+const MAX_IFDS: usize = 32;
+/// Tags in IFD0 naming the camera's manufacturer and model.
+const TAG_MAKE: u16 = 0x010f;
+const TAG_MODEL: u16 = 0x0110;
+/// Tags in IFD0 for the star rating (0 to 5, a SHORT) and keywords (semicolon separated UTF-16
+/// in BYTEs) that Windows and some editors write.
+const TAG_RATING: u16 = 0x4746;
+const TAG_XP_KEYWORDS: u16 = 0x9c9e;
+/// Tags in the Exif IFD for the lens's name, the focal length in millimetres (a RATIONAL) and
+/// the ISO speed (PhotographicSensitivity, a SHORT).
+const TAG_LENS_MODEL: u16 = 0xa434;
+const TAG_FOCAL_LENGTH: u16 = 0x920a;
+const TAG_ISO: u16 = 0x8827;
+/// When the shutter fired, as opposed to DateTime (0x0132), which editors bump on every save.
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+/// Fraction of a second to go with DateTimeOriginal, as digits after the decimal point.
+const TAG_SUB_SEC_TIME_ORIGINAL: u16 = 0x9291;
+/// UTC offset DateTimeOriginal was recorded in, like `+01:00`. Only newer cameras write it.
+const TAG_OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+
+/// Start of image marker every JPEG begins with.
+pub const JPEG_SOI: [u8; 2] = [0xff, 0xd8];
+/// Marker of the APPn segment EXIF is stored in.
+pub const JPEG_APP1: u8 = 0xe1;
+/// Start of an APP1 segment holding EXIF, ahead of the TIFF structure.
+pub const EXIF_SIGNATURE: &[u8] = b"Exif\0\0";
+
+/// Every PNG starts with this, followed by a series of length-prefixed chunks.
+pub const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Keywords of PNG text chunks that say when the image was made. "Creation Time" is the one
+/// the PNG spec defines; ImageMagick writes "date:create".
+const PNG_DATE_KEYWORDS: [&[u8]; 2] = [b"Creation Time", b"date:create"];
+
+/// RIFF files (WebP, AVI) start with this, followed by the file size and a form type.
+pub const RIFF_MAGIC: &[u8] = b"RIFF";
+/// Form type of a WebP RIFF file, which keeps EXIF in an `EXIF` chunk.
+pub const WEBP_FORM: &[u8] = b"WEBP";
+/// Form type of an AVI RIFF file, which has its recording date in an `IDIT` or `DTIM` chunk.
+pub const AVI_FORM: &[u8] = b"AVI ";
+/// Seconds from the Windows FILETIME epoch (1601-01-01) to the Unix epoch.
+const FILETIME_EPOCH_OFFSET: i64 = 11_644_473_600;
+
+/// Fujifilm RAF files start with this, and keep their EXIF in an embedded JPEG preview.
+pub const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW ";
+/// Where in a RAF header the (big-endian) offset and length of the embedded JPEG are stored.
+const RAF_JPEG_OFFSET: usize = 84;
+const RAF_JPEG_LENGTH: usize = 88;
+
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+/// Type used by some writers for IFD pointers instead of LONG.
+const TYPE_IFD: u16 = 13;
+
+#[derive(Error, Debug)]
+pub enum ExifError {
+    #[error("Not a TIFF structure (expected a header like \"II*\\0\" or \"MM\\0*\")")]
+    BadHeader,
+    #[error("Offset {0:#x} points outside the metadata read")]
+    OutOfBounds(usize),
+    #[error("Tag {0:#06x} has an unexpected type or size")]
+    BadEntry(u16),
+    #[error("No DateTimeOriginal tag found")]
+    NoDate,
+    #[error("No EXIF segment found before the image data")]
+    NoExifSegment,
+    #[error("Malformed JPEG marker segment {0:#04x}")]
+    BadJpeg(u8),
+    #[error("File ends in the middle of its metadata")]
+    Truncated,
+}
+
+/// One 12 byte IFD entry.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// Where the entry itself starts, so values of 4 bytes or less can be read in place.
+    pos: usize,
+}
+
+/// Headers that start a TIFF structure, and whether it's big-endian. Besides the standard Intel
+/// and Motorola byte order marks, some RAW formats swap the magic number for their own.
+const TIFF_HEADERS: [(&[u8; 4], bool); 6] = [
+    (b"II*\0", false),
+    (b"MM\0*", true),
+    // Olympus ORF
+    (b"IIRO", false),
+    (b"IIRS", false),
+    (b"MMOR", true),
+    // Panasonic RW2
+    (b"IIU\0", false),
+];
+
+/// Offset of the JPEG preview embedded in a RAF file, read from the start of its header.
+pub fn raf_jpeg_offset(header: &[u8]) -> Result<u64, ExifError> {
+    raf_header_u32(header, RAF_JPEG_OFFSET)
+}
+
+/// Length of the JPEG preview embedded in a RAF file, read from the start of its header.
+pub fn raf_jpeg_len(header: &[u8]) -> Result<u64, ExifError> {
+    raf_header_u32(header, RAF_JPEG_LENGTH)
+}
+
+fn raf_header_u32(header: &[u8], at: usize) -> Result<u64, ExifError> {
+    let b = header.get(at..at + 4).ok_or(ExifError::OutOfBounds(at))?;
+    Ok(u64::from(u32::from_be_bytes([b[0], b[1], b[2], b[3]])))
+}
+
+/// Whether a JPEG's frame is coded losslessly, as the raw sensor data in CR2 and DNG files is.
+/// Those aren't images a viewer can show.
+pub fn is_lossless_jpeg(jpeg: &[u8]) -> bool {
+    let mut pos = JPEG_SOI.len();
+    while let Some(&[0xff, marker, len_hi, len_lo]) = jpeg.get(pos..pos + 4) {
+        match marker {
+            // Lossless start of frame markers, for Huffman and arithmetic coding.
+            0xc3 | 0xc7 | 0xcb | 0xcf => return true,
+            // Any other start of frame, or the start of scan with no frame before it.
+            0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => return false,
+            0xda => return false,
+            _ => pos += 2 + usize::from(u16::from_be_bytes([len_hi, len_lo])),
+        }
+    }
+    false
+}
+
+/// Creation time noted in a PNG `tEXt` or `iTXt` chunk, as written. `None` for chunks with
+/// another keyword, and for compressed `iTXt` text.
+pub fn png_creation_time<'a>(kind: &[u8], data: &'a [u8]) -> Option<&'a str> {
+    let split_nul = |b: &'a [u8]| b.iter().position(|&c| c == 0).map(|i| (&b[..i], &b[i + 1..]));
+    let (keyword, rest) = split_nul(data)?;
+    if !PNG_DATE_KEYWORDS.contains(&keyword) {
+        return None;
+    }
+    let text = match kind {
+        b"tEXt" => rest,
+        b"iTXt" => {
+            // Compression flag and method, then the language tag and translated keyword.
+            let (&compressed, rest) = rest.split_first()?;
+            if compressed != 0 {
+                return None;
+            }
+            let (_language, rest) = split_nul(rest.get(1..)?)?;
+            split_nul(rest)?.1
+        }
+        _ => return None,
+    };
+    std::str::from_utf8(text).ok()
+}
+
+/// Whether `data` starts with the header of a RIFF file of the given form type.
+pub fn is_riff(data: &[u8], form: &[u8]) -> bool {
+    data.starts_with(RIFF_MAGIC) && data.get(8..12) == Some(form)
+}
+
+/// Unix timestamp of an AVI `DTIM` chunk, a Windows FILETIME (100ns ticks since 1601) stored as
+/// two little-endian 32-bit words, high word first.
+pub fn riff_filetime(data: &[u8]) -> Option<i64> {
+    let word = |i: usize| {
+        let b = data.get(i..i + 4)?;
+        Some(u64::from(u32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+    };
+    let ticks = word(0)? << 32 | word(4)?;
+    Some((ticks / 10_000_000) as i64 - FILETIME_EPOCH_OFFSET).filter(|&t| t > 0)
+}
+
+/// Whether `data` starts with a TIFF header photosort understands.
+pub fn is_tiff(data: &[u8]) -> bool {
+    TIFF_HEADERS.iter().any(|(magic, _)| data.starts_with(*magic))
+}
+
+/// Bytes of a TIFF structure, looked up by their offset from its byte order mark.
+pub trait TiffData {
+    /// The `len` bytes at `offset`, or `None` if they haven't been read.
+    fn get(&self, offset: usize, len: usize) -> Option<&[u8]>;
+}
+
+impl TiffData for [u8] {
+    fn get(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        self.get(offset..offset.checked_add(len)?)
+    }
+}
+
+/// The parts of a TIFF structure read from a file so far, for structures that are read as
+/// they're walked rather than all at once.
+#[derive(Default)]
+pub struct TiffParts {
+    /// Each part's offset and bytes.
+    parts: Vec<(usize, Vec<u8>)>,
+}
+
+impl TiffParts {
+    pub fn insert(&mut self, offset: usize, data: Vec<u8>) {
+        self.parts.push((offset, data));
+    }
+
+    /// Length of the part starting at `offset`, if one does.
+    pub fn len_at(&self, offset: usize) -> Option<usize> {
+        self.parts.iter().filter(|(start, _)| *start == offset).map(|(_, data)| data.len()).max()
+    }
+
+    /// Total bytes read.
+    pub fn total_len(&self) -> usize {
+        self.parts.iter().map(|(_, data)| data.len()).sum()
+    }
+}
+
+impl TiffData for TiffParts {
+    fn get(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        self.parts
+            .iter()
+            .find_map(|(start, data)| TiffData::get(&data[..], offset.checked_sub(*start)?, len))
+    }
+}
+
+/// A TIFF structure, with `data` starting at the byte order mark. Offsets in the structure are
+/// relative to that point.
+struct Tiff<'a, D: TiffData + ?Sized> {
+    data: &'a D,
+    big_endian: bool,
+}
+
+impl<'a, D: TiffData + ?Sized> Tiff<'a, D> {
+    fn new(data: &'a D) -> Result<Tiff<'a, D>, ExifError> {
+        let magic = data.get(0, 4).ok_or(ExifError::BadHeader)?;
+        let big_endian = TIFF_HEADERS
+            .iter()
+            .find(|(header, _)| magic == *header)
+            .map(|&(_, big_endian)| big_endian)
+            .ok_or(ExifError::BadHeader)?;
+        Ok(Tiff { data, big_endian })
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], ExifError> {
+        self.data.get(offset, len).ok_or(ExifError::OutOfBounds(offset))
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, ExifError> {
+        let b = self.bytes(offset, 2)?;
+        let b = [b[0], b[1]];
+        Ok(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, ExifError> {
+        let b = self.bytes(offset, 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Ok(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    fn ifd0_offset(&self) -> Result<usize, ExifError> {
+        Ok(self.u32(4)? as usize)
+    }
+
+    fn entries(&self, ifd: usize) -> Result<Vec<Entry>, ExifError> {
+        let count = self.u16(ifd)? as usize;
+        (0..count)
+            .map(|i| {
+                let pos = ifd + 2 + i * 12;
+                Ok(Entry {
+                    tag: self.u16(pos)?,
+                    kind: self.u16(pos + 2)?,
+                    count: self.u32(pos + 4)?,
+                    pos,
+                })
+            })
+            .collect()
+    }
+
+    /// Offset of the IFD following `ifd` in its chain, if any.
+    fn next_ifd(&self, ifd: usize) -> Result<Option<usize>, ExifError> {
+        let count = self.u16(ifd)? as usize;
+        let next = self.u32(ifd + 2 + count * 12)? as usize;
+        Ok(Some(next).filter(|&n| n != 0))
+    }
+
+    /// Offsets of the IFDs an entry like ExifIFD or SubIFDs points at.
+    fn pointers(&self, entry: &Entry) -> Result<Vec<usize>, ExifError> {
+        if !matches!(entry.kind, TYPE_LONG | TYPE_IFD) {
+            return Err(ExifError::BadEntry(entry.tag));
+        }
+        let count = entry.count as usize;
+        let start = if count == 1 { entry.pos + 8 } else { self.u32(entry.pos + 8)? as usize };
+        (0..count).map(|i| Ok(self.u32(start + i * 4)? as usize)).collect()
+    }
+
+    /// First value of a SHORT or LONG entry.
+    fn uint(&self, entry: &Entry) -> Result<u32, ExifError> {
+        match (entry.kind, entry.count) {
+            (_, 0) => Err(ExifError::BadEntry(entry.tag)),
+            (TYPE_SHORT, 1..=2) => Ok(u32::from(self.u16(entry.pos + 8)?)),
+            (TYPE_SHORT, _) => Ok(u32::from(self.u16(self.u32(entry.pos + 8)? as usize)?)),
+            (TYPE_LONG, 1) => self.u32(entry.pos + 8),
+            (TYPE_LONG, _) => self.u32(self.u32(entry.pos + 8)? as usize),
+            _ => Err(ExifError::BadEntry(entry.tag)),
+        }
+    }
+
+    /// Values of a RATIONAL entry, as floats. A zero denominator gives NaN.
+    fn rationals(&self, entry: &Entry) -> Result<Vec<f64>, ExifError> {
+        if entry.kind != TYPE_RATIONAL {
+            return Err(ExifError::BadEntry(entry.tag));
+        }
+        let start = self.u32(entry.pos + 8)? as usize;
+        (0..entry.count as usize)
+            .map(|i| {
+                let (num, den) = (self.u32(start + i * 8)?, self.u32(start + i * 8 + 4)?);
+                Ok(f64::from(num) / f64::from(den))
+            })
+            .collect()
+    }
+
+    /// Text of an ASCII entry, without the trailing NUL.
+    fn ascii(&self, entry: &Entry) -> Result<&'a [u8], ExifError> {
+        if entry.kind != TYPE_ASCII {
+            return Err(ExifError::BadEntry(entry.tag));
+        }
+        let value = self.value_bytes(entry)?;
+        let end = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+        Ok(&value[..end])
+    }
+
+    /// Raw contents of a BYTE entry.
+    fn byte_values(&self, entry: &Entry) -> Result<&'a [u8], ExifError> {
+        if entry.kind != TYPE_BYTE {
+            return Err(ExifError::BadEntry(entry.tag));
+        }
+        self.value_bytes(entry)
+    }
+
+    /// Contents of an entry with one-byte values, which are stored in the entry itself when
+    /// they fit in four bytes.
+    fn value_bytes(&self, entry: &Entry) -> Result<&'a [u8], ExifError> {
+        let len = entry.count as usize;
+        if len <= 4 {
+            self.bytes(entry.pos + 8, len)
+        } else {
+            self.bytes(self.u32(entry.pos + 8)? as usize, len)
+        }
+    }
+}
+
+/// Values in one IFD, looked up by tag. This lets [`Exif::collect`] work on IFDs parsed here
+/// and ones parsed by another EXIF reader alike.
+pub trait Ifd {
+    /// Text of an ASCII entry, without the trailing NUL. `None` when there's no such entry.
+    fn ascii(&self, tag: u16) -> Result<Option<&[u8]>, ExifError>;
+    /// Values of a RATIONAL entry, as floats. A zero denominator gives NaN.
+    fn rationals(&self, tag: u16) -> Option<Vec<f64>>;
+    /// First value of a SHORT or LONG entry.
+    fn uint(&self, tag: u16) -> Option<u32>;
+    /// Contents of a BYTE entry.
+    fn bytes(&self, tag: u16) -> Option<&[u8]>;
+}
+
+/// An IFD in a TIFF structure parsed here.
+struct TiffIfd<'t, 'a, D: TiffData + ?Sized> {
+    tiff: &'t Tiff<'a, D>,
+    entries: &'t [Entry],
+}
+
+impl<D: TiffData + ?Sized> TiffIfd<'_, '_, D> {
+    fn find(&self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.tag == tag)
+    }
+}
+
+impl<D: TiffData + ?Sized> Ifd for TiffIfd<'_, '_, D> {
+    fn ascii(&self, tag: u16) -> Result<Option<&[u8]>, ExifError> {
+        self.find(tag).map(|entry| self.tiff.ascii(entry)).transpose()
+    }
+
+    fn rationals(&self, tag: u16) -> Option<Vec<f64>> {
+        self.tiff.rationals(self.find(tag)?).ok()
+    }
+
+    fn uint(&self, tag: u16) -> Option<u32> {
+        self.tiff.uint(self.find(tag)?).ok()
+    }
+
+    fn bytes(&self, tag: u16) -> Option<&[u8]> {
+        self.tiff.byte_values(self.find(tag)?).ok()
+    }
+}
+
+/// The tags photosort reads out of a TIFF structure.
+#[derive(Debug, Default, Clone)]
+pub struct Exif {
+    /// DateTimeOriginal as written, normally `YYYY:MM:DD HH:MM:SS`.
+    pub date_time_original: Option<String>,
+    /// SubSecTimeOriginal, the digits after the decimal point of DateTimeOriginal's seconds.
+    pub sub_sec_time_original: Option<String>,
+    /// OffsetTimeOriginal, DateTimeOriginal's offset from UTC.
+    pub offset_time_original: Option<String>,
+    /// GPSDateStamp and GPSTimeStamp together, in the same form. Always UTC.
+    pub gps_date_time: Option<String>,
+    /// Latitude and longitude in degrees, positive to the north and east.
+    pub gps_position: Option<(f64, f64)>,
+    /// Make and Model, as written.
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// LensModel, FocalLength and PhotographicSensitivity.
+    pub lens_model: Option<String>,
+    pub focal_length: Option<f64>,
+    pub iso: Option<u32>,
+    /// Rating and XPKeywords.
+    pub rating: Option<u32>,
+    pub keywords: Vec<String>,
+    /// Offsets of the IFDs the tags were read from, in the order they were read.
+    pub ifd_offsets: Vec<usize>,
+}
+
+impl Exif {
+    /// Fills in whatever `ifd` has that's still missing.
+    pub fn collect(&mut self, ifd: &impl Ifd) -> Result<(), ExifError> {
+        let text = |tag| Some(String::from_utf8_lossy(ifd.ascii(tag).ok()??).into_owned());
+        if self.make.is_none() {
+            self.make = text(TAG_MAKE);
+        }
+        if self.model.is_none() {
+            self.model = text(TAG_MODEL);
+        }
+        if self.lens_model.is_none() {
+            self.lens_model = text(TAG_LENS_MODEL);
+        }
+        if self.focal_length.is_none() {
+            self.focal_length = ifd
+                .rationals(TAG_FOCAL_LENGTH)
+                .and_then(|values| values.first().copied())
+                .filter(|&mm| mm.is_finite() && mm > 0.0);
+        }
+        if self.iso.is_none() {
+            self.iso = ifd.uint(TAG_ISO).filter(|&iso| iso > 0);
+        }
+        if self.rating.is_none() {
+            self.rating = ifd.uint(TAG_RATING);
+        }
+        if let (true, Some(bytes)) = (self.keywords.is_empty(), ifd.bytes(TAG_XP_KEYWORDS)) {
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .take_while(|&unit| unit != 0)
+                .collect();
+            self.keywords = String::from_utf16_lossy(&units)
+                .split(';')
+                .map(|keyword| keyword.trim().to_string())
+                .filter(|keyword| !keyword.is_empty())
+                .collect();
+        }
+        if self.date_time_original.is_none() {
+            if let Some(date) = ifd.ascii(TAG_DATE_TIME_ORIGINAL)? {
+                self.date_time_original = Some(String::from_utf8_lossy(date).into_owned());
+                // These are only meaningful next to the DateTimeOriginal they came with.
+                self.sub_sec_time_original = text(TAG_SUB_SEC_TIME_ORIGINAL);
+                self.offset_time_original = text(TAG_OFFSET_TIME_ORIGINAL);
+            }
+        }
+        if self.gps_date_time.is_none() {
+            // Some receivers write fractional seconds; they're dropped like everywhere else.
+            let hms = ifd.rationals(TAG_GPS_TIME_STAMP).unwrap_or_default();
+            if let (Some(date), [h, m, s]) = (ifd.ascii(TAG_GPS_DATE_STAMP)?, &hms[..]) {
+                let valid = |v: f64, max: f64| (0.0..max).contains(&v);
+                if valid(*h, 24.0) && valid(*m, 60.0) && valid(*s, 61.0) {
+                    let time = format!("{:02}:{:02}:{:02}", *h as u32, *m as u32, *s as u32);
+                    self.gps_date_time =
+                        Some(format!("{} {}", String::from_utf8_lossy(date), time));
+                }
+            }
+        }
+        if self.gps_position.is_none() {
+            let coordinate = |ref_tag, tag, negative: u8| {
+                let sign = match ifd.ascii(ref_tag).ok()?? {
+                    [r] if *r == negative => -1.0,
+                    [_] => 1.0,
+                    _ => return None,
+                };
+                match ifd.rationals(tag)?[..] {
+                    [d, m, s] => Some(sign * (d + m / 60.0 + s / 3600.0)),
+                    _ => None,
+                }
+            };
+            let latitude = coordinate(TAG_GPS_LATITUDE_REF, TAG_GPS_LATITUDE, b'S');
+            let longitude = coordinate(TAG_GPS_LONGITUDE_REF, TAG_GPS_LONGITUDE, b'W');
+            // The range check also throws out the NaNs zero denominators make.
+            self.gps_position = latitude.zip(longitude).filter(|(lat, lon)| {
+                (-90.0..=90.0).contains(lat) && (-180.0..=180.0).contains(lon)
+            });
+        }
+        Ok(())
+    }
+
+    fn complete(&self) -> bool {
+        self.date_time_original.is_some() && self.gps_date_time.is_some()
+    }
+}
+
+/// Walks the IFDs of `tiff` breadth first from IFD0, following Exif, GPS and SubIFD pointers and
+/// the chain of next IFDs, and hands each one's offset and entries to `visit` until it returns
+/// false. IFDs that can't be parsed are passed over, except for IFD0; the first that lay outside
+/// the data read is returned, so the caller can tell whether reading more might help.
+fn walk<D: TiffData + ?Sized>(
+    tiff: &Tiff<'_, D>,
+    mut visit: impl FnMut(usize, &[Entry]) -> Result<bool, ExifError>,
+) -> Result<Option<ExifError>, ExifError> {
+    let ifd0 = tiff.ifd0_offset()?;
+    let mut queue = std::collections::VecDeque::from([ifd0]);
+    let mut seen = Vec::new();
+    let mut out_of_bounds = None;
+    while let Some(ifd) = queue.pop_front() {
+        if seen.len() == MAX_IFDS {
+            break;
+        }
+        if seen.contains(&ifd) {
+            continue;
+        }
+        seen.push(ifd);
+        // A broken pointer to some preview IFD shouldn't fail a file whose date is elsewhere,
+        // but without a readable IFD0 there's nothing to go on.
+        let entries = match tiff.entries(ifd) {
+            Ok(entries) => entries,
+            Err(e) if ifd == ifd0 => return Err(e),
+            Err(e @ ExifError::OutOfBounds(_)) => {
+                out_of_bounds.get_or_insert(e);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        if !visit(ifd, &entries)? {
+            break;
+        }
+        for entry in &entries {
+            if !matches!(entry.tag, TAG_EXIF_IFD | TAG_GPS_IFD | TAG_SUB_IFDS) {
+                continue;
+            }
+            let pointers = match tiff.pointers(entry) {
+                Ok(pointers) => pointers,
+                // An IFD cut short by the end of the data read, its last entries included.
+                Err(e @ ExifError::OutOfBounds(_)) => {
+                    out_of_bounds.get_or_insert(e);
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            // Exif sub-IFDs jump the queue: that's where cameras put the date.
+            if entry.tag == TAG_EXIF_IFD {
+                pointers.into_iter().for_each(|pointer| queue.push_front(pointer));
+            } else {
+                queue.extend(pointers);
+            }
+        }
+        if let Ok(Some(next)) = tiff.next_ifd(ifd) {
+            queue.push_back(next);
+        }
+    }
+    Ok(out_of_bounds)
+}
+
+/// Reads the tags photosort cares about from a TIFF structure (a TIFF-based RAW file, or the
+/// body of a JPEG's APP1 segment).
+///
+/// IFDs are searched breadth first from IFD0, so the usual spot for a tag (like IFD0's Exif
+/// sub-IFD for the date) is found before any other copy. When DateTimeOriginal doesn't turn up
+/// and some IFD lay outside `data`, that is reported as [`ExifError::OutOfBounds`] so the caller
+/// can read that part.
+pub fn read(data: &(impl TiffData + ?Sized)) -> Result<Exif, ExifError> {
+    let tiff = Tiff::new(data)?;
+    let mut exif = Exif::default();
+    let out_of_bounds = walk(&tiff, |ifd, entries| {
+        exif.ifd_offsets.push(ifd);
+        exif.collect(&TiffIfd { tiff: &tiff, entries })?;
+        Ok(!exif.complete())
+    })?;
+    match out_of_bounds {
+        Some(e) if exif.date_time_original.is_none() => Err(e),
+        _ => Ok(exif),
+    }
+}
+
+/// Offsets and lengths of the JPEG images a TIFF structure holds: the thumbnail EXIF keeps in
+/// IFD1, and the larger previews RAW files store as IFDs of their own with a single JPEG strip.
+/// IFDs of raw sensor data are left out by their photometric interpretation where they say what
+/// it is; [`is_lossless_jpeg`] tells the rest apart.
+pub fn previews(data: &(impl TiffData + ?Sized)) -> Result<Vec<(usize, usize)>, ExifError> {
+    let tiff = Tiff::new(data)?;
+    let mut previews = Vec::new();
+    walk(&tiff, |_, entries| {
+        let ifd = TiffIfd { tiff: &tiff, entries };
+        let only = |tag| ifd.find(tag).filter(|e| e.count == 1).and_then(|e| tiff.uint(e).ok());
+        let mut found = vec![(
+            ifd.uint(TAG_JPEG_INTERCHANGE_FORMAT),
+            ifd.uint(TAG_JPEG_INTERCHANGE_FORMAT_LENGTH),
+        )];
+        let compression = ifd.uint(TAG_COMPRESSION);
+        let photometric = ifd.uint(TAG_PHOTOMETRIC_INTERPRETATION);
+        if matches!(compression, Some(COMPRESSION_OLD_JPEG | COMPRESSION_JPEG))
+            && !matches!(photometric, Some(PHOTOMETRIC_CFA | PHOTOMETRIC_LINEAR_RAW))
+        {
+            found.push((only(TAG_STRIP_OFFSETS), only(TAG_STRIP_BYTE_COUNTS)));
+        }
+        for (offset, len) in found.into_iter().filter_map(|(offset, len)| offset.zip(len)) {
+            let preview = (offset as usize, len as usize);
+            if len > 0 && !previews.contains(&preview) {
+                previews.push(preview);
+            }
+        }
+        Ok(true)
+    })?;
+    Ok(previews)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, Ifd, Value};
+
+    const A7_MODELS: [&str; 4] = ["ILCE-7", "ILCE-7M3", "ILCE-7RM4", "ILCE-7SM3"];
+
+    #[test]
+    fn arw_date_comes_from_the_exif_sub_ifd() {
+        for model in A7_MODELS {
+            let exif = read(&testutil::arw(model, "2022:08:09 10:11:12", 0)[..]).unwrap();
+            assert_eq!(exif.date_time_original.as_deref(), Some("2022:08:09 10:11:12"));
+            assert_eq!(exif.make.as_deref(), Some("SONY"));
+            assert_eq!(exif.model.as_deref(), Some(model));
+        }
+    }
+
+    #[test]
+    fn arw_walk_takes_in_sub_ifds_and_the_next_ifd() {
+        let arw = testutil::arw("ILCE-7M3", "2022:08:09 10:11:12", 0);
+        // IFD0, then the Exif IFD ahead of the raw data's SubIFD, then IFD1.
+        let exif = read(&arw[..]).unwrap();
+        assert_eq!(exif.ifd_offsets.len(), 4);
+        assert_eq!(exif.ifd_offsets[0], 8);
+        // The preview from IFD0 and the thumbnail from IFD1, but not the raw data.
+        assert_eq!(previews(&arw[..]).unwrap(), vec![(0x0002_0000, 400_000), (0x0001_2000, 9_000)]);
+    }
+
+    #[test]
+    fn exif_ifd_is_found_down_the_next_ifd_chain() {
+        let exif = Ifd::new(vec![(0x9003, Value::Ascii("2022:08:09 10:11:12"))]);
+        let mut ifd0 = Ifd::new(vec![(0x010f, Value::Ascii("SONY"))]);
+        ifd0.next = Some(Box::new(Ifd::new(vec![(0x8769, Value::Ifds(vec![exif]))])));
+        let exif = read(&testutil::tiff(&ifd0, true)[..]).unwrap();
+        assert_eq!(exif.date_time_original.as_deref(), Some("2022:08:09 10:11:12"));
+    }
+
+    #[test]
+    fn arw_read_in_parts_asks_for_the_rest() {
+        let arw = testutil::arw("ILCE-7RM4", "2022:08:09 10:11:12", 8 * 1024);
+        let mut parts = TiffParts::default();
+        parts.insert(0, arw[..4096].to_vec());
+        let offset = match read(&parts) {
+            Err(ExifError::OutOfBounds(offset)) => offset,
+            result => panic!("expected to be asked for more, got {:?}", result),
+        };
+        assert!(offset >= 4096);
+        parts.insert(offset, arw[offset..].to_vec());
+        let exif = read(&parts).unwrap();
+        assert_eq!(exif.date_time_original.as_deref(), Some("2022:08:09 10:11:12"));
+    }
+
+    #[test]
+    fn both_byte_orders_read_alike() {
+        let exif = Ifd::new(vec![(0x9003, Value::Ascii("2021:06:07 12:34:56"))]);
+        let ifd0 = Ifd::new(vec![
+            (0x010f, Value::Ascii("Acme")),
+            (0x4746, Value::Short(vec![4])),
+            (0x8769, Value::Ifds(vec![exif])),
+        ]);
+        for big_endian in [false, true] {
+            let exif = read(&testutil::tiff(&ifd0, big_endian)[..]).unwrap();
+            assert_eq!(exif.date_time_original.as_deref(), Some("2021:06:07 12:34:56"));
+            assert_eq!(exif.make.as_deref(), Some("Acme"));
+            assert_eq!(exif.rating, Some(4));
+        }
+    }
+
+    #[test]
+    fn read_survives_being_cut_short() {
+        let tiff = testutil::exif_tiff("2021:06:07 12:34:56");
+        for len in 0..tiff.len() {
+            // The date comes last, so each cut should ask for more.
+            match read(&tiff[..len]) {
+                Err(ExifError::BadHeader | ExifError::OutOfBounds(_)) => {}
+                result => panic!("cut to {} bytes: {:?}", len, result),
+            }
+            let _ = previews(&tiff[..len]);
+        }
+    }
+
+    #[test]
+    fn headers_must_be_tiff() {
+        assert!(matches!(read(&b"MM\0+\0\0\0\x08"[..]), Err(ExifError::BadHeader)));
+        assert!(matches!(read(&b""[..]), Err(ExifError::BadHeader)));
+        assert!(is_tiff(b"IIRO\x08\0\0\0") && !is_tiff(b"II"));
+    }
+
+    #[test]
+    fn ifds_pointing_in_circles_are_walked_once() {
+        let mut tiff = testutil::tiff(&Ifd::new(vec![(0x010f, Value::Ascii("Acme"))]), false);
+        // IFD0's next IFD is itself.
+        tiff[8 + 2 + 12..8 + 2 + 12 + 4].copy_from_slice(&8u32.to_le_bytes());
+        let exif = read(&tiff[..]).unwrap();
+        assert_eq!(exif.ifd_offsets, [8]);
+        assert_eq!(exif.date_time_original, None);
+    }
+
+    #[test]
+    fn long_ifd_chains_are_cut_off() {
+        let mut ifd = Ifd::new(vec![(0x9003, Value::Ascii("2021:06:07 12:34:56"))]);
+        for _ in 0..MAX_IFDS {
+            let mut before = Ifd::new(vec![(0x0103, Value::Short(vec![6]))]);
+            before.next = Some(Box::new(ifd));
+            ifd = before;
+        }
+        let exif = read(&testutil::tiff(&ifd, false)[..]).unwrap();
+        assert_eq!(exif.ifd_offsets.len(), MAX_IFDS);
+        assert_eq!(exif.date_time_original, None);
+    }
+
+    #[test]
+    fn pointers_out_of_bounds_are_reported_when_the_date_is_missing() {
+        let ifd0 = Ifd::new(vec![(0x8769, Value::Long(vec![0x00ff_0000]))]);
+        let tiff = testutil::tiff(&ifd0, false);
+        assert!(matches!(read(&tiff[..]), Err(ExifError::OutOfBounds(0x00ff_0000))));
+        let ifd0 = Ifd::new(vec![
+            (0x014a, Value::Long(vec![0x00ff_0000])),
+            (0x8769, Value::Ifds(vec![Ifd::new(vec![(0x9003, Value::Ascii("2021:06:07"))])])),
+        ]);
+        let exif = read(&testutil::tiff(&ifd0, false)[..]).unwrap();
+        assert_eq!(exif.date_time_original.as_deref(), Some("2021:06:07"));
+    }
+
+    #[test]
+    fn entries_of_the_wrong_type_are_refused() {
+        let exif = Ifd::new(vec![(0x9003, Value::Short(vec![2021]))]);
+        let tiff = testutil::tiff(&Ifd::new(vec![(0x8769, Value::Ifds(vec![exif]))]), false);
+        assert!(matches!(read(&tiff[..]), Err(ExifError::BadEntry(0x9003))));
+        // Other tags of the wrong type are passed over.
+        let ifd0 = Ifd::new(vec![
+            (0x010f, Value::Long(vec![1])),
+            (0x8827, Value::Ascii("100")),
+            (0x9003, Value::Ascii("2021:06:07 12:34:56")),
+        ]);
+        let exif = read(&testutil::tiff(&ifd0, true)[..]).unwrap();
+        assert_eq!((exif.make, exif.iso), (None, None));
+    }
+
+    #[test]
+    fn gps_dates_and_positions_need_sensible_values() {
+        let gps = |time: Vec<(u32, u32)>, latitude: Vec<(u32, u32)>| {
+            let gps = Ifd::new(vec![
+                (0x0001, Value::Ascii("S")),
+                (0x0002, Value::Rational(latitude)),
+                (0x0003, Value::Ascii("E")),
+                (0x0004, Value::Rational(vec![(13, 1), (24, 1), (0, 1)])),
+                (0x0007, Value::Rational(time)),
+                (0x001d, Value::Ascii("2021:06:07")),
+            ]);
+            read(&testutil::tiff(&Ifd::new(vec![(0x8825, Value::Ifds(vec![gps]))]), false)[..])
+        };
+        let exif = gps(vec![(10, 1), (20, 1), (305, 10)], vec![(33, 1), (30, 1), (0, 1)]).unwrap();
+        assert_eq!(exif.gps_date_time.as_deref(), Some("2021:06:07 10:20:30"));
+        assert_eq!(exif.gps_position, Some((-33.5, 13.4)));
+        let exif = gps(vec![(24, 1), (0, 1), (0, 1)], vec![(33, 0), (0, 1), (0, 1)]).unwrap();
+        assert_eq!((exif.gps_date_time, exif.gps_position), (None, None));
+    }
+
+    #[test]
+    fn png_creation_time_reads_text_chunks() {
+        let text = png_creation_time(b"tEXt", b"Creation Time\x002020:02:01 14:32:14");
+        assert_eq!(text, Some("2020:02:01 14:32:14"));
+        let itxt = png_creation_time(b"iTXt", b"date:create\0\0\0en\0Erstellt\x002020-02-01");
+        assert_eq!(itxt, Some("2020-02-01"));
+        // Compressed, another keyword, another chunk type, and cut short.
+        assert_eq!(png_creation_time(b"iTXt", b"date:create\0\x01\0\0\0x\x9c"), None);
+        assert_eq!(png_creation_time(b"tEXt", b"Software\0GIMP"), None);
+        assert_eq!(png_creation_time(b"zTXt", b"Creation Time\0\0x\x9c"), None);
+        let itxt = b"date:create\0\0\0en\0\x002020-02-01";
+        for len in 0..itxt.len() - "2020-02-01".len() {
+            assert_eq!(png_creation_time(b"iTXt", &itxt[..len]), None, "cut to {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn riff_filetime_reads_both_words() {
+        let ticks: u64 = (1_580_567_534 + 11_644_473_600) * 10_000_000;
+        let data = [(ticks >> 32) as u32, ticks as u32].map(u32::to_le_bytes).concat();
+        assert_eq!(riff_filetime(&data), Some(1_580_567_534));
+        assert_eq!(riff_filetime(&data[..7]), None);
+        assert_eq!(riff_filetime(&[0; 8]), None);
+        assert!(is_riff(b"RIFF\0\0\0\0AVI ", AVI_FORM) && !is_riff(b"RIFF\0\0\0\0AVI", AVI_FORM));
+    }
+
+    #[test]
+    fn lossless_jpegs_are_told_apart_by_their_frame() {
+        let jpeg = |sof: u8| [&JPEG_SOI[..], &[0xff, 0xc4, 0, 4, 0, 0, 0xff, sof, 0, 2]].concat();
+        assert!(is_lossless_jpeg(&jpeg(0xc3)));
+        assert!(!is_lossless_jpeg(&jpeg(0xc0)));
+        let lossless = jpeg(0xc3);
+        for len in 0..lossless.len() - 3 {
+            assert!(!is_lossless_jpeg(&lossless[..len]));
+        }
+        // A segment length running past the end.
+        assert!(!is_lossless_jpeg(&[0xff, 0xd8, 0xff, 0xe1, 0xff, 0xff, 0xff, 0xc3]));
+    }
+
+    #[test]
+    fn raf_offsets_are_read_from_the_header() {
+        let mut header = [RAF_MAGIC, &[0; 68]].concat();
+        header.extend_from_slice(&0x1234u32.to_be_bytes());
+        header.extend_from_slice(&0x5678u32.to_be_bytes());
+        assert_eq!(raf_jpeg_offset(&header).unwrap(), 0x1234);
+        assert_eq!(raf_jpeg_len(&header).unwrap(), 0x5678);
+        assert!(raf_jpeg_len(&header[..90]).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::path::Path;
+
+use serde_json::Value;
+use thiserror::Error;
+use tracing::debug;
+
+use crate::Date;
+
+/// Tags asked of exiftool, most specific first: when the shutter fired (with the fraction of a
+/// second and UTC offset exiftool has pieced together, if it could), then when the file's
+/// contents were created, as videos and some scans record it.
+const DATE_TAGS: &[&str] =
+    &["SubSecDateTimeOriginal", "DateTimeOriginal", "CreateDate", "MediaCreateDate", "DateCreated"];
+
+#[derive(Error, Debug)]
+pub enum ExiftoolError {
+    #[error("Could not run exiftool (is it installed and on PATH?): {0}")]
+    NotRunnable(std::io::Error),
+}
+
+/// Checks that exiftool can be run at all, so a missing install is reported once up front rather
+/// than for every file.
+pub fn check() -> Result<(), ExiftoolError> {
+    std::process::Command::new("exiftool")
+        .arg("-ver")
+        .output()
+        .map(|_| ())
+        .map_err(ExiftoolError::NotRunnable)
+}
+
+/// Asks exiftool for the capture date of `path`, for formats photosort can't read itself.
+/// exiftool failing on the file, or finding no date, gives `None`.
+pub async fn date(path: &Path) -> Result<Option<Date>, ExiftoolError> {
+    let output = tokio::process::Command::new("exiftool")
+        .arg("-json")
+        .args(DATE_TAGS.iter().map(|tag| format!("-{}", tag)))
+        // Keeps file names that start with a dash from being read as options.
+        .arg("--")
+        .arg(path)
+        .output()
+        .await
+        .map_err(ExiftoolError::NotRunnable)?;
+    if !output.status.success() {
+        debug!(stderr = %String::from_utf8_lossy(&output.stderr).trim(), "exiftool failed");
+        return Ok(None);
+    }
+    // One object per file, with each tag that was found as a key.
+    let tags = match serde_json::from_slice::<Vec<Value>>(&output.stdout) {
+        Ok(mut files) if !files.is_empty() => files.swap_remove(0),
+        _ => return Ok(None),
+    };
+    Ok(DATE_TAGS.iter().find_map(|tag| tags.get(tag)?.as_str().and_then(Date::from_text)))
+}
@@ -0,0 +1,288 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::Timelike;
+
+use crate::cli::DateSource;
+use crate::filename::FilenamePatterns;
+use crate::{exiftool, read_metadata, sidecar, Date, FileParseError, Metadata};
+
+/// A file being dated. Extractors share it so the file's own metadata is read at most once,
+/// however many of them want it.
+pub struct Probe<'a> {
+    pub path: &'a Path,
+    metadata: Option<Result<Metadata, FileParseError>>,
+}
+
+impl<'a> Probe<'a> {
+    pub fn new(path: &'a Path) -> Probe<'a> {
+        Probe { path, metadata: None }
+    }
+
+    /// The file's own metadata, read the first time it's asked for. `None` when it couldn't be
+    /// read.
+    pub async fn metadata(&mut self) -> Option<&Metadata> {
+        if self.metadata.is_none() {
+            self.metadata = Some(read_metadata(self.path).await);
+        }
+        self.metadata.as_ref()?.as_ref().ok()
+    }
+
+    /// The result of reading the metadata, or `None` if nothing asked for it.
+    pub fn into_metadata(self) -> Option<Result<Metadata, FileParseError>> {
+        self.metadata
+    }
+}
+
+/// One place a file's capture date can come from.
+#[async_trait]
+pub trait DateExtractor: Send + Sync {
+    /// How the extractor is named on the command line and in output.
+    fn name(&self) -> &'static str;
+
+    /// The date this extractor finds for the file, if it finds one. Errors are for when the
+    /// extractor itself can't work, not for files it has nothing to say about.
+    async fn extract(&self, probe: &mut Probe<'_>) -> Result<Option<Date>>;
+}
+
+/// An XMP sidecar next to the file.
+pub struct XmpExtractor;
+
+#[async_trait]
+impl DateExtractor for XmpExtractor {
+    fn name(&self) -> &'static str {
+        DateSource::Xmp.name()
+    }
+
+    async fn extract(&self, probe: &mut Probe<'_>) -> Result<Option<Date>> {
+        Ok(sidecar::xmp_date(probe.path).await)
+    }
+}
+
+/// DateTimeOriginal from the file's own metadata, or the format's equivalent.
+pub struct ExifExtractor;
+
+#[async_trait]
+impl DateExtractor for ExifExtractor {
+    fn name(&self) -> &'static str {
+        DateSource::Exif.name()
+    }
+
+    async fn extract(&self, probe: &mut Probe<'_>) -> Result<Option<Date>> {
+        Ok(probe.metadata().await.and_then(|m| m.date.clone()))
+    }
+}
+
+/// The GPS timestamp in the file's own metadata.
+pub struct GpsExtractor;
+
+#[async_trait]
+impl DateExtractor for GpsExtractor {
+    fn name(&self) -> &'static str {
+        DateSource::Gps.name()
+    }
+
+    async fn extract(&self, probe: &mut Probe<'_>) -> Result<Option<Date>> {
+        Ok(probe.metadata().await.and_then(|m| m.gps_date.clone()))
+    }
+}
+
+/// Whatever date exiftool finds.
+pub struct ExiftoolExtractor;
+
+#[async_trait]
+impl DateExtractor for ExiftoolExtractor {
+    fn name(&self) -> &'static str {
+        DateSource::Exiftool.name()
+    }
+
+    async fn extract(&self, probe: &mut Probe<'_>) -> Result<Option<Date>> {
+        Ok(exiftool::date(probe.path).await?)
+    }
+}
+
+/// A Google Takeout metadata file next to the file.
+pub struct TakeoutExtractor;
+
+#[async_trait]
+impl DateExtractor for TakeoutExtractor {
+    fn name(&self) -> &'static str {
+        DateSource::Json.name()
+    }
+
+    async fn extract(&self, probe: &mut Probe<'_>) -> Result<Option<Date>> {
+        Ok(sidecar::takeout_date(probe.path).await)
+    }
+}
+
+/// A date in the file's name.
+pub struct FilenameExtractor {
+    patterns: FilenamePatterns,
+}
+
+#[async_trait]
+impl DateExtractor for FilenameExtractor {
+    fn name(&self) -> &'static str {
+        DateSource::Filename.name()
+    }
+
+    async fn extract(&self, probe: &mut Probe<'_>) -> Result<Option<Date>> {
+        Ok(self.patterns.date(probe.path))
+    }
+}
+
+/// The file's last modification time, in local time.
+pub struct MtimeExtractor;
+
+#[async_trait]
+impl DateExtractor for MtimeExtractor {
+    fn name(&self) -> &'static str {
+        DateSource::Mtime.name()
+    }
+
+    async fn extract(&self, probe: &mut Probe<'_>) -> Result<Option<Date>> {
+        let modified = tokio::fs::metadata(probe.path)
+            .await
+            .and_then(|m| m.modified())
+            .context("Failed to read modification time")?;
+        let local = chrono::DateTime::<chrono::Local>::from(modified);
+        // Modification times carry nanoseconds; dates elsewhere are to the second.
+        let date = Date::new(local.naive_local().with_nanosecond(0).unwrap_or_default());
+        Ok(Some(date.with_offset(Some(local.offset().local_minus_utc()))))
+    }
+}
+
+/// The extractor for a date source. `patterns` are what the filename source matches against.
+pub fn get_extractor(source: DateSource, patterns: &FilenamePatterns) -> Box<dyn DateExtractor> {
+    match source {
+        DateSource::Xmp => Box::new(XmpExtractor),
+        DateSource::Exif => Box::new(ExifExtractor),
+        DateSource::Gps => Box::new(GpsExtractor),
+        DateSource::Exiftool => Box::new(ExiftoolExtractor),
+        DateSource::Json => Box::new(TakeoutExtractor),
+        DateSource::Filename => Box::new(FilenameExtractor { patterns: patterns.clone() }),
+        DateSource::Mtime => Box::new(MtimeExtractor),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::testutil::{self, Ifd, Value};
+
+    /// Writes `contents` to `name` in a new temporary directory.
+    fn file(name: &str, contents: &[u8]) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    /// A JPEG whose EXIF says it was taken at 2021-06-07T12:34:56.
+    fn dated_jpeg() -> (tempfile::TempDir, PathBuf) {
+        file("a.jpg", &testutil::jpeg(&testutil::exif_tiff("2021:06:07 12:34:56")))
+    }
+
+    async fn extract(extractor: &dyn DateExtractor, path: &Path) -> Option<String> {
+        let mut probe = Probe::new(path);
+        extractor.extract(&mut probe).await.unwrap().map(|date| date.to_string())
+    }
+
+    #[tokio::test]
+    async fn exif_reads_date_time_original() {
+        let (_dir, path) = dated_jpeg();
+        assert_eq!(extract(&ExifExtractor, &path).await.as_deref(), Some("2021-06-07T12:34:56"));
+    }
+
+    #[tokio::test]
+    async fn exif_reads_arw_files_past_the_first_read() {
+        let arw = testutil::arw("ILCE-7M3", "2022:08:09 10:11:12", 16 * 1024);
+        let (_dir, path) = file("DSC01234.ARW", &arw);
+        assert_eq!(extract(&ExifExtractor, &path).await.as_deref(), Some("2022-08-09T10:11:12"));
+    }
+
+    #[tokio::test]
+    async fn exif_has_nothing_for_a_file_without_metadata() {
+        let (_dir, path) = file("a.jpg", b"not a photo, just some text");
+        assert_eq!(extract(&ExifExtractor, &path).await, None);
+        assert_eq!(extract(&GpsExtractor, &path).await, None);
+    }
+
+    #[tokio::test]
+    async fn gps_reads_date_and_time_stamps() {
+        let gps = Ifd::new(vec![
+            (0x0007, Value::Rational(vec![(10, 1), (20, 1), (30, 1)])),
+            (0x001d, Value::Ascii("2021:06:07")),
+        ]);
+        let tiff = testutil::tiff(&Ifd::new(vec![(0x8825, Value::Ifds(vec![gps]))]), true);
+        let (_dir, path) = file("a.jpg", &testutil::jpeg(&tiff));
+        assert_eq!(extract(&GpsExtractor, &path).await.as_deref(), Some("2021-06-07T10:20:30"));
+        assert_eq!(extract(&ExifExtractor, &path).await, None);
+    }
+
+    #[tokio::test]
+    async fn metadata_is_read_once_for_every_extractor() {
+        let (_dir, path) = dated_jpeg();
+        let mut probe = Probe::new(&path);
+        assert!(ExifExtractor.extract(&mut probe).await.unwrap().is_some());
+        std::fs::remove_file(&path).unwrap();
+        // Answered from what was read the first time.
+        assert!(ExifExtractor.extract(&mut probe).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn xmp_reads_the_sidecar() {
+        let (dir, path) = file("a.jpg", b"");
+        let xmp = r#"<x:xmpmeta><rdf:Description exif:DateTimeOriginal="2019-02-03T04:05:06"/>"#;
+        std::fs::write(dir.path().join("a.xmp"), xmp).unwrap();
+        assert_eq!(extract(&XmpExtractor, &path).await.as_deref(), Some("2019-02-03T04:05:06"));
+        assert_eq!(extract(&XmpExtractor, &dir.path().join("b.jpg")).await, None);
+    }
+
+    #[tokio::test]
+    async fn takeout_reads_the_photo_taken_time() {
+        let (dir, path) = file("a.jpg", b"");
+        let json = r#"{"photoTakenTime": {"timestamp": "1623069296"}}"#;
+        std::fs::write(dir.path().join("a.jpg.supplemental-metadata.json"), json).unwrap();
+        assert_eq!(extract(&TakeoutExtractor, &path).await.as_deref(), Some("2021-06-07T12:34:56"));
+    }
+
+    #[tokio::test]
+    async fn filename_matches_the_patterns() {
+        let extractor = FilenameExtractor { patterns: FilenamePatterns::new(&[]).unwrap() };
+        let path = Path::new("IMG_20200101_123456.jpg");
+        assert_eq!(extract(&extractor, path).await.as_deref(), Some("2020-01-01T12:34:56"));
+        assert_eq!(extract(&extractor, Path::new("IMG_1234.jpg")).await, None);
+    }
+
+    #[tokio::test]
+    async fn mtime_reads_the_modification_time() {
+        let (_dir, path) = file("a.jpg", b"");
+        let local = chrono::NaiveDate::from_ymd_opt(2018, 5, 6).unwrap().and_hms_opt(7, 8, 9);
+        let modified = chrono::TimeZone::from_local_datetime(&chrono::Local, &local.unwrap());
+        let modified = modified.unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified.into()).unwrap();
+        assert_eq!(extract(&MtimeExtractor, &path).await.as_deref(), Some("2018-05-06T07:08:09"));
+    }
+
+    #[tokio::test]
+    async fn mtime_fails_for_a_missing_file() {
+        let mut probe = Probe::new(Path::new("/nonexistent/photosort/a.jpg"));
+        assert!(MtimeExtractor.extract(&mut probe).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn exiftool_reads_the_date_or_fails_without_exiftool() {
+        let (_dir, path) = dated_jpeg();
+        let mut probe = Probe::new(&path);
+        let date = ExiftoolExtractor.extract(&mut probe).await;
+        match exiftool::check() {
+            Ok(()) => assert_eq!(date.unwrap().unwrap().to_string(), "2021-06-07T12:34:56"),
+            Err(_) => assert!(date.is_err()),
+        }
+    }
+}
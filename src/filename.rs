@@ -0,0 +1,155 @@
+use std::convert::TryFrom;
+use std::path::Path;
+
+use regex::Regex;
+use thiserror::Error;
+
+use crate::Date;
+
+/// Names phones and apps give their files, most specific first. Each needs `year`, `month` and
+/// `day` groups; the time of day is optional.
+const BUILTIN_PATTERNS: &[&str] = &[
+    // Screenshot_2021-03-04-10-22-33.png
+    concat!(
+        r"(?:^|\D)(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})",
+        r"[-_ T](?P<hour>\d{2})[-.:](?P<minute>\d{2})[-.:](?P<second>\d{2})(?:\D|$)",
+    ),
+    // PXL_20220102_123456789.jpg, IMG_20200101_123456.jpg
+    concat!(
+        r"(?:^|\D)(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})",
+        r"[-_](?P<hour>\d{2})(?P<minute>\d{2})(?P<second>\d{2})",
+    ),
+    // IMG-20200101-WA0001.jpg, as WhatsApp saves them
+    r"(?:^|\D)(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})-WA\d",
+    // 2020-01-01 Birthday.jpg
+    r"(?:^|\D)(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})(?:\D|$)",
+];
+
+#[derive(Error, Debug)]
+pub enum PatternError {
+    #[error("Invalid filename pattern {0:?}: {1}")]
+    Invalid(String, regex::Error),
+    #[error("Filename pattern {0:?} needs named groups year, month and day")]
+    MissingGroups(String),
+}
+
+/// Reads dates out of file names like `IMG-20200101-WA0001.jpg`, for files with no metadata to
+/// go on.
+#[derive(Debug, Clone)]
+pub struct FilenamePatterns {
+    patterns: Vec<Regex>,
+}
+
+impl FilenamePatterns {
+    /// Compiles the built-in patterns, with `extra` ones (from the config file) tried first.
+    pub fn new(extra: &[String]) -> Result<FilenamePatterns, PatternError> {
+        let patterns = extra
+            .iter()
+            .map(String::as_str)
+            .chain(BUILTIN_PATTERNS.iter().copied())
+            .map(|pattern| {
+                let regex =
+                    Regex::new(pattern).map_err(|e| PatternError::Invalid(pattern.into(), e))?;
+                let names: Vec<_> = regex.capture_names().flatten().collect();
+                if !["year", "month", "day"].iter().all(|group| names.contains(group)) {
+                    return Err(PatternError::MissingGroups(pattern.into()));
+                }
+                Ok(regex)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(FilenamePatterns { patterns })
+    }
+
+    /// Date in the name of `path`, from the first pattern that matches with a plausible date.
+    pub fn date(&self, path: &Path) -> Option<Date> {
+        let name = path.file_name()?.to_str()?;
+        self.patterns.iter().find_map(|pattern| {
+            let captures = pattern.captures(name)?;
+            let part = |group: &str, max: u32| {
+                let value = captures.name(group).map_or("00", |m| m.as_str());
+                Some(value).filter(|v| v.parse::<u32>().is_ok_and(|n| n <= max))
+            };
+            let (month, day) = (part("month", 12)?, part("day", 31)?);
+            if month.parse::<u32>() == Ok(0) || day.parse::<u32>() == Ok(0) {
+                return None;
+            }
+            let date = format!(
+                "{}:{:0>2}:{:0>2} {:0>2}:{:0>2}:{:0>2}",
+                part("year", 9999)?,
+                month,
+                day,
+                part("hour", 23)?,
+                part("minute", 59)?,
+                part("second", 59)?
+            );
+            Date::try_from(date).ok()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(patterns: &FilenamePatterns, name: &str) -> Option<String> {
+        patterns.date(Path::new(name)).map(|date| date.to_string())
+    }
+
+    #[test]
+    fn builtin_patterns_match_what_phones_and_apps_write() {
+        let patterns = FilenamePatterns::new(&[]).unwrap();
+        let dated = [
+            ("Screenshot_2021-03-04-10-22-33.png", "2021-03-04T10:22:33"),
+            ("PXL_20220102_123456789.jpg", "2022-01-02T12:34:56"),
+            ("IMG_20200101_123456.jpg", "2020-01-01T12:34:56"),
+            ("IMG-20200101-WA0001.jpg", "2020-01-01T00:00:00"),
+            ("2020-01-01 Birthday.jpg", "2020-01-01T00:00:00"),
+        ];
+        for (name, expected) in dated {
+            assert_eq!(date(&patterns, name).as_deref(), Some(expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn implausible_dates_are_passed_over() {
+        let patterns = FilenamePatterns::new(&[]).unwrap();
+        for name in [
+            "IMG_1234.jpg",
+            "IMG_20201301_123456.jpg",
+            "IMG_20200230_123456.jpg",
+            "IMG_20200100_123456.jpg",
+            "IMG_20200101_246000.jpg",
+            "120200101-WA0001.jpg",
+            "",
+        ] {
+            assert_eq!(date(&patterns, name), None, "{}", name);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_that_arent_utf8_have_no_date() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let patterns = FilenamePatterns::new(&[]).unwrap();
+        let name = OsStr::from_bytes(b"IMG_20200101_123456\xff.jpg");
+        assert!(patterns.date(Path::new(name)).is_none());
+    }
+
+    #[test]
+    fn extra_patterns_are_tried_first() {
+        let extra = [r"(?P<day>\d{2})\.(?P<month>\d{2})\.(?P<year>\d{4})".to_string()];
+        let patterns = FilenamePatterns::new(&extra).unwrap();
+        let name = "Urlaub 24.12.2019 2020-01-01.jpg";
+        assert_eq!(date(&patterns, name).as_deref(), Some("2019-12-24T00:00:00"));
+    }
+
+    #[test]
+    fn extra_patterns_must_compile_and_name_the_date() {
+        let invalid = FilenamePatterns::new(&["(".to_string()]);
+        assert!(matches!(invalid, Err(PatternError::Invalid(..))));
+        let missing = FilenamePatterns::new(&[r"(?P<year>\d{4})".to_string()]);
+        assert!(matches!(missing, Err(PatternError::MissingGroups(..))));
+    }
+}
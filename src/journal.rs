@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// One line of a journal.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum Record {
+    /// What the run was started with, so it can be run again: its arguments, the directory it
+    /// was run in (which the paths after it are relative to), the library, and for `sort` the
+    /// files it was given, including any read from --files-from.
+    Start { args: Vec<String>, cwd: PathBuf, library: PathBuf, inputs: Vec<PathBuf> },
+    /// Written, and synced to disk, before a file is moved.
+    Intent { source: PathBuf, dest: PathBuf },
+    /// Written once a file has been moved.
+    Done { source: PathBuf, dest: PathBuf },
+    /// Written by `photosort undo` once a move has been reversed.
+    Undone { source: PathBuf, dest: PathBuf },
+    /// The run got to the end, whether or not every file could be sorted.
+    Finish,
+}
+
+/// An append-only record of the moves a `photosort sort` or `photosort apply` run makes, kept in
+/// the `journal` directory in photosort's data directory, for `photosort resume` to pick the run
+/// up again should it be interrupted, and for `photosort undo` to reverse it.
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Starts the journal of a new run that sorts `inputs` into `library`.
+    pub fn create(library: &Path, inputs: &[PathBuf]) -> Result<Journal> {
+        let dir = journal_dir()?;
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create journal directory {}", dir.display()))?;
+        // Named so they sort in the order the runs started.
+        let name = format!("{}-{}.jsonl", Local::now().format("%Y%m%dT%H%M%S"), std::process::id());
+        let path = dir.join(name);
+        let file = File::options()
+            .append(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("Failed to create journal {}", path.display()))?;
+        let journal = Journal { path, file: Mutex::new(file) };
+        let start = Record::Start {
+            args: std::env::args_os().map(|arg| arg.to_string_lossy().into_owned()).collect(),
+            cwd: std::env::current_dir().context("Failed to find the current directory")?,
+            library: library.to_path_buf(),
+            inputs: inputs.to_vec(),
+        };
+        journal.write(&start, true).context("Failed to write to the journal")?;
+        Ok(journal)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records that `source` is about to be moved to `dest`. It's on disk before this returns,
+    /// so a move that's interrupted is never lost track of.
+    pub fn intend(&self, source: &Path, dest: &Path) -> io::Result<()> {
+        let (source, dest) = (source.to_path_buf(), dest.to_path_buf());
+        self.write(&Record::Intent { source, dest }, true)
+    }
+
+    /// Records that `source` has been moved to `dest`. This isn't synced: should it be lost,
+    /// `photosort resume` finds the file at `dest` anyway.
+    pub fn done(&self, source: &Path, dest: &Path) -> io::Result<()> {
+        let (source, dest) = (source.to_path_buf(), dest.to_path_buf());
+        self.write(&Record::Done { source, dest }, false)
+    }
+
+    /// Records that moving `source` to `dest` has been reversed.
+    pub fn undone(&self, source: &Path, dest: &Path) -> io::Result<()> {
+        let (source, dest) = (source.to_path_buf(), dest.to_path_buf());
+        self.write(&Record::Undone { source, dest }, false)
+    }
+
+    pub fn finish(&self) -> io::Result<()> {
+        self.write(&Record::Finish, true)
+    }
+
+    fn write(&self, record: &Record, sync: bool) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        // In one write, so records from files being sorted at once don't interleave.
+        file.write_all(line.as_bytes())?;
+        if sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// A run read back from its journal.
+pub struct Run {
+    pub path: PathBuf,
+    pub args: Vec<String>,
+    pub cwd: PathBuf,
+    pub library: PathBuf,
+    pub inputs: Vec<PathBuf>,
+    /// Every move the run set out to make, in order.
+    pub moves: Vec<Move>,
+    pub finished: bool,
+}
+
+pub struct Move {
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    /// Whether the move was recorded as made.
+    pub done: bool,
+    /// Whether `photosort undo` has reversed it since.
+    pub undone: bool,
+}
+
+impl Run {
+    /// Reads the journal at `path`, or with None, the latest run's.
+    pub fn read(path: Option<&Path>) -> Result<Run> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => latest()?,
+        };
+        let context = || format!("Failed to read journal {}", path.display());
+        let file = File::open(&path).with_context(context)?;
+        let mut lines = BufReader::new(file).lines().peekable();
+        let mut records = Vec::new();
+        while let Some(line) = lines.next() {
+            let line = line.with_context(context)?;
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                // Cut short by whatever interrupted the run.
+                Err(_) if lines.peek().is_none() => break,
+                Err(e) => return Err(e).with_context(context),
+            }
+        }
+        let mut records = records.into_iter();
+        let (args, cwd, library, inputs) = match records.next() {
+            Some(Record::Start { args, cwd, library, inputs }) => (args, cwd, library, inputs),
+            _ => bail!("Journal {} doesn't say how the run was started", path.display()),
+        };
+        let mut run = Run { path, args, cwd, library, inputs, moves: Vec::new(), finished: false };
+        for record in records {
+            match record {
+                Record::Intent { source, dest } => {
+                    run.moves.push(Move { source, dest, done: false, undone: false })
+                }
+                Record::Done { source, dest } => {
+                    let made = run.moves.iter_mut().rev().find(|m| m.source == source && !m.done);
+                    match made {
+                        Some(made) => made.done = true,
+                        None => run.moves.push(Move { source, dest, done: true, undone: false }),
+                    }
+                }
+                Record::Undone { source, dest } => {
+                    let made = run.moves.iter_mut().find(|m| m.source == source && m.dest == dest);
+                    if let Some(made) = made {
+                        made.undone = true;
+                    }
+                }
+                Record::Finish => run.finished = true,
+                Record::Start { .. } => bail!("Journal {} starts twice", run.path.display()),
+            }
+        }
+        Ok(run)
+    }
+
+    /// Sources of the moves recorded as made.
+    pub fn moved(&self) -> HashSet<PathBuf> {
+        self.moves.iter().filter(|m| m.done).map(|m| m.source.clone()).collect()
+    }
+
+    /// Opens the run's journal to carry on recording in it.
+    pub fn reopen(&self) -> Result<Journal> {
+        let context = || format!("Failed to open journal {}", self.path.display());
+        let file = File::options().append(true).open(&self.path).with_context(context)?;
+        // Drops a record cut short by the interruption, so the next starts on a line of its own.
+        let text = std::fs::read(&self.path).with_context(context)?;
+        let complete = text.iter().rposition(|&b| b == b'\n').map_or(0, |end| end + 1);
+        file.set_len(complete as u64).with_context(context)?;
+        Ok(Journal { path: self.path.clone(), file: Mutex::new(file) })
+    }
+}
+
+fn journal_dir() -> Result<PathBuf> {
+    let dir = config::data_dir().context("Could not find a data directory to keep journals in")?;
+    Ok(dir.join("journal"))
+}
+
+/// The journal of the run started last.
+fn latest() -> Result<PathBuf> {
+    let dir = journal_dir()?;
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => bail!("No runs have been journaled yet"),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {}", dir.display())),
+    };
+    let mut journals = Vec::new();
+    for entry in entries {
+        let path = entry.with_context(|| format!("Failed to list {}", dir.display()))?.path();
+        if path.extension().is_some_and(|ext| ext == "jsonl") {
+            journals.push(path);
+        }
+    }
+    journals.into_iter().max().context("No runs have been journaled yet")
+}
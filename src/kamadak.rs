@@ -0,0 +1,84 @@
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use kamadak_exif::{Context, Field, In, Reader, Value};
+use thiserror::Error;
+
+use crate::exif::{Exif, ExifError, Ifd};
+
+#[derive(Error, Debug)]
+pub enum KamadakError {
+    #[error("kamadak-exif couldn't read the file: {0}")]
+    Read(#[from] kamadak_exif::Error),
+    #[error("{0}")]
+    Exif(#[from] ExifError),
+    #[error("Reading task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// The fields kamadak-exif found in one IFD.
+struct FieldsIfd<'a> {
+    fields: Vec<&'a Field>,
+}
+
+impl FieldsIfd<'_> {
+    fn find(&self, tag: u16) -> Option<&Value> {
+        self.fields.iter().find(|f| f.tag.number() == tag).map(|f| &f.value)
+    }
+}
+
+impl Ifd for FieldsIfd<'_> {
+    fn ascii(&self, tag: u16) -> Result<Option<&[u8]>, ExifError> {
+        match self.find(tag) {
+            None => Ok(None),
+            Some(Value::Ascii(strings)) => Ok(Some(strings.first().map_or(&[], Vec::as_slice))),
+            Some(_) => Err(ExifError::BadEntry(tag)),
+        }
+    }
+
+    fn rationals(&self, tag: u16) -> Option<Vec<f64>> {
+        match self.find(tag)? {
+            Value::Rational(values) => Some(values.iter().map(|r| r.to_f64()).collect()),
+            _ => None,
+        }
+    }
+
+    fn uint(&self, tag: u16) -> Option<u32> {
+        self.find(tag)?.get_uint(0)
+    }
+
+    fn bytes(&self, tag: u16) -> Option<&[u8]> {
+        match self.find(tag)? {
+            Value::Byte(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+/// Reads EXIF from `path` with kamadak-exif, which finds it in any container it knows (TIFF,
+/// JPEG, HEIF, PNG and WebP).
+pub async fn read(path: &Path) -> Result<Exif, KamadakError> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || read_blocking(path)).await?
+}
+
+fn read_blocking(path: PathBuf) -> Result<Exif, KamadakError> {
+    let file = std::fs::File::open(path).map_err(kamadak_exif::Error::Io)?;
+    let parsed = Reader::new().read_from_container(&mut BufReader::new(file))?;
+    // Fields come out IFD by IFD; group them back up so they're collected the same way the
+    // built-in parser's are. Interoperability IFD tags reuse the GPS tag numbers, so they're
+    // left out.
+    let mut ifds: Vec<((In, Context), FieldsIfd)> = Vec::new();
+    for field in parsed.fields().filter(|f| f.tag.context() != Context::Interop) {
+        let key = (field.ifd_num, field.tag.context());
+        match ifds.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, ifd)) => ifd.fields.push(field),
+            None => ifds.push((key, FieldsIfd { fields: vec![field] })),
+        }
+    }
+    let mut exif = Exif::default();
+    for (_, ifd) in &ifds {
+        exif.collect(ifd)?;
+    }
+    Ok(exif)
+}
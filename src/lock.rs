@@ -0,0 +1,158 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use tracing::{info, warn};
+
+/// Name of the lockfile, in the library root. Hidden, so sorting the library itself skips it.
+const LOCK_FILE: &str = ".photosort.lock";
+/// How often `--wait` checks whether the lock has been released.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An advisory lock on a library, so two runs sorting into it at once can't both take the same
+/// free name or write to the journal over each other. It's a file in the library root saying
+/// which process holds it and since when, removed when the lock is dropped.
+pub struct LibraryLock {
+    path: PathBuf,
+}
+
+/// Who holds a lock, as its file says.
+struct Holder {
+    /// None while the holder is still writing the file.
+    pid: Option<u32>,
+    started: Option<String>,
+}
+
+impl LibraryLock {
+    /// Locks `library`. While another run holds it, this waits for that run to finish if `wait`
+    /// is set and fails otherwise. With `force`, the lock is taken over whoever holds it, for one
+    /// left behind by a run that was killed.
+    pub async fn acquire(library: &Path, wait: bool, force: bool) -> Result<LibraryLock> {
+        std::fs::create_dir_all(library)
+            .with_context(|| format!("Failed to create library {}", library.display()))?;
+        let path = library.join(LOCK_FILE);
+        let mut waiting = false;
+        loop {
+            match create(&path) {
+                Ok(()) => return Ok(LibraryLock { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to create lockfile {}", path.display()));
+                }
+            }
+            let holder = match Holder::read(&path) {
+                Ok(holder) => holder,
+                // Released in the meantime.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to read lockfile {}", path.display()));
+                }
+            };
+            if force {
+                warn!("Taking over the lock on {} from {}", library.display(), holder);
+                match std::fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(e).with_context(|| {
+                            format!("Failed to remove lockfile {}", path.display())
+                        });
+                    }
+                    _ => continue,
+                }
+            }
+            if holder.pid.and_then(sys::running) == Some(false) {
+                bail!(
+                    "{} is locked by {}, which is no longer running. If no other photosort is \
+                     using it, run again with --force-unlock",
+                    library.display(),
+                    holder
+                );
+            }
+            if !wait {
+                bail!(
+                    "{} is locked by {}. Run again with --wait to wait for it to finish",
+                    library.display(),
+                    holder
+                );
+            }
+            if !waiting {
+                info!("Waiting for {} to finish with {}", holder, library.display());
+                waiting = true;
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl Drop for LibraryLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove lockfile {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Creates the lockfile at `path`, failing if there already is one.
+fn create(path: &Path) -> io::Result<()> {
+    let mut file = File::options().write(true).create_new(true).open(path)?;
+    let contents = format!("pid {}\nstarted {}\n", std::process::id(), Local::now().to_rfc3339());
+    let written = file.write_all(contents.as_bytes()).and_then(|()| file.sync_data());
+    if written.is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    written
+}
+
+impl Holder {
+    fn read(path: &Path) -> io::Result<Holder> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut holder = Holder { pid: None, started: None };
+        for line in contents.lines() {
+            match line.split_once(' ') {
+                Some(("pid", pid)) => holder.pid = pid.parse().ok(),
+                Some(("started", started)) => holder.started = Some(started.to_string()),
+                _ => {}
+            }
+        }
+        Ok(holder)
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.pid, &self.started) {
+            (Some(pid), Some(started)) => write!(f, "photosort (pid {}, started {})", pid, started),
+            (Some(pid), None) => write!(f, "photosort (pid {})", pid),
+            _ => write!(f, "another photosort"),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::convert::TryFrom;
+    use std::io;
+
+    /// Whether a process with this PID is running, on this machine at least.
+    pub fn running(pid: u32) -> Option<bool> {
+        let pid = libc::pid_t::try_from(pid).ok()?;
+        // Safety: signal 0 only checks that the process exists and could be signalled.
+        if unsafe { libc::kill(pid, 0) } == 0 {
+            return Some(true);
+        }
+        // It exists, but belongs to another user.
+        Some(io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    pub fn running(_pid: u32) -> Option<bool> {
+        None
+    }
+}
@@ -1,214 +1,1254 @@
+use std::collections::HashSet;
 use std::convert::TryFrom;
-use std::io::{BufRead, Cursor};
-use std::path::Path;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use anyhow::{Context, Result};
-use async_trait::async_trait;
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone, Timelike};
+use chrono_tz::Tz;
+use clap::{CommandFactory, Parser};
+use futures::StreamExt;
 use thiserror::Error;
 use tokio::io::{AsyncReadExt};
+use tracing::{error, trace};
+use tracing_subscriber::EnvFilter;
 
-#[async_trait]
-trait Renamer {
-    async fn rename(&self, source: &Path, dest: &Path) -> std::io::Result<()>;
+mod archive;
+mod bmff;
+mod cli;
+mod config;
+mod dump;
+mod exec;
+mod exif;
+mod exiftool;
+mod extract;
+mod filename;
+mod journal;
+#[cfg(feature = "kamadak-exif")]
+mod kamadak;
+mod lock;
+mod mmap;
+mod mts;
+mod pairs;
+mod perms;
+#[cfg(feature = "object-store")]
+mod objectstore;
+mod plan;
+mod rclone;
+mod renamer;
+mod reflink;
+mod report;
+mod sidecar;
+mod sort;
+mod space;
+mod template;
+#[cfg(test)]
+mod testutil;
+mod thumb;
+mod trash;
+mod walk;
+mod watermark;
+mod webdav;
+mod xattr;
+
+use cli::{
+    Cli, Command, CompletionsCommand, ResumeCommand, UndoCommand, VerifyCommand, WatchCommand,
+};
+use config::Config;
+use journal::Run;
+use plan::Manifest;
+use report::Transfer;
+use sort::{library_root, DateReader, Layout, Sorter};
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+enum FileParseError {
+    #[error("An error occured operating on a file: {0}")]
+    FileError(std::io::Error),
+    #[error("Error parsing date from file: {0}")]
+    DateParseError(String),
+    #[error("Error reading EXIF metadata: {0}")]
+    ExifError(exif::ExifError),
+    #[error("Error reading ISO-BMFF boxes: {0}")]
+    BmffError(bmff::BmffError),
+    #[error("Unsupported file: {0}")]
+    UnsupportedError(String),
 }
 
-struct FileRenamer;
+impl From<std::io::Error> for FileParseError {
+    fn from(err: std::io::Error) -> FileParseError {
+        FileParseError::FileError(err)
+    }
+}
 
-impl FileRenamer {
-    fn new() -> Self {
-        Self{}
+impl From<bmff::BmffError> for FileParseError {
+    fn from(err: bmff::BmffError) -> FileParseError {
+        FileParseError::BmffError(err)
     }
 }
 
-#[async_trait]
-impl Renamer for FileRenamer {
-    async fn rename(&self, source: &Path, dest: &Path) -> std::io::Result<()> {
-        tokio::fs::rename(source, dest).await
+impl From<exif::ExifError> for FileParseError {
+    fn from(err: exif::ExifError) -> FileParseError {
+        FileParseError::ExifError(err)
     }
 }
 
-struct GitRenamer;
+#[derive(Debug, Clone)]
+struct Date {
+    /// Wall-clock time, to the second. A leap second is kept the way chrono keeps them, as second
+    /// 59 with a nanosecond count past one billion.
+    _datetime: NaiveDateTime,
+    /// Fraction of a second, as the digits after the decimal point. Empty when not recorded.
+    _subsec: String,
+    /// Seconds east of UTC the time was recorded in, when that's known.
+    _offset: Option<i32>,
+}
 
-impl GitRenamer {
-    fn new() -> Self {
-        Self{}
+impl TryFrom<String> for Date {
+    type Error = FileParseError;
+
+    fn try_from(src: String) -> Result<Self, FileParseError> {
+        let error = |what: &str| Err(FileParseError::DateParseError(what.into()));
+        let numbers = |s: Option<&str>| -> Option<Vec<u32>> {
+            s?.split(':').map(|n| n.parse().ok()).collect()
+        };
+        let mut date_time_vals = src.split_whitespace();
+        let (year, month, day) = match numbers(date_time_vals.next()).as_deref() {
+            Some(&[year, month, day]) => (year, month, day),
+            _ => return error("Read something that is not a date"),
+        };
+
+        // Time of day is optional; without one the date is taken to be at midnight.
+        let time = date_time_vals.next().or(Some("00:00:00"));
+        let (hour, minute, second) = match numbers(time).as_deref() {
+            Some(&[hour, minute, second]) => (hour, minute, second),
+            _ => return error("Read something that is not a time"),
+        };
+
+        // Leap seconds are written as second 60.
+        let time = match second {
+            60 => NaiveTime::from_hms_milli_opt(hour, minute, 59, 1_000),
+            _ => NaiveTime::from_hms_opt(hour, minute, second),
+        };
+        let date = i32::try_from(year).ok().and_then(|y| NaiveDate::from_ymd_opt(y, month, day));
+        let datetime = match date.zip(time) {
+            Some((date, time)) => date.and_time(time),
+            None => return error(&format!("{:?} is not a possible date", src)),
+        };
+        Ok(Date::new(datetime))
     }
 }
 
-#[async_trait]
-impl Renamer for GitRenamer {
-    async fn rename(&self, source: &Path, dest: &Path) -> std::io::Result<()> {
-        let status = tokio::process::Command::new("git")
-            .arg("mv")
-            .args(&[source.as_os_str(), dest.as_os_str()])
-            .status()
-            .await?;
-        if status.success() {
-            Ok(())
-        } else {
-            // XXX - should replace interface with custom Error/Result
-            Err(std::io::Error::new(std::io::ErrorKind::Other, "git mv failed"))
-        }
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self._datetime.format("%Y-%m-%dT%H:%M:%S"))
     }
 }
 
-fn get_renamer(arg: &Option<String>) -> Box<dyn Renamer> {
-    match arg {
-        Some(c) => match c.as_str() {
-            "git" => Box::new(GitRenamer::new()),
-            _ => Box::new(FileRenamer::new())
-        },
-        None => Box::new(FileRenamer::new())
+impl PartialEq for Date {
+    fn eq(&self, other: &Date) -> bool {
+        self._datetime == other._datetime
     }
 }
 
-#[derive(Error, Debug)]
-enum FileParseError {
-    #[error("An error occured operating on a file: {0}")]
-    FileError(std::io::Error),
-    #[error("Error seeking: {0}")]
-    FileSeekError(String),
-    #[error("Error parsing date from file: {0}")]
-    DateParseError(String),
-    #[error("Error converting file date bytes to date string: {0}")]
-    DateConvertError(std::string::FromUtf8Error)
+impl PartialOrd for Date {
+    fn partial_cmp(&self, other: &Date) -> Option<std::cmp::Ordering> {
+        Some(self._datetime.cmp(&other._datetime))
+    }
 }
 
-impl From<std::io::Error> for FileParseError {
-    fn from(err: std::io::Error) -> FileParseError {
-        FileParseError::FileError(err)
+impl Date {
+    /// Parses a date given on the command line, `YYYY-MM-DD` with an optional `HH:MM:SS` after a
+    /// space or `T`. `default_time` is used when only a date is given.
+    fn parse_arg(arg: &str, default_time: &str) -> Result<Date, String> {
+        let (date, time) = match arg.split_once([' ', 'T']) {
+            Some((date, time)) => (date, time),
+            None => (arg, default_time),
+        };
+        let well_formed = |s: &str, sep: char, lens: &[usize]| {
+            let parts: Vec<&str> = s.split(sep).collect();
+            parts.len() == lens.len()
+                && parts
+                    .iter()
+                    .zip(lens)
+                    .all(|(p, &len)| p.len() == len && p.bytes().all(|b| b.is_ascii_digit()))
+        };
+        if !well_formed(date, '-', &[4, 2, 2]) || !well_formed(time, ':', &[2, 2, 2]) {
+            return Err(format!("expected YYYY-MM-DD or \"YYYY-MM-DD HH:MM:SS\", found {:?}", arg));
+        }
+        Date::try_from(format!("{} {}", date.replace('-', ":"), time)).map_err(|e| e.to_string())
+    }
+
+    /// Parses a date another program wrote out as text: EXIF style (`2020:02:01 14:32:14`), ISO
+    /// 8601 (`2020-02-01T14:32:14.250+01:00`), RFC 2822 (`Sat, 01 Feb 2020 14:32:14 +0000`) or
+    /// C's `ctime` (`Sat Feb  1 14:32:14 2020`). The time is kept as written, with any UTC
+    /// offset noted alongside it.
+    fn from_text(text: &str) -> Option<Date> {
+        const MONTHS: [&str; 12] =
+            ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+        let digits = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+        let hms = |s: &str| {
+            let mut parts: Vec<&str> = s.split(':').collect();
+            if parts.len() == 2 {
+                parts.push("00");
+            }
+            let valid = parts.len() == 3 && parts.iter().all(|p| digits(p, 2));
+            Some(parts.join(":")).filter(|_| valid)
+        };
+
+        let text = text.trim();
+        let (mut subsec, mut zone) = ("", "");
+        let (date, time) = if matches!(text.as_bytes().get(4), Some(b':' | b'-')) {
+            let (y, m, d) = (text.get(0..4)?, text.get(5..7)?, text.get(8..10)?);
+            if !digits(y, 4) || !digits(m, 2) || !digits(d, 2) {
+                return None;
+            }
+            let time = match text.get(10..) {
+                Some("") => "00:00:00".to_string(),
+                Some(rest) if rest.starts_with([' ', 'T']) => {
+                    let end = rest[1..].find(|c: char| c != ':' && !c.is_ascii_digit());
+                    let end = end.map_or(rest.len(), |e| e + 1);
+                    zone = &rest[end..];
+                    if let Some(fraction) = zone.strip_prefix('.') {
+                        let len = fraction.find(|c: char| !c.is_ascii_digit());
+                        let len = len.unwrap_or(fraction.len());
+                        (subsec, zone) = fraction.split_at(len);
+                    }
+                    hms(&rest[1..end])?
+                }
+                _ => return None,
+            };
+            (format!("{}:{}:{}", y, m, d), time)
+        } else {
+            let month_of = |s: &str| {
+                let s = s.to_ascii_lowercase();
+                MONTHS.iter().position(|m| s.starts_with(m)).map(|m| m + 1)
+            };
+            let mut parts: Vec<&str> = text.split_whitespace().collect();
+            // Neither format needs the day of the week.
+            let weekday = |p: &&str| p.starts_with(char::is_alphabetic) && month_of(p).is_none();
+            if parts.first().is_some_and(weekday) {
+                parts.remove(0);
+            }
+            let (day, month, year, time) = match parts[..] {
+                [month, day, time, year, ..] if month_of(month).is_some() => {
+                    (day, month, year, Some(time))
+                }
+                [day, month, year, ref rest @ ..] => {
+                    zone = rest.get(1).copied().unwrap_or_default();
+                    (day, month, year, rest.first().copied())
+                }
+                _ => return None,
+            };
+            let month = month_of(month)?;
+            let time = time.map_or(Some("00:00:00".to_string()), hms)?;
+            if !digits(year, 4) || !(1..=2).contains(&day.len()) || !digits(day, day.len()) {
+                return None;
+            }
+            (format!("{}:{:02}:{:0>2}", year, month, day), time)
+        };
+        let date = Date::try_from(format!("{} {}", date, time)).ok()?;
+        Some(date.with_subsec(subsec).with_offset(parse_offset(zone)))
+    }
+
+    /// Adds the fraction of a second, as written in EXIF's SubSecTimeOriginal: the digits after
+    /// the decimal point, possibly padded with spaces. Anything that isn't digits is ignored.
+    fn with_subsec(mut self, subsec: &str) -> Date {
+        let subsec = subsec.trim();
+        if subsec.bytes().all(|b| b.is_ascii_digit()) {
+            self._subsec = subsec.to_string();
+        }
+        self
+    }
+
+    /// Notes the UTC offset the date was recorded in.
+    fn with_offset(mut self, offset: Option<i32>) -> Date {
+        self._offset = offset;
+        self
+    }
+
+    /// The same moment on the clock in `zone`. Dates that don't know their UTC offset are taken
+    /// to be in `assume`, and are left as they are without it.
+    fn in_zone(&self, zone: Tz, assume: Option<Tz>) -> Date {
+        let utc = match (self._offset, assume) {
+            (Some(offset), _) => self._datetime - chrono::Duration::seconds(offset.into()),
+            (None, Some(assume)) => match assume.from_local_datetime(&self._datetime).earliest() {
+                Some(time) => time.naive_utc(),
+                None => return self.clone(),
+            },
+            (None, None) => return self.clone(),
+        };
+        let local = zone.from_utc_datetime(&utc);
+        Date {
+            _datetime: local.naive_local(),
+            _subsec: self._subsec.clone(),
+            _offset: Some(local.offset().fix().local_minus_utc()),
+        }
+    }
+
+    fn new(datetime: NaiveDateTime) -> Date {
+        Date { _datetime: datetime, _subsec: String::new(), _offset: None }
+    }
+
+    /// Date and time in UTC of a Unix timestamp, if it's one chrono can represent.
+    fn from_timestamp(timestamp: i64) -> Option<Date> {
+        let utc = chrono::DateTime::from_timestamp(timestamp, 0)?;
+        Some(Date::new(utc.naive_utc()).with_offset(Some(0)))
+    }
+
+    /// The moment this date names, taking it to be in local time when it has no UTC offset.
+    /// None when that local time was skipped by a daylight saving change.
+    fn to_system_time(&self) -> Option<SystemTime> {
+        let utc = match self._offset {
+            Some(offset) => self._datetime - chrono::Duration::seconds(offset.into()),
+            None => chrono::Local.from_local_datetime(&self._datetime).earliest()?.naive_utc(),
+        };
+        // Nine digits of fraction are nanoseconds.
+        let nanos: u64 = format!("{:0<9.9}", self._subsec).parse().unwrap_or(0);
+        Some(SystemTime::from(utc.and_utc()) + Duration::from_nanos(nanos))
+    }
+
+    /// Whether this is a date that could really have been taken, rather than one from a camera
+    /// whose clock was never set. Impossible dates like `0000:00:00` don't parse in the first
+    /// place.
+    fn is_valid(&self) -> bool {
+        self.year() >= EARLIEST_YEAR
+    }
+
+    fn year(&self) -> i32 {
+        self._datetime.year()
+    }
+
+    fn month(&self) -> u32 {
+        self._datetime.month()
+    }
+
+    fn day(&self) -> u32 {
+        self._datetime.day()
+    }
+
+    /// ISO 8601 week-numbering year and week, e.g. 2021-01-01 is in week 53 of 2020.
+    fn iso_week(&self) -> (i32, u32) {
+        let week = self._datetime.iso_week();
+        (week.year(), week.week())
+    }
+
+    fn hour(&self) -> u32 {
+        self._datetime.hour()
+    }
+
+    fn minute(&self) -> u32 {
+        self._datetime.minute()
+    }
+
+    /// Leap seconds are second 60.
+    fn second(&self) -> u32 {
+        self._datetime.second() + u32::from(self._datetime.nanosecond() >= 1_000_000_000)
+    }
+
+    fn subsec(&self) -> &str {
+        &self._subsec
+    }
+
+    /// Seconds east of UTC, when the date records its offset.
+    fn offset(&self) -> Option<i32> {
+        self._offset
     }
 }
 
-impl From<std::string::FromUtf8Error> for FileParseError {
-    fn from(err: std::string::FromUtf8Error) -> FileParseError {
-        FileParseError::DateConvertError(err)
+/// Seconds east of UTC in a time zone designator like `Z`, `+01:00`, `-0500` or `+09`.
+fn parse_offset(zone: &str) -> Option<i32> {
+    let zone = zone.trim();
+    if matches!(zone, "Z" | "UT" | "UTC" | "GMT") {
+        return Some(0);
     }
+    let sign = match zone.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let digits = zone[1..].replace(':', "");
+    if !matches!(digits.len(), 2 | 4) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits.get(2..).filter(|m| !m.is_empty()).map_or(Ok(0), str::parse).ok()?;
+    Some(sign * (hours * 3600 + minutes * 60))
 }
 
-struct Date {
-    _src: String,
+/// Year of the oldest surviving photograph. Anything earlier is a broken clock.
+const EARLIEST_YEAR: i32 = 1826;
+
+/// How much of the start of a file is read to tell what format it's in. Everything after that is
+/// read as the format's structure leads to it.
+const SNIFF_LEN: u64 = 4 * 1024;
+
+/// How much of a TIFF-based file is read at a time when an IFD or value lies outside what's been
+/// read of it so far.
+const TIFF_READ_LEN: usize = 4 * 1024;
+
+/// Most of a TIFF structure photosort will read, whether from a TIFF-based file or from a chunk
+/// or item in another format.
+const MAX_TIFF_LEN: u64 = 16 * 1024 * 1024;
+
+/// How far into an AVCHD video the recording time is looked for. It comes with the first frame.
+const MAX_MTS_SCAN_LEN: u64 = 64 * 1024;
+
+/// Largest PNG text chunk read looking for a creation time. Ones holding whole XMP packets are
+/// bigger, and have no date worth reading.
+const MAX_PNG_TEXT_LEN: u64 = 64 * 1024;
+
+/// Largest ISO-BMFF box photosort will read into memory. A long video's `moov` box, with its
+/// sample tables, can run to several megabytes.
+const MAX_BOX_LEN: u64 = 64 * 1024 * 1024;
+
+/// Smallest file that could hold a TIFF header and a single IFD entry.
+const MIN_LEN: usize = 8 + 2 + 12;
+
+/// What a file's own metadata says about when it was taken.
+#[derive(Debug, Default)]
+struct Metadata {
+    /// From DateTimeOriginal, or the format's equivalent.
+    date: Option<Date>,
+    /// From the EXIF GPS tags, which are in UTC.
+    gps_date: Option<Date>,
+    /// Where it was taken, as latitude and longitude.
+    position: Option<(f64, f64)>,
+    camera: Camera,
+    tags: Tags,
+    /// Where in the file it was read from.
+    location: Option<Location>,
+    /// Offsets of the IFDs read, relative to the TIFF header, for metadata read from EXIF.
+    ifd_offsets: Vec<usize>,
 }
 
-impl TryFrom<String> for Date {
-    type Error = FileParseError;
+/// Where in a file its metadata was found.
+#[derive(Debug, Clone)]
+struct Location {
+    /// What holds the metadata, like `JPEG APP1 segment`.
+    container: &'static str,
+    /// Offset of the metadata from the start of the file: of the TIFF header for EXIF, and of
+    /// the box or chunk's data otherwise.
+    offset: u64,
+}
 
-    fn try_from(src: String) -> Result<Self, FileParseError> {
-        let mut date_time_vals = src.split_whitespace();
-        let date = date_time_vals.next().unwrap_or("");
-        let year_month_day = date.split(":").collect::<Vec<&str>>();
-        if year_month_day.len() != 3 {
-            return Err(FileParseError::DateParseError("Read something that is not a date".into()));
+/// How a file has been rated and tagged, in its metadata or an XMP sidecar.
+#[derive(Debug, Default, Clone)]
+struct Tags {
+    /// Stars, from 1 to 5. 0 means unrated and -1 rejected.
+    rating: Option<i32>,
+    keywords: Vec<String>,
+}
+
+/// What a file's metadata says about the camera that took it, and how it was set up.
+#[derive(Debug, Default, Clone)]
+struct Camera {
+    make: Option<String>,
+    model: Option<String>,
+    lens: Option<String>,
+    /// In millimetres.
+    focal_length: Option<f64>,
+    iso: Option<u32>,
+}
+
+impl Camera {
+    fn new(make: Option<String>, model: Option<String>) -> Camera {
+        Camera { make: tidy_text(make), model: tidy_text(model), ..Camera::default() }
+    }
+
+    fn from_exif(exif: &mut exif::Exif) -> Camera {
+        Camera {
+            lens: tidy_text(exif.lens_model.take()),
+            focal_length: exif.focal_length,
+            iso: exif.iso,
+            ..Camera::new(exif.make.take(), exif.model.take())
+        }
+    }
+}
+
+/// Text as cameras write it, which is often padded with spaces, or `None` if there's none.
+fn tidy_text(text: Option<String>) -> Option<String> {
+    text.map(|s| s.trim().to_string()).filter(|s| !s.is_empty())
+}
+
+impl Metadata {
+    /// Takes the dates out of EXIF, failing when it has none.
+    fn from_exif(mut exif: exif::Exif) -> Result<Metadata, FileParseError> {
+        let camera = Camera::from_exif(&mut exif);
+        let rating = exif.rating.and_then(|rating| i32::try_from(rating).ok());
+        let tags = Tags { rating, keywords: std::mem::take(&mut exif.keywords) };
+        let subsec = exif.sub_sec_time_original.unwrap_or_default();
+        let offset = exif.offset_time_original.as_deref().and_then(parse_offset);
+        let date = exif.date_time_original.map(|d| {
+            Ok(Date::try_from(d)?.with_subsec(&subsec).with_offset(offset))
+        });
+        let date = date.transpose();
+        let gps_date = exif.gps_date_time.and_then(|d| Date::try_from(d).ok());
+        let gps_date = gps_date.map(|d| d.with_offset(Some(0)));
+        let position = exif.gps_position;
+        match (date, gps_date) {
+            (Ok(None), None) => Err(exif::ExifError::NoDate.into()),
+            (Err(e), None) => Err(e),
+            (date, gps_date) => Ok(Metadata {
+                date: date.ok().flatten(),
+                gps_date,
+                position,
+                camera,
+                tags,
+                location: None,
+                ifd_offsets: exif.ifd_offsets,
+            }),
         }
+    }
 
-        Ok(Date {_src: date.into() })
+    /// Notes where in the file the metadata was read from.
+    fn found_in(self, container: &'static str, offset: u64) -> Metadata {
+        Metadata { location: Some(Location { container, offset }), ..self }
+    }
+
+    /// Takes the date and camera out of a movie's `moov` box.
+    fn from_moov(moov: &[u8]) -> Result<Metadata, FileParseError> {
+        // mvhd is in UTC, so prefer the local time phones record alongside it.
+        let date = bmff::quicktime_value(moov, bmff::QUICKTIME_CREATION_DATE);
+        let date = match date.and_then(Date::from_text) {
+            Some(date) => date,
+            None => Date::from_timestamp(bmff::movie_creation_time(moov)?)
+                .ok_or_else(|| bmff::BmffError::BadBox("mvhd".into()))?,
+        };
+        let text = |key| bmff::quicktime_value(moov, key).map(String::from);
+        let camera = Camera::new(text(bmff::QUICKTIME_MAKE), text(bmff::QUICKTIME_MODEL));
+        Ok(Metadata { camera, ..Metadata::from(date) })
     }
 }
 
-impl Date {
-    fn year(&self) -> &str {
-        self._src.split(":").nth(0).unwrap()
+impl From<Date> for Metadata {
+    fn from(date: Date) -> Metadata {
+        Metadata { date: Some(date), ..Metadata::default() }
     }
+}
 
-    fn month(&self) -> &str {
-        self._src.split(":").nth(1).unwrap()
+async fn read_metadata(file: &Path) -> Result<Metadata, FileParseError> {
+    #[cfg(feature = "kamadak-exif")]
+    match kamadak::read(file).await.map(Metadata::from_exif) {
+        Ok(Ok(metadata)) => return Ok(metadata),
+        Ok(Err(e)) => tracing::debug!(error = %e, "No date from kamadak-exif, trying the built-in parser"),
+        Err(e) => tracing::debug!(error = %e, "Trying the built-in parser"),
     }
+    if tokio::fs::metadata(file).await?.len() >= mmap::MIN_LEN {
+        if let Some(metadata) = mmap::read(file).await? {
+            trace!(?metadata, "Result of metadata read through a memory map");
+            return Ok(metadata);
+        }
+    }
+    let mut f = tokio::fs::File::open(file).await.map_err(FileParseError::FileError)?;
+    let mut header = Vec::new();
+    (&mut f).take(SNIFF_LEN).read_to_end(&mut header).await.map_err(FileParseError::FileError)?;
 
-    fn day(&self) -> &str {
-        self._src.split(":").nth(2).unwrap()
+    if header.len() < MIN_LEN {
+        return Err(FileParseError::UnsupportedError(format!(
+            "only {} bytes long, too small to be a photo",
+            header.len()
+        )));
     }
+
+    // TIFF-based RAW files like CR2 start with the TIFF header, while JPEGs carry one inside
+    // their APP1 segment.
+    let exif_date = |tiff: &[u8]| Metadata::from_exif(exif::read(tiff)?);
+    let metadata = if header.starts_with(&exif::JPEG_SOI) {
+        let (offset, tiff) = read_jpeg_exif(&mut f, 0).await?;
+        exif_date(&tiff)?.found_in("JPEG APP1 segment", offset)
+    } else if header.starts_with(exif::PNG_SIGNATURE) {
+        read_png_metadata(&mut f).await?
+    } else if exif::is_riff(&header, exif::WEBP_FORM) {
+        let (offset, _, data) = read_riff_chunk(&mut f, &[b"EXIF"])
+            .await?
+            .ok_or_else(|| FileParseError::DateParseError("WebP has no EXIF chunk".into()))?;
+        // Some writers keep the JPEG APP1 signature ahead of the TIFF header.
+        let tiff = data.strip_prefix(exif::EXIF_SIGNATURE).unwrap_or(&data);
+        let offset = offset + (data.len() - tiff.len()) as u64;
+        exif_date(tiff)?.found_in("WebP EXIF chunk", offset)
+    } else if bmff::has_brand(&header, bmff::HEIF_BRANDS) {
+        let (_, meta) = read_bmff_box(&mut f, b"meta").await?.ok_or(bmff::BmffError::NoExifItem)?;
+        let (offset, len) = bmff::exif_location(&meta)?;
+        if len > MAX_TIFF_LEN {
+            return Err(bmff::BmffError::BadExifItem.into());
+        }
+        let mut item = vec![0; len as usize];
+        f.seek(SeekFrom::Start(offset)).await?;
+        f.read_exact(&mut item).await.map_err(truncated)?;
+        let tiff = bmff::exif_item_tiff(&item)?;
+        let offset = offset + (item.len() - tiff.len()) as u64;
+        exif_date(tiff)?.found_in("HEIF Exif item", offset)
+    } else if bmff::is_bmff(&header) {
+        let (offset, moov) =
+            read_bmff_box(&mut f, b"moov").await?.ok_or(bmff::BmffError::NoMovieHeader)?;
+        Metadata::from_moov(&moov)?.found_in("moov box", offset)
+    } else if exif::is_riff(&header, exif::AVI_FORM) {
+        let (date, location) = match read_riff_chunk(&mut f, &[b"IDIT", b"DTIM"]).await? {
+            Some((offset, id, data)) if &id == b"DTIM" => {
+                let date = exif::riff_filetime(&data).and_then(Date::from_timestamp);
+                (date, Some(("AVI DTIM chunk", offset)))
+            }
+            Some((offset, _, data)) => {
+                let text = String::from_utf8_lossy(&data);
+                (Date::from_text(text.trim_end_matches('\0')), Some(("AVI IDIT chunk", offset)))
+            }
+            None => (None, None),
+        };
+        let metadata = date.map(Metadata::from).ok_or_else(|| {
+            FileParseError::DateParseError("AVI has no IDIT or DTIM date".into())
+        })?;
+        let (container, offset) = location.unwrap();
+        metadata.found_in(container, offset)
+    } else if mts::is_m2ts(&header) {
+        Metadata::from(Date::try_from(read_mts_recording_time(&mut f, header).await?)?)
+    } else if header.starts_with(exif::RAF_MAGIC) {
+        let jpeg = exif::raf_jpeg_offset(&header)?;
+        let (offset, tiff) = read_jpeg_exif(&mut f, jpeg).await?;
+        exif_date(&tiff)?.found_in("RAF JPEG preview's APP1 segment", offset)
+    } else if exif::is_tiff(&header) {
+        Metadata::from_exif(read_tiff(&mut f, header).await?)?.found_in("TIFF structure", 0)
+    } else {
+        return Err(FileParseError::UnsupportedError(format!(
+            "not a photo or video format photosort can read (starts with {:02x?})",
+            &header[..4]
+        )));
+    };
+
+    trace!(?metadata, "Result of metadata read");
+    Ok(metadata)
 }
 
-async fn get_date_from_file(file: &Path) -> Result<Date, FileParseError> {
-    let mut file_header = [0; 1024];
-    let mut f = tokio::fs::File::open(file).await.map_err(|e| FileParseError::FileError(e))?;
-    f.read_exact(&mut file_header).await.map_err(|e| FileParseError::FileError(e))?;
+/// Running out of file before the metadata means it's cut short, not unreadable.
+fn truncated(e: std::io::Error) -> FileParseError {
+    match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => exif::ExifError::Truncated.into(),
+        _ => FileParseError::FileError(e),
+    }
+}
 
-    // First find the initial pattern of 'II*' indicating start of file (JPG has its magic number
-    // and some other stuff before that pattern, CR2 files appear to start with that pattern).
-    let start: usize = file_header[0..16]
-        .windows(3)
-        .position(|seq| seq == &[0x49u8, 0x49u8, 0x2au8])
-        .ok_or(
-            FileParseError::FileSeekError(
-                format!(
-                    "Did not find 'II*' (0x49 0x49 0x2a) in file header. First 16 bytes: {:?}",
-                    &file_header[0..16])
-            )
-        )?;
+/// Walks the marker segments of the JPEG starting at `start` in `f` to the APP1 segment holding
+/// EXIF and returns the TIFF structure in it, and where in the file that starts. Other segments,
+/// like the large ICC profiles phones write ahead of EXIF, are seeked over rather than read.
+async fn read_jpeg_exif(
+    f: &mut tokio::fs::File,
+    start: u64,
+) -> Result<(u64, Vec<u8>), FileParseError> {
+    f.seek(SeekFrom::Start(start)).await?;
+    let mut soi = [0; 2];
+    f.read_exact(&mut soi).await.map_err(truncated)?;
+    if soi != exif::JPEG_SOI {
+        return Err(exif::ExifError::BadJpeg(soi[1]).into());
+    }
+    loop {
+        let mut marker = [0; 2];
+        f.read_exact(&mut marker).await.map_err(truncated)?;
+        match marker {
+            // Fill byte ahead of the real marker.
+            [0xff, 0xff] => {
+                f.seek(SeekFrom::Current(-1)).await?;
+            }
+            // Markers without a length or payload.
+            [0xff, 0x01] | [0xff, 0xd0..=0xd8] => {}
+            // Start of scan or end of image: entropy-coded data follows, no more metadata.
+            [0xff, 0xda] | [0xff, 0xd9] => return Err(exif::ExifError::NoExifSegment.into()),
+            [0xff, m] => {
+                let mut len = [0; 2];
+                f.read_exact(&mut len).await.map_err(truncated)?;
+                let len = u16::from_be_bytes(len).checked_sub(2).ok_or(exif::ExifError::BadJpeg(m))?;
+                if m != exif::JPEG_APP1 {
+                    f.seek(SeekFrom::Current(i64::from(len))).await?;
+                    continue;
+                }
+                let offset = f.seek(SeekFrom::Current(0)).await?;
+                let mut segment = vec![0; usize::from(len)];
+                f.read_exact(&mut segment).await.map_err(truncated)?;
+                // APP1 also carries XMP, which has a different signature.
+                if let Some(tiff) = segment.strip_prefix(exif::EXIF_SIGNATURE) {
+                    return Ok((offset + exif::EXIF_SIGNATURE.len() as u64, tiff.to_vec()));
+                }
+            }
+            [b, _] => return Err(exif::ExifError::BadJpeg(b).into()),
+        }
+    }
+}
 
-    let mut read = Vec::with_capacity(1024);
-    let mut buf  = Cursor::new(&file_header[start..]);
-    let r = buf.read_until(0x49u8, &mut read)?;
-    if r != 1 {
-        return Err(FileParseError::FileSeekError(format!("Did not find expected bytes in file while seeking to date. Expected 1 byte 'I' (0x49), found: {:?}", read)));
+/// Reads the TIFF structure `f` starts with, of which `header` has been read. IFDs and values
+/// outside what's been read are read as the structure points at them, so the metadata can be
+/// anywhere in the file without the image data in between being read.
+async fn read_tiff(f: &mut tokio::fs::File, header: Vec<u8>) -> Result<exif::Exif, FileParseError> {
+    let mut parts = exif::TiffParts::default();
+    parts.insert(0, header);
+    loop {
+        let offset = match exif::read(&parts) {
+            Err(exif::ExifError::OutOfBounds(offset)) => offset,
+            result => return Ok(result?),
+        };
+        // Asked for the same offset again, what was read there wasn't enough.
+        let read_before = parts.len_at(offset);
+        let len = read_before.map_or(TIFF_READ_LEN, |len| len * 2);
+        if (parts.total_len() + len) as u64 > MAX_TIFF_LEN {
+            return Err(exif::ExifError::OutOfBounds(offset).into());
+        }
+        let mut data = Vec::new();
+        f.seek(SeekFrom::Start(offset as u64)).await?;
+        (&mut *f).take(len as u64).read_to_end(&mut data).await?;
+        // Nothing new means the offset is at or past the end of the file.
+        if data.len() <= read_before.unwrap_or(0) {
+            return Err(exif::ExifError::OutOfBounds(offset).into());
+        }
+        parts.insert(offset, data);
     }
-    read.clear();
+}
 
-    let r = buf.read_until(0x49u8, &mut read)?;
-    if r != 1 {
-        return Err(FileParseError::FileSeekError(format!("Did not find expected bytes in file while seeking to date. Expected 1 byte 'I' (0x49), found: {:?}", read)));
+/// Reads on from the start of the AVCHD video in `f`, of which `header` has been read, until the
+/// recording time turns up.
+async fn read_mts_recording_time(
+    f: &mut tokio::fs::File,
+    mut data: Vec<u8>,
+) -> Result<String, FileParseError> {
+    let mut at_end = false;
+    loop {
+        if let Some(time) = mts::recording_time(&data) {
+            return Ok(time);
+        }
+        if at_end || data.len() as u64 >= MAX_MTS_SCAN_LEN {
+            return Err(FileParseError::DateParseError(
+                "no recording time at the start of the video stream".into(),
+            ));
+        }
+        at_end = (&mut *f).take(SNIFF_LEN).read_to_end(&mut data).await? == 0;
     }
-    read.clear();
+}
 
-    let r = buf.read_until(0x2au8, &mut read)?;
-    if r != 1 {
-        return Err(FileParseError::FileSeekError(format!("Did not find expected bytes in file while seeking to date. Expected 1 byte '*' (0xau8), found: {:?}", read)));
+/// Walks the chunks of a PNG to the `eXIf` chunk and returns the dates in its EXIF. PNGs without
+/// one, like most screenshots, may still note a creation time in a `tEXt` or `iTXt` chunk,
+/// which is used instead. Image data is seeked over rather than read.
+async fn read_png_metadata(f: &mut tokio::fs::File) -> Result<Metadata, FileParseError> {
+    f.seek(SeekFrom::Start(exif::PNG_SIGNATURE.len() as u64)).await?;
+    let mut text_date = None;
+    let mut exif_error = None;
+    let chunk_name = |kind: &[u8]| match kind {
+        b"eXIf" => "PNG eXIf chunk",
+        b"tEXt" => "PNG tEXt chunk",
+        _ => "PNG iTXt chunk",
+    };
+    loop {
+        let mut head = [0; 8];
+        f.read_exact(&mut head).await.map_err(truncated)?;
+        let len = u32::from_be_bytes([head[0], head[1], head[2], head[3]]);
+        let kind = &head[4..];
+        match kind {
+            // Text chunks can hold whole XMP packets; a date is never that big.
+            b"eXIf" | b"tEXt" | b"iTXt" if u64::from(len) <= MAX_PNG_TEXT_LEN => {
+                let offset = f.seek(SeekFrom::Current(0)).await?;
+                let mut data = vec![0; len as usize];
+                f.read_exact(&mut data).await.map_err(truncated)?;
+                f.seek(SeekFrom::Current(4)).await?;
+                if kind == b"eXIf" {
+                    let metadata = exif::read(&data[..]).map_err(FileParseError::from);
+                    match metadata.and_then(Metadata::from_exif) {
+                        Ok(metadata) => return Ok(metadata.found_in(chunk_name(kind), offset)),
+                        Err(e) => exif_error = Some(e),
+                    }
+                } else if text_date.is_none() {
+                    let date = exif::png_creation_time(kind, &data).and_then(Date::from_text);
+                    text_date = date.map(|date| (date, chunk_name(kind), offset));
+                }
+            }
+            b"IEND" => break,
+            // Skip over the chunk's data and CRC.
+            _ => {
+                f.seek(SeekFrom::Current(i64::from(len) + 4)).await?;
+            }
+        }
+    }
+    match (text_date, exif_error) {
+        (Some((date, container, offset)), _) => Ok(Metadata::from(date).found_in(container, offset)),
+        (None, Some(e)) => Err(e),
+        (None, None) => {
+            Err(FileParseError::DateParseError("PNG has no eXIf chunk or creation time".into()))
+        }
     }
-    read.clear();
+}
 
-    // Should be just after II* at this point
-    buf.read_until(0x25u8, &mut read)?;
-    read.clear();
+/// Walks the chunks of the RIFF file in `f` to the first one with one of the given IDs and
+/// returns where its data starts, its ID and the data, or `None` when the file has none. `LIST`
+/// chunks are searched too, except for AVI's `movi` list, which holds the frames; those and other
+/// chunks, like the image data, are seeked over.
+async fn read_riff_chunk(
+    f: &mut tokio::fs::File,
+    ids: &[&[u8; 4]],
+) -> Result<Option<(u64, [u8; 4], Vec<u8>)>, FileParseError> {
+    // Past "RIFF", the file size and the form type.
+    f.seek(SeekFrom::Start(12)).await?;
+    loop {
+        let mut head = [0; 12];
+        match f.read_exact(&mut head[..8]).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let id = [head[0], head[1], head[2], head[3]];
+        let len = u32::from_le_bytes([head[4], head[5], head[6], head[7]]);
+        if ids.contains(&&id) && u64::from(len) <= MAX_TIFF_LEN {
+            let offset = f.seek(SeekFrom::Current(0)).await?;
+            let mut data = vec![0; len as usize];
+            f.read_exact(&mut data).await.map_err(truncated)?;
+            return Ok(Some((offset, id, data)));
+        }
+        if &id == b"LIST" && len >= 4 {
+            // The list's children follow its type directly, so step inside unless it's movi.
+            f.read_exact(&mut head[8..]).await.map_err(truncated)?;
+            if &head[8..] == b"movi" {
+                f.seek(SeekFrom::Current(i64::from(len) - 4 + i64::from(len % 2))).await?;
+            }
+            continue;
+        }
+        // Chunks are padded to an even length.
+        f.seek(SeekFrom::Current(i64::from(len) + i64::from(len % 2))).await?;
+    }
+}
 
-    // There is a twice repeated pattern immediately before the date time string starts:
-    // 48 00 00 00 01 00 00 00  48 00 00 00 01 00 00 00, That is, an H 3 null bytes, a 1 byte
-    // (not ascii 1) and 3 more null bytes. Let's read through that, checking that we got what we
-    // expected at the end.
-    buf.read_until(0x48u8, &mut read)?;
-    read.clear();
+/// Walks the top-level boxes of the ISO-BMFF file in `f` to the first one of the given type and
+/// returns where its body starts and the body, or `None` when the file has none. Other boxes are
+/// seeked over.
+async fn read_bmff_box(
+    f: &mut tokio::fs::File,
+    kind: &[u8; 4],
+) -> Result<Option<(u64, Vec<u8>)>, FileParseError> {
+    let mut pos = 0;
+    loop {
+        let mut head = [0; 16];
+        f.seek(SeekFrom::Start(pos)).await?;
+        let read = (&mut *f).take(16).read(&mut head).await?;
+        let (header_len, size) = match bmff::box_size(&head[..read]) {
+            Some(sizes) if read >= 8 => sizes,
+            _ => return Ok(None),
+        };
+        if head[4..8] == kind[..] {
+            let body_len = match size {
+                Some(size) => size.checked_sub(header_len),
+                None => Some(f.metadata().await?.len().saturating_sub(pos + header_len)),
+            };
+            let bad = || bmff::BmffError::BadBox(String::from_utf8_lossy(kind).into());
+            let body_len = body_len.filter(|&len| len <= MAX_BOX_LEN).ok_or_else(bad)?;
+            let mut body = vec![0; body_len as usize];
+            f.seek(SeekFrom::Start(pos + header_len)).await?;
+            f.read_exact(&mut body).await.map_err(truncated)?;
+            return Ok(Some((pos + header_len, body)));
+        }
+        match size {
+            Some(size) if size >= header_len => pos += size,
+            _ => return Ok(None),
+        }
+    }
+}
+
+fn read_file_list(list: &Path, null_separated: bool) -> Result<Vec<PathBuf>> {
+    let separator = if null_separated { b'\0' } else { b'\n' };
+    let paths = if list == Path::new("-") {
+        walk::read_file_list(std::io::stdin().lock(), separator)
+    } else {
+        let f = std::fs::File::open(list).with_context(|| format!("Failed to open {:?}", list))?;
+        walk::read_file_list(std::io::BufReader::new(f), separator)
+    };
+    paths.context("Failed to read file list")
+}
 
-    let r = buf.read_until(0x48u8, &mut read)?;
-    let expected = [0x00u8, 0x00u8, 0x00u8, 0x01u8, 0x00u8, 0x00u8, 0x00u8, 0x48u8];
-    if r != 8 || read.as_slice() != expected {
-        return Err(FileParseError::FileSeekError(format!("Did not find expected bytes in file while seeking to date. Expected 8 bytes matching {:?}, found: {:?}", expected, read)));
+/// Returns whether the input is already at the destination its date would sort it to.
+async fn verify(cmd: &VerifyCommand, layout: &Layout, dates: &DateReader) -> Result<bool> {
+    let capture = dates.read(&cmd.input).await?;
+    let expected = layout.destination_for(&cmd.input, &capture.date, &capture.camera)?;
+    let actual = tokio::fs::canonicalize(&cmd.input).await.context("Failed to resolve input path")?;
+    if tokio::fs::canonicalize(&expected).await.ok() == Some(actual) {
+        println!("{}: ok", cmd.input.display());
+        Ok(true)
+    } else {
+        println!("{}: expected at {}", cmd.input.display(), expected.display());
+        Ok(false)
     }
-    read.clear();
-    buf.set_position(buf.position() + 7);
+}
 
-    let mut data = [0; 10];
-    // For whatever reason the compiler is deciding to use tokio's AsyncRead implementation of this
-    // instead of the Cursor Read implementation of read_exact. Seems like having the AsyncRead
-    // trait in scope overrides the standard implementation of read_exact since Cursor implements
-    // both AsyncRead and Read. Since this is a read on an in-memory buffer, no other reason that
-    // it has to be async.
-    buf.read_exact(&mut data).await?;
-    // 2020:02:01 14:32:14
-    let date = String::from_utf8(data.into_iter().map(|b| *b).collect::<Vec<u8>>())?;
+async fn watch(cmd: &WatchCommand, sorter: &Sorter) -> Result<()> {
+    // Files that couldn't be sorted (or weren't moved because of --dry-run, --copy or the symlink
+    // backend) stay in the watched directory; remember them so they aren't retried and reported
+    // on every scan.
+    let mut seen = HashSet::new();
+    loop {
+        let mut batches = sorter.list(std::slice::from_ref(&cmd.dir));
+        while let Some(batch) = batches.next().await {
+            let files = match batch {
+                Ok(files) => files,
+                Err(e) => {
+                    error!("{}", e);
+                    continue;
+                }
+            };
+            for path in files {
+                if seen.contains(&path) {
+                    continue;
+                }
+                match sorter.sort_file(&path).await {
+                    Ok(sorted) => {
+                        println!("{} -> {}", path.display(), sorted.dest.display());
+                        if sorter.dry_run || sorter.transfer != Transfer::Move {
+                            seen.insert(path);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to sort {}: {:#}", path.display(), e);
+                        seen.insert(path);
+                    }
+                }
+            }
+        }
+        if let Err(e) = sorter.finish_batch().await {
+            error!("{:#}", e);
+        }
+        tokio::time::delay_for(Duration::from_secs(cmd.interval)).await;
+    }
+}
+
+/// The command line of the journaled `run`, from the directory it was run in, which the paths in
+/// its arguments and journal are relative to.
+fn journaled_cli(run: &Run) -> Result<Cli> {
+    std::env::set_current_dir(&run.cwd)
+        .with_context(|| format!("Failed to change to {}, where the run was", run.cwd.display()))?;
+    let cli = Cli::try_parse_from(&run.args).context("Failed to parse the run's arguments")?;
+    if !matches!(cli.command, Command::Sort(_) | Command::Apply(_)) {
+        bail!("Only sort and apply runs are journaled");
+    }
+    Ok(cli)
+}
+
+/// Writes the completion script to stdout. Profile names from the config file are baked into the
+/// script, so it needs regenerating after adding a profile.
+fn completions(cmd: &CompletionsCommand, config: &Config) {
+    let mut profiles: Vec<String> = config.profiles.keys().cloned().collect();
+    profiles.sort();
+    let mut command = Cli::command();
+    if !profiles.is_empty() {
+        command = command.mut_arg("profile", |arg| arg.value_parser(profiles));
+    }
+    clap_complete::generate(cmd.shell, &mut command, "photosort", &mut std::io::stdout());
+}
 
-    eprintln!("Result of metadata read: {:?}", data);
-    Date::try_from(date)
+/// Sets up logging to stderr. `RUST_LOG` takes precedence over -v/-q when it is set.
+fn init_logging(verbose: u8, quiet: u8) {
+    let level = match i16::from(verbose) - i16::from(quiet) {
+        i16::MIN..=-2 => "off",
+        -1 => "error",
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("photosort={}", level)));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(console::colors_enabled_stderr())
+        .init();
 }
 
 #[tokio::main]
-async fn main() -> Result<(), std::boxed::Box<(dyn std::error::Error)>> {
-    let in_file = std::env::args().nth(1).unwrap();
-    let renamer_arg = std::env::args().nth(2);
-    let renamer = get_renamer(&renamer_arg);
-    eprintln!("photosort {:?}", in_file);
-
-    let filename = Path::new(&in_file);
-    let date = get_date_from_file(&filename).await.context("Error in reading date out of input file")?;
-
-    let home_var = std::env::var("HOME").context("$HOME env var not available")?;
-    let home_dir = Path::new(&home_var);
-    let photos_dir = home_dir.join("annex/photos");
-    let new_path = format!("{}/{}/{}/{}", date.year(), date.month(), date.day(), filename.file_name().unwrap().to_str().unwrap());
-    let dest = photos_dir.join(&new_path);
-    let dest_dir = dest.parent().unwrap(); //.unwrap_or(Path::new("~/")).canonicalize().context("Failed to get parent of dest")?;
-    eprintln!("input path: {:?}", filename);
-    eprintln!("output path: {:?}", dest);
-    tokio::fs::create_dir_all(&dest_dir).await.context("Failed to create dest dir")?;
-    renamer.rename(&filename, &dest).await.context("Failed to rename file")?;
-    Ok(())
+async fn main() -> Result<ExitCode> {
+    let cli = Cli::parse();
+    report::init_color(cli.color);
+    init_logging(cli.verbose, cli.quiet);
+    let journaled = match &cli.command {
+        Command::Resume(ResumeCommand { journal }) | Command::Undo(UndoCommand { journal }) => {
+            Some(Run::read(journal.as_deref())?)
+        }
+        _ => None,
+    };
+    let undo = matches!(cli.command, Command::Undo(_));
+    // Whatever the journaled run was started with, it's this one's to say how to lock.
+    let (wait, force_unlock) = (cli.wait, cli.force_unlock);
+    let cli = match &journaled {
+        Some(run) => journaled_cli(run)?,
+        None => cli,
+    };
+    let config = Config::load(cli.config.as_deref())?;
+    let profile = config.profile(cli.profile.as_deref())?;
+    let library = match &journaled {
+        Some(run) => run.library.clone(),
+        None => library_root(cli.dest.as_deref().or(profile.dest.as_deref()))?,
+    };
+    if let (true, Some(run)) = (undo, &journaled) {
+        let options = match &cli.command {
+            Command::Sort(cmd) => &cmd.options,
+            Command::Apply(cmd) => &cmd.options,
+            _ => unreachable!("only sort and apply runs are journaled"),
+        };
+        let mut sorter = Sorter::new(library, options, &profile)?;
+        sorter.lock(wait, force_unlock).await?;
+        let undone_all = sorter.undo(run).await?;
+        return Ok(if undone_all { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+    }
+    let resumed = journaled;
+
+    match &cli.command {
+        Command::Sort(cmd) => {
+            let mut sorter = Sorter::new(library, &cmd.options, &profile)?;
+            sorter.lock(wait, force_unlock).await?;
+            let inputs = match &resumed {
+                Some(run) => {
+                    sorter.resume(run).await?;
+                    run.inputs.clone()
+                }
+                None => {
+                    if cmd.interactive && cmd.files_from.as_deref() == Some(Path::new("-")) {
+                        bail!(
+                            "--interactive reads answers from stdin, so it can't be combined \
+                             with --files-from -"
+                        );
+                    }
+                    let mut inputs = cmd.inputs.clone();
+                    if let Some(list) = &cmd.files_from {
+                        inputs.extend(read_file_list(list, cmd.null_separated)?);
+                    }
+                    sorter.start_journal(&inputs)?;
+                    inputs
+                }
+            };
+            let sorter = Arc::new(sorter);
+            let summary = sorter
+                .sort_paths(&inputs, cmd.extract_to.as_deref(), cmd.interactive, cmd.since_last_run)
+                .await?;
+            return Ok(summary.exit_code());
+        }
+        Command::Plan(cmd) => {
+            let sorter = Arc::new(Sorter::new(library, &cmd.options, &profile)?);
+            let mut inputs = cmd.inputs.clone();
+            if let Some(list) = &cmd.files_from {
+                inputs.extend(read_file_list(list, cmd.null_separated)?);
+            }
+            let (manifest, summary) = sorter.plan_paths(&inputs).await?;
+            manifest.write(&cmd.manifest)?;
+            return Ok(summary.exit_code());
+        }
+        Command::Apply(cmd) => {
+            let manifest = Manifest::read(&cmd.manifest)?;
+            // The library the plan was made for, whatever this run's --dest or profile says.
+            let mut sorter = Sorter::new(manifest.library.clone(), &cmd.options, &profile)?;
+            sorter.lock(wait, force_unlock).await?;
+            match &resumed {
+                Some(run) => sorter.resume(run).await?,
+                None => sorter.start_journal(&[])?,
+            }
+            let summary = sorter.apply_manifest(&manifest).await?;
+            return Ok(summary.exit_code());
+        }
+        Command::Verify(cmd) => {
+            let layout = Layout::new(library, &cmd.layout, &profile)?;
+            let dates = DateReader::new(&cmd.dates, &profile)?;
+            if !verify(cmd, &layout, &dates).await? {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Exif(cmd) => {
+            let dates = DateReader::new(&cmd.dates, &profile)?;
+            dump::dump(cmd, &dates).await?
+        }
+        Command::Thumb(cmd) => thumb::thumb(cmd).await?,
+        Command::Watch(cmd) => {
+            let mut sorter = Sorter::new(library, &cmd.options, &profile)?;
+            sorter.lock(wait, force_unlock).await?;
+            watch(cmd, &sorter).await?
+        }
+        Command::Completions(cmd) => completions(cmd, &config),
+        Command::Resume(_) | Command::Undo(_) => {
+            unreachable!("journaled runs are run as the command they were")
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PNG chunk of type `kind` around `data`. The CRC isn't checked, so it's left as zeros.
+    fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u32).to_be_bytes()[..], kind, data, &[0; 4]].concat()
+    }
+
+    /// A PNG with `chunks` between its header and image data.
+    fn png(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut png = [exif::PNG_SIGNATURE, &png_chunk(b"IHDR", &[0; 13])].concat();
+        chunks.iter().for_each(|chunk| png.extend_from_slice(chunk));
+        png.extend(png_chunk(b"IDAT", &[0x78; 40]));
+        png.extend(png_chunk(b"IEND", b""));
+        png
+    }
+
+    /// A RIFF chunk with the ID `id` around `data`, padded to an even length.
+    fn riff_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = [&id[..], &(data.len() as u32).to_le_bytes(), data].concat();
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    /// A RIFF file of the form type `form` holding `chunks`.
+    fn riff(form: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body = [&form[..], &chunks.concat()].concat();
+        [&b"RIFF"[..], &(body.len() as u32).to_le_bytes(), &body].concat()
+    }
+
+    /// Writes `contents` to a file named `name` in a new temporary directory.
+    fn file(name: &str, contents: &[u8]) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    /// The date `contents` has when read as a file, checking that it was found in `container`.
+    /// Built with kamadak-exif, EXIF is read by that first, which doesn't say where it was.
+    async fn read(contents: &[u8], container: &str) -> Result<String, FileParseError> {
+        let (_dir, path) = file("a", contents);
+        let metadata = read_metadata(&path).await?;
+        match metadata.location {
+            Some(location) => assert_eq!(location.container, container),
+            None if cfg!(feature = "kamadak-exif") => {}
+            None => panic!("no location noted"),
+        }
+        Ok(metadata.date.unwrap().to_string())
+    }
+
+    /// Reads every shorter prefix of `contents`, none of which should panic.
+    async fn read_cut_short(contents: &[u8]) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        for len in 0..contents.len() {
+            std::fs::write(&path, &contents[..len]).unwrap();
+            let _ = read_metadata(&path).await;
+        }
+    }
+
+    #[test]
+    fn from_text_reads_the_formats_programs_write() {
+        let dates = [
+            ("2020:02:01 14:32:14", "2020-02-01T14:32:14", "", None),
+            ("2020-02-01T14:32:14.250+01:00", "2020-02-01T14:32:14", "250", Some(3600)),
+            ("2020-02-01T14:32Z", "2020-02-01T14:32:00", "", Some(0)),
+            ("  2020-02-01\n", "2020-02-01T00:00:00", "", None),
+            ("Sat, 01 Feb 2020 14:32:14 +0000", "2020-02-01T14:32:14", "", Some(0)),
+            ("1 February 2020", "2020-02-01T00:00:00", "", None),
+            ("Sat Feb  1 14:32:14 2020", "2020-02-01T14:32:14", "", None),
+            ("2016:12:31 23:59:60", "2016-12-31T23:59:60", "", None),
+        ];
+        for (text, expected, subsec, offset) in dates {
+            let date = Date::from_text(text).unwrap_or_else(|| panic!("{:?} didn't parse", text));
+            assert_eq!(date.to_string(), expected, "{:?}", text);
+            assert_eq!(date.subsec(), subsec, "{:?}", text);
+            assert_eq!(date.offset(), offset, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn from_text_refuses_what_isnt_a_date() {
+        let texts = [
+            "",
+            "2020",
+            "2020-13-01",
+            "2020-02-30 00:00:00",
+            "2020-02-01X",
+            "2020-02-01T14",
+            "2020-02-01 14:32:14:15",
+            "2020é02-01",
+            "２０２０-02-01",
+            "Feb 2020",
+            "Sat, 01 Foo 2020 14:32:14",
+            "Sat, 001 Feb 2020",
+            "Sat Feb  1 14:32 20",
+        ];
+        for text in texts {
+            assert!(Date::from_text(text).is_none(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn from_text_survives_being_cut_short() {
+        let texts = ["2020-02-01T14:32:14.250+01:00", "Sat, 01 Feb 2020 14:32:14 +0000", "€2020"];
+        for text in texts {
+            for (end, _) in text.char_indices() {
+                Date::from_text(&text[..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_offset_reads_time_zone_designators() {
+        let zones = [("Z", 0), ("GMT", 0), ("+01:00", 3600), ("-0500", -18000), (" +09 ", 32400)];
+        for (zone, seconds) in zones {
+            assert_eq!(parse_offset(zone), Some(seconds), "{:?}", zone);
+        }
+        for zone in ["", "+", "CET", "+1", "+123", "+01:0a", "-€1", "01:00"] {
+            assert_eq!(parse_offset(zone), None, "{:?}", zone);
+        }
+    }
+
+    #[tokio::test]
+    async fn png_dates_come_from_exif_or_text_chunks() {
+        let tiff = testutil::exif_tiff("2021:06:07 12:34:56");
+        let with_exif = png(&[png_chunk(b"eXIf", &tiff)]);
+        let date = read(&with_exif, "PNG eXIf chunk").await.unwrap();
+        assert_eq!(date, "2021-06-07T12:34:56");
+
+        let text = png_chunk(b"tEXt", b"Creation Time\x002020:02:01 14:32:14");
+        let date = read(&png(&[text]), "PNG tEXt chunk").await.unwrap();
+        assert_eq!(date, "2020-02-01T14:32:14");
+
+        // Behind a broken eXIf chunk and a text chunk with another keyword.
+        let itxt = png_chunk(b"iTXt", b"date:create\0\0\0en\0\x002020-02-01T14:32:14+01:00");
+        let other = png_chunk(b"tEXt", b"Software\x002019:01:01 00:00:00");
+        let chunks = [png_chunk(b"eXIf", b"II*\0bad"), other, itxt];
+        let date = read(&png(&chunks), "PNG iTXt chunk").await.unwrap();
+        assert_eq!(date, "2020-02-01T14:32:14");
+
+        assert!(read(&png(&[]), "").await.is_err());
+        read_cut_short(&with_exif).await;
+    }
+
+    #[tokio::test]
+    async fn webp_exif_is_found_past_the_image_data() {
+        let tiff = testutil::exif_tiff("2021:06:07 12:34:56");
+        let exif = [exif::EXIF_SIGNATURE, &tiff].concat();
+        let webp = riff(b"WEBP", &[riff_chunk(b"VP8 ", &[0; 11]), riff_chunk(b"EXIF", &exif)]);
+        let date = read(&webp, "WebP EXIF chunk").await.unwrap();
+        assert_eq!(date, "2021-06-07T12:34:56");
+        assert!(read(&riff(b"WEBP", &[riff_chunk(b"VP8 ", &[0; 40])]), "").await.is_err());
+        read_cut_short(&webp).await;
+    }
+
+    #[tokio::test]
+    async fn avi_dates_come_from_idit_or_dtim_chunks_outside_the_frames() {
+        let idit = riff_chunk(b"IDIT", b"Sat Feb 01 14:32:14 2020\n\0");
+        let header = [&b"hdrl"[..], &riff_chunk(b"avih", &[0; 7]), &idit].concat();
+        let hdrl = riff_chunk(b"LIST", &header);
+        let avi = riff(b"AVI ", &[hdrl]);
+        let date = read(&avi, "AVI IDIT chunk").await.unwrap();
+        assert_eq!(date, "2020-02-01T14:32:14");
+        read_cut_short(&avi).await;
+
+        // A date in the frames is passed over for the DTIM chunk after them.
+        let frames = [&b"movi"[..], &riff_chunk(b"IDIT", b"Mon Jan 01 00:00:00 1990\0")].concat();
+        let ticks: u64 = (1_580_567_534 + 11_644_473_600) * 10_000_000;
+        let filetime = [(ticks >> 32) as u32, ticks as u32].map(u32::to_le_bytes).concat();
+        let avi = riff(b"AVI ", &[riff_chunk(b"LIST", &frames), riff_chunk(b"DTIM", &filetime)]);
+        let date = read(&avi, "AVI DTIM chunk").await.unwrap();
+        assert_eq!(date, "2020-02-01T14:32:14");
+        read_cut_short(&avi).await;
+
+        assert!(read(&riff(b"AVI ", &[riff_chunk(b"JUNK", &[0; 32])]), "").await.is_err());
+    }
 }
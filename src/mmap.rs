@@ -0,0 +1,65 @@
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
+
+use crate::{bmff, exif, FileParseError, Metadata};
+
+/// Files at least this big are memory-mapped to read their metadata, when they're in a format
+/// that's read that way. Below it, a few reads cost less than setting up the map.
+pub const MIN_LEN: u64 = 16 * 1024 * 1024;
+
+/// Reads the metadata of a TIFF-based RAW file, HEIF image or ISO-BMFF movie through a memory
+/// map, so following IFD and box offsets around a large file is left to the page cache rather
+/// than done with seeks and buffered reads. `None` for files in other formats, which are read
+/// the usual way.
+pub async fn read(path: &Path) -> Result<Option<Metadata>, FileParseError> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || read_blocking(path))
+        .await
+        .map_err(|e| FileParseError::FileError(std::io::Error::other(e)))?
+}
+
+/// Maps the whole of the file at `path` for reading.
+pub fn map(path: &Path) -> std::io::Result<Mmap> {
+    let file = std::fs::File::open(path)?;
+    // Safety: the map is only read. A file that another program truncates while it's mapped
+    // would fault on access past its new end, a risk photosort takes for files it's reading.
+    unsafe { Mmap::map(&file) }
+}
+
+fn read_blocking(path: PathBuf) -> Result<Option<Metadata>, FileParseError> {
+    let map = map(&path)?;
+    let data = &map[..];
+    // Where a slice of the map starts in the file.
+    let offset_of = |part: &[u8]| (part.as_ptr() as usize - data.as_ptr() as usize) as u64;
+    let metadata = if exif::is_tiff(data) {
+        Metadata::from_exif(exif::read(data)?)?.found_in("TIFF structure", 0)
+    } else if bmff::has_brand(data, bmff::HEIF_BRANDS) {
+        let tiff = heif_exif_tiff(data)?;
+        Metadata::from_exif(exif::read(tiff)?)?.found_in("HEIF Exif item", offset_of(tiff))
+    } else if bmff::is_bmff(data) {
+        let moov = top_level_box(data, b"moov").ok_or(bmff::BmffError::NoMovieHeader)?;
+        Metadata::from_moov(moov)?.found_in("moov box", offset_of(moov))
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(metadata))
+}
+
+/// The TIFF structure in the Exif item of the HEIF image `data`.
+pub fn heif_exif_tiff(data: &[u8]) -> Result<&[u8], FileParseError> {
+    let meta = top_level_box(data, b"meta").ok_or(bmff::BmffError::NoExifItem)?;
+    let (offset, len) = bmff::exif_location(meta)?;
+    let item = usize::try_from(offset)
+        .ok()
+        .zip(usize::try_from(len).ok())
+        .and_then(|(offset, len)| data.get(offset..offset.checked_add(len)?))
+        .ok_or(exif::ExifError::Truncated)?;
+    Ok(bmff::exif_item_tiff(item)?)
+}
+
+/// Body of the first top-level box of the given type.
+fn top_level_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    bmff::boxes(data).find(|(k, _)| k == kind).map(|(_, body)| body)
+}
@@ -0,0 +1,151 @@
+/// AVCHD files are MPEG transport streams whose 188 byte packets each follow a 4 byte timecode.
+const PACKET_LEN: usize = 192;
+/// First byte of every transport stream packet.
+const SYNC_BYTE: u8 = 0x47;
+/// PID of the main video stream in AVCHD files.
+const VIDEO_PID: u16 = 0x1011;
+/// UUID of the H.264 user data SEI message that camcorders store recording metadata in, followed
+/// by its "Modified Digital Video Pack Metadata" signature.
+const MDPM_SIGNATURE: &[u8] =
+    b"\x17\xee\x8c\x60\xf8\x4d\x11\xd9\x8c\xd6\x08\x00\x20\x0c\x9a\x66MDPM";
+/// MDPM tags holding the time zone, year and month, then the day, hour, minute and second.
+const MDPM_DATE: u8 = 0x18;
+const MDPM_TIME: u8 = 0x19;
+
+/// Whether `header` starts like an AVCHD transport stream (`.MTS` or `.M2TS`).
+pub fn is_m2ts(header: &[u8]) -> bool {
+    header.get(4) == Some(&SYNC_BYTE) && header.get(4 + PACKET_LEN) == Some(&SYNC_BYTE)
+}
+
+/// Recording time from the MDPM metadata in the video stream, in EXIF's `YYYY:MM:DD HH:MM:SS`
+/// form. Only the packets in `data` are looked at, and the metadata comes with the first frame,
+/// so the start of the file is enough.
+pub fn recording_time(data: &[u8]) -> Option<String> {
+    let mut video = Vec::new();
+    for packet in data.chunks_exact(PACKET_LEN) {
+        let ts = &packet[4..];
+        if ts[0] != SYNC_BYTE {
+            break;
+        }
+        if u16::from_be_bytes([ts[1] & 0x1f, ts[2]]) != VIDEO_PID {
+            continue;
+        }
+        // Packets may carry an adaptation field ahead of (or instead of) their payload.
+        let payload = match (ts[3] >> 4) & 0x3 {
+            1 => &ts[4..],
+            3 => match ts.get(5 + usize::from(ts[4])..) {
+                Some(payload) => payload,
+                None => continue,
+            },
+            _ => continue,
+        };
+        video.extend_from_slice(payload);
+    }
+
+    let start = video.windows(MDPM_SIGNATURE.len()).position(|w| w == MDPM_SIGNATURE)?;
+    let sei = unescape(&video[start + MDPM_SIGNATURE.len()..]);
+    let (&count, entries) = sei.split_first()?;
+    let entries: Vec<&[u8]> = entries.chunks_exact(5).take(usize::from(count)).collect();
+    let find = |tag: u8| entries.iter().find(|e| e[0] == tag).map(|e| &e[1..]);
+    let (date, time) = (find(MDPM_DATE)?, find(MDPM_TIME)?);
+    // Everything is binary-coded decimal, so the hex digits are the decimal ones.
+    let digits = date[1..].iter().chain(time);
+    if digits.clone().any(|&b| b >> 4 > 9 || b & 0xf > 9) {
+        return None;
+    }
+    Some(format!(
+        "{:02x}{:02x}:{:02x}:{:02x} {:02x}:{:02x}:{:02x}",
+        date[1], date[2], date[3], time[0], time[1], time[2], time[3]
+    ))
+}
+
+/// Drops the emulation prevention bytes H.264 inserts after two zero bytes, up to the end of
+/// the SEI message (as far as an MDPM block could reach).
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut zeros = 0;
+    for &b in data.iter().take(1 + 255 * 5 * 2) {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 192 byte AVCHD packet of the stream `pid` carrying `payload`, with an adaptation field
+    /// of stuffing ahead of it to fill the packet out.
+    fn packet(pid: u16, payload: &[u8]) -> Vec<u8> {
+        let stuffing = PACKET_LEN - 8 - payload.len();
+        let control = if stuffing > 0 { 0x30 } else { 0x10 };
+        let mut packet = vec![0, 0, 0, 0, SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, control];
+        if stuffing > 0 {
+            packet.push(stuffing as u8 - 1);
+            packet.resize(packet.len() + stuffing - 1, 0xff);
+        }
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// The start of a video recorded at 2021-06-07 00:00:01, with the MDPM block split across
+    /// two video packets and an audio packet between them. The time's two zero bytes are
+    /// followed by an emulation prevention byte, as H.264 requires.
+    fn video() -> Vec<u8> {
+        let mdpm = [
+            MDPM_SIGNATURE,
+            &[2, MDPM_DATE, 0x00, 0x20, 0x21, 0x06, MDPM_TIME, 0x07, 0x00, 0x00, 0x03, 0x01],
+        ]
+        .concat();
+        let (first, second) = mdpm.split_at(12);
+        [
+            packet(VIDEO_PID, &[0x11; 184]),
+            packet(VIDEO_PID, first),
+            packet(0x1100, &[0; 184]),
+            packet(VIDEO_PID, second),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn recording_time_reads_the_mdpm_date_and_time() {
+        let video = video();
+        assert!(is_m2ts(&video));
+        assert_eq!(recording_time(&video).as_deref(), Some("2021:06:07 00:00:01"));
+    }
+
+    #[test]
+    fn recording_time_survives_cut_short_streams() {
+        let video = video();
+        for len in 0..video.len() {
+            assert_eq!(recording_time(&video[..len]), None, "cut to {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn recording_time_refuses_digits_that_arent_decimal() {
+        let mut video = video();
+        let at = video.windows(4).position(|w| w == [MDPM_DATE, 0x00, 0x20, 0x21]).unwrap();
+        video[at + 4] = 0x2a;
+        assert_eq!(recording_time(&video), None);
+    }
+
+    #[test]
+    fn recording_time_stops_at_a_lost_sync_byte() {
+        let mut video = video();
+        video[PACKET_LEN + 4] = 0;
+        assert_eq!(recording_time(&video), None);
+    }
+
+    #[test]
+    fn unescape_drops_emulation_prevention_bytes() {
+        assert_eq!(unescape(&[0, 0, 3, 1, 0, 0, 3, 0, 0, 3]), [0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(unescape(&[0, 3, 0, 0, 0, 3]), [0, 3, 0, 0, 0]);
+        assert_eq!(unescape(&[]), [0u8; 0]);
+    }
+}
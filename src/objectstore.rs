@@ -0,0 +1,101 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+use async_trait::async_trait;
+
+use crate::renamer::{run, RenameError, Renamer};
+use crate::report::Transfer;
+
+#[derive(Clone, Copy)]
+enum Store {
+    /// `s3://` URLs, through the AWS CLI. It also talks to S3-compatible stores (MinIO,
+    /// Backblaze B2 and the like) when `AWS_ENDPOINT_URL` points at one.
+    S3,
+    /// `gs://` URLs, through the Google Cloud CLI.
+    Gcs,
+}
+
+/// Uploads files to a bucket, under keys laid out the way paths in the library would be. Files
+/// are removed locally once uploaded, unless they're being copied.
+pub struct ObjectStoreRenamer {
+    store: Store,
+    copy: bool,
+}
+
+impl ObjectStoreRenamer {
+    /// Returns None unless `library` is an `s3://` or `gs://` URL.
+    pub fn new(library: &Path, copy: bool) -> Option<Self> {
+        let url = library.to_str()?;
+        let store = if url.starts_with("s3://") {
+            Store::S3
+        } else if url.starts_with("gs://") {
+            Store::Gcs
+        } else {
+            return None;
+        };
+        Some(Self { store, copy })
+    }
+
+    fn program(&self) -> &'static str {
+        match self.store {
+            Store::S3 => "aws",
+            Store::Gcs => "gcloud",
+        }
+    }
+}
+
+#[async_trait]
+impl Renamer for ObjectStoreRenamer {
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError> {
+        let (source_arg, url) = (source.as_os_str(), dest.as_os_str());
+        let args = match self.store {
+            Store::S3 => [OsStr::new("s3"), OsStr::new("cp"), source_arg, url],
+            Store::Gcs => [OsStr::new("storage"), OsStr::new("cp"), source_arg, url],
+        };
+        run(self.program(), &args, dest).await?;
+        if !self.copy {
+            tokio::fs::remove_file(source)
+                .await
+                .map_err(|e| RenameError::from_io(e, source, dest))?;
+        }
+        Ok(())
+    }
+
+    fn transfer(&self) -> Transfer {
+        if self.copy {
+            Transfer::Copy
+        } else {
+            Transfer::Move
+        }
+    }
+
+    async fn exists(&self, dest: &Path) -> Result<bool, RenameError> {
+        let url = dest.to_string_lossy();
+        let checked = match self.store {
+            Store::S3 => {
+                let (bucket, key) = url["s3://".len()..].split_once('/').unwrap_or((&url, ""));
+                let args = ["s3api", "head-object", "--bucket", bucket, "--key", key];
+                let args: Vec<&OsStr> = args.iter().map(OsStr::new).collect();
+                run(self.program(), &args, dest).await
+            }
+            Store::Gcs => {
+                let args = ["storage", "objects", "describe", &url];
+                let args: Vec<&OsStr> = args.iter().map(OsStr::new).collect();
+                run(self.program(), &args, dest).await
+            }
+        };
+        match checked {
+            Ok(_) => Ok(true),
+            Err(RenameError::CommandFailed { ref stderr, .. })
+                if stderr.contains("404") || stderr.to_lowercase().contains("not found") =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn local(&self) -> bool {
+        false
+    }
+}
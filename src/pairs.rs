@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use crate::walk::has_extension;
+
+/// Extensions of the RAW formats photosort reads, which cameras can save a JPEG alongside.
+const RAW_EXTENSIONS: &[&str] = &["arw", "cr2", "dng", "nef", "orf", "raf", "rw2"];
+const JPEG_EXTENSIONS: &[&str] = &["jpg", "jpeg"];
+/// Extensions of the stills of Live Photos. ProRAW ones are DNGs.
+const LIVE_STILL_EXTENSIONS: &[&str] = &["heic", "heif", "jpg", "jpeg", "dng"];
+const LIVE_MOTION_EXTENSIONS: &[&str] = &["mov"];
+
+/// Why a file is sorted along with another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Partner {
+    /// The JPEG a camera saved alongside a RAW file, with `--pair`.
+    Jpeg,
+    /// The video half of a Live Photo.
+    Motion,
+}
+
+impl Partner {
+    /// Extensions of the files this kind of partner goes with, and of the partners themselves.
+    fn extensions(self) -> (&'static [&'static str], &'static [&'static str]) {
+        match self {
+            Partner::Jpeg => (RAW_EXTENSIONS, JPEG_EXTENSIONS),
+            Partner::Motion => (LIVE_STILL_EXTENSIONS, LIVE_MOTION_EXTENSIONS),
+        }
+    }
+}
+
+/// A file to sort, and the files sorted along with it.
+pub struct Unit {
+    pub path: PathBuf,
+    pub partners: Vec<(PathBuf, Partner)>,
+}
+
+impl From<PathBuf> for Unit {
+    fn from(path: PathBuf) -> Unit {
+        Unit { path, partners: Vec::new() }
+    }
+}
+
+/// Gives each file in `units`, the files found in one directory, that `kind` of partner goes
+/// with the one of the same name, if there is one, in place of the partner's own unit. Cameras
+/// and phones name the halves alike: `DSC_0001.NEF` and `DSC_0001.JPG`, `IMG_1234.HEIC` and
+/// `IMG_1234.MOV`. Everything else is left as it is, in order.
+pub fn pair(units: Vec<Unit>, kind: Partner) -> Vec<Unit> {
+    let (leaders, followers) = kind.extensions();
+    let mut leading: HashMap<&OsStr, usize> = HashMap::new();
+    for (i, unit) in units.iter().enumerate().filter(|(_, u)| has_extension(&u.path, leaders)) {
+        if let Some(stem) = unit.path.file_stem() {
+            leading.entry(stem).or_insert(i);
+        }
+    }
+    // The unit of each leader's partner. Ones with partners of their own are left alone.
+    let mut partners: HashMap<usize, usize> = HashMap::new();
+    for (i, unit) in units.iter().enumerate().filter(|(_, u)| has_extension(&u.path, followers)) {
+        if let Some(&leader) = unit.path.file_stem().and_then(|stem| leading.get(stem)) {
+            if unit.partners.is_empty() {
+                partners.entry(leader).or_insert(i);
+            }
+        }
+    }
+    // Taken out of the list first, since a partner can come before the file it goes with.
+    let mut units: Vec<_> = units.into_iter().map(Some).collect();
+    let mut taken = HashMap::new();
+    for (leader, partner) in partners {
+        if let Some(unit) = units[partner].take() {
+            taken.insert(leader, unit.path);
+        }
+    }
+    units
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, unit)| {
+            let mut unit = unit?;
+            unit.partners.extend(taken.remove(&i).map(|path| (path, kind)));
+            Some(unit)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn units(names: &[&str]) -> Vec<Unit> {
+        names.iter().map(|name| Unit::from(PathBuf::from(name))).collect()
+    }
+
+    /// Each unit's file, then its partners'.
+    fn names(units: &[Unit]) -> Vec<Vec<&str>> {
+        units.iter().map(files).collect()
+    }
+
+    fn files(unit: &Unit) -> Vec<&str> {
+        let partners = unit.partners.iter().map(|(path, _)| path);
+        std::iter::once(&unit.path).chain(partners).map(|path| path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn pairs_raw_files_with_their_jpegs() {
+        let paired = pair(units(&["a.JPG", "a.NEF", "b.jpg", "c.cr2"]), Partner::Jpeg);
+        assert_eq!(names(&paired), [vec!["a.NEF", "a.JPG"], vec!["b.jpg"], vec!["c.cr2"]]);
+    }
+
+    #[test]
+    fn pairs_live_photos_after_raw_files() {
+        let units = pair(units(&["a.dng", "a.jpg", "a.mov", "b.heic", "b.mov"]), Partner::Jpeg);
+        let paired = pair(units, Partner::Motion);
+        assert_eq!(names(&paired), [vec!["a.dng", "a.jpg", "a.mov"], vec!["b.heic", "b.mov"]]);
+    }
+
+    #[test]
+    fn pairs_each_file_once() {
+        let paired = pair(units(&["a.nef", "a.cr2", "a.jpg", "a.jpeg"]), Partner::Jpeg);
+        assert_eq!(names(&paired), [vec!["a.nef", "a.jpg"], vec!["a.cr2"], vec!["a.jpeg"]]);
+    }
+}
@@ -0,0 +1,178 @@
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::cli::SortOptions;
+use crate::config::Profile;
+
+/// What `--chmod` and `--chown` say to give sorted files and the directories made for them.
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    mode: Option<u32>,
+    owner: Option<Owner>,
+}
+
+/// A user and group to give files to, either of which may be left as it is.
+#[derive(Debug, Clone, Copy)]
+pub struct Owner {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl Permissions {
+    pub fn new(options: &SortOptions, profile: &Profile) -> Result<Permissions> {
+        let mode = match (options.chmod, &profile.chmod) {
+            (Some(mode), _) => Some(mode),
+            (None, Some(mode)) => Some(
+                parse_mode(mode)
+                    .map_err(anyhow::Error::msg)
+                    .context("Invalid chmod in config profile")?,
+            ),
+            (None, None) => None,
+        };
+        let owner = match (options.chown, &profile.chown) {
+            (Some(owner), _) => Some(owner),
+            (None, Some(owner)) => Some(
+                parse_owner(owner)
+                    .map_err(anyhow::Error::msg)
+                    .context("Invalid chown in config profile")?,
+            ),
+            (None, None) => None,
+        };
+        Ok(Permissions { mode, owner })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.owner.is_none()
+    }
+
+    /// Sets a sorted file's owner and mode. Symlinks are left alone, since what they point to
+    /// isn't in the library.
+    pub fn apply_to_file(&self, path: &Path) -> io::Result<()> {
+        if std::fs::symlink_metadata(path)?.file_type().is_symlink() {
+            return Ok(());
+        }
+        self.apply(path, self.mode)
+    }
+
+    /// Sets the owner and mode of a directory made for sorted files. It gets search permission
+    /// wherever the mode gives read permission, so that what's in it can be read.
+    pub fn apply_to_dir(&self, path: &Path) -> io::Result<()> {
+        self.apply(path, self.mode.map(|mode| mode | (mode & 0o444) >> 2))
+    }
+
+    #[cfg(unix)]
+    fn apply(&self, path: &Path, mode: Option<u32>) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Before the mode, since changing the owner can clear setuid and setgid bits.
+        if let Some(Owner { uid, gid }) = self.owner {
+            std::os::unix::fs::chown(path, uid, gid)?;
+        }
+        if let Some(mode) = mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    /// Nothing to do, since neither option parses off Unix.
+    #[cfg(not(unix))]
+    fn apply(&self, _path: &Path, _mode: Option<u32>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parses a file mode in octal, like `640` or `0664`.
+pub fn parse_mode(arg: &str) -> Result<u32, String> {
+    if !cfg!(unix) {
+        return Err("file modes can only be set on Unix".into());
+    }
+    u32::from_str_radix(arg, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777)
+        .ok_or_else(|| format!("expected an octal mode like 640, found {:?}", arg))
+}
+
+/// Parses `USER`, `USER:GROUP` or `:GROUP`, each a name or a numeric ID.
+pub fn parse_owner(arg: &str) -> Result<Owner, String> {
+    if !cfg!(unix) {
+        return Err("owners can only be set on Unix".into());
+    }
+    let (user, group) = match arg.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (arg, None),
+    };
+    let uid = match user {
+        "" => None,
+        user => Some(user.parse().ok().or_else(|| sys::user_id(user)).ok_or_else(|| {
+            format!("no user named {:?}", user)
+        })?),
+    };
+    let gid = match group.filter(|group| !group.is_empty()) {
+        None => None,
+        Some(group) => Some(group.parse().ok().or_else(|| sys::group_id(group)).ok_or_else(
+            || format!("no group named {:?}", group),
+        )?),
+    };
+    if uid.is_none() && gid.is_none() {
+        return Err(format!("expected USER, USER:GROUP or :GROUP, found {:?}", arg));
+    }
+    Ok(Owner { uid, gid })
+}
+
+/// Looking up users and groups by name, with the reentrant calls since other threads may be
+/// running.
+#[cfg(unix)]
+mod sys {
+    use std::ffi::CString;
+    use std::os::raw::c_char;
+
+    pub fn user_id(name: &str) -> Option<u32> {
+        let name = CString::new(name).ok()?;
+        // Safety: passwd is plain data, all zeroes until getpwnam_r fills it in from a
+        // NUL-terminated name and a buffer valid for `len`.
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let found = lookup(|buf, len, result: &mut *mut libc::passwd| unsafe {
+            libc::getpwnam_r(name.as_ptr(), &mut entry, buf, len, result)
+        });
+        found.then_some(entry.pw_uid)
+    }
+
+    pub fn group_id(name: &str) -> Option<u32> {
+        let name = CString::new(name).ok()?;
+        // Safety: as in user_id.
+        let mut entry: libc::group = unsafe { std::mem::zeroed() };
+        let found = lookup(|buf, len, result: &mut *mut libc::group| unsafe {
+            libc::getgrnam_r(name.as_ptr(), &mut entry, buf, len, result)
+        });
+        found.then_some(entry.gr_gid)
+    }
+
+    /// Calls `get` with a buffer for the entry's strings, growing it while it's too small.
+    /// Returns whether an entry was found. `get` is only ever handed a buffer valid for the
+    /// length it's given.
+    fn lookup<T>(mut get: impl FnMut(*mut c_char, usize, &mut *mut T) -> libc::c_int) -> bool {
+        let mut buf = vec![0 as c_char; 1024];
+        loop {
+            let mut result = std::ptr::null_mut();
+            match get(buf.as_mut_ptr(), buf.len(), &mut result) {
+                libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+                0 => return !result.is_null(),
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// Never reached, since neither option parses off Unix.
+#[cfg(not(unix))]
+mod sys {
+    pub fn user_id(_name: &str) -> Option<u32> {
+        None
+    }
+
+    pub fn group_id(_name: &str) -> Option<u32> {
+        None
+    }
+}
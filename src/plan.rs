@@ -0,0 +1,88 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::DateSource;
+use crate::dump::describe;
+use crate::sort::Placement;
+use crate::{Camera, Date};
+
+/// What `photosort plan` writes and `photosort apply` reads: the moves a sort would make, with
+/// enough about each file to tell whether it changed in between. It's meant to be read and
+/// edited, so a destination can be changed or a file's entry dropped before it's applied.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub library: PathBuf,
+    pub files: Vec<PlannedFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PlannedFile {
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    /// The capture date the destination was worked out from, and the date source it came from.
+    pub date: String,
+    pub extractor: String,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub bytes: u64,
+    /// BLAKE3 hash of the file when it was planned, in hex.
+    pub blake3: String,
+}
+
+impl PlannedFile {
+    pub fn new(source: &Path, placement: &Placement, checksum: [u8; 32]) -> PlannedFile {
+        PlannedFile {
+            // So the plan can be applied from another directory.
+            source: std::path::absolute(source).unwrap_or_else(|_| source.to_path_buf()),
+            dest: placement.dest.clone(),
+            date: describe(&placement.date),
+            extractor: placement.extractor.to_string(),
+            camera_make: placement.camera.make.clone(),
+            camera_model: placement.camera.model.clone(),
+            bytes: placement.bytes,
+            blake3: blake3::Hash::from(checksum).to_hex().to_string(),
+        }
+    }
+
+    /// The placement the file was planned with, and the checksum it had.
+    pub fn placement(&self) -> Result<(Placement, [u8; 32])> {
+        let date = Date::from_text(&self.date)
+            .ok_or_else(|| anyhow!("{:?} is not a date", self.date))?;
+        let extractor = DateSource::value_variants()
+            .iter()
+            .map(|source| source.name())
+            .find(|name| *name == self.extractor)
+            .ok_or_else(|| anyhow!("{:?} is not a date source", self.extractor))?;
+        let checksum = blake3::Hash::from_hex(&self.blake3)
+            .map_err(|_| anyhow!("{:?} is not a BLAKE3 hash", self.blake3))?;
+        let camera = Camera::new(self.camera_make.clone(), self.camera_model.clone());
+        let placement = Placement {
+            dest: self.dest.clone(),
+            date,
+            extractor,
+            camera,
+            bytes: self.bytes,
+            skip: None,
+        };
+        Ok((placement, checksum.into()))
+    }
+}
+
+impl Manifest {
+    pub fn read(path: &Path) -> Result<Manifest> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read plan {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("Invalid plan {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut text = serde_json::to_string_pretty(self).expect("plans always serialize");
+        text.push('\n');
+        std::fs::write(path, text).with_context(|| format!("Failed to write plan {}", path.display()))
+    }
+}
@@ -0,0 +1,89 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+use async_trait::async_trait;
+
+use crate::renamer::{run, RenameError, Renamer};
+use crate::report::Transfer;
+
+/// rclone's exit codes for a directory or file that isn't there.
+const EXIT_DIR_NOT_FOUND: i32 = 3;
+const EXIT_FILE_NOT_FOUND: i32 = 4;
+/// rclone's exit code under `--error-on-no-transfer` when it didn't transfer anything.
+const EXIT_NO_TRANSFER: i32 = 9;
+
+/// Hands each file to `rclone moveto` (or `copyto`), for remotes photosort has no backend of its
+/// own for. The library is the `--remote`, like `myremote:photos`, and destinations are paths
+/// under it.
+pub struct RcloneRenamer {
+    copy: bool,
+    /// Whether rclone may replace a file that's already at the destination, under
+    /// `--on-conflict overwrite`. Otherwise a file that's turned up there since it was planned is
+    /// reported as [`RenameError::DestExists`], for the sorter to apply the policy to.
+    overwrite: bool,
+    /// `--bwlimit` for rclone, in bytes per second.
+    bwlimit: Option<String>,
+}
+
+impl RcloneRenamer {
+    pub fn new(copy: bool, overwrite: bool, bwlimit: Option<u64>) -> Self {
+        Self { copy, overwrite, bwlimit: bwlimit.map(|rate| format!("{}B", rate)) }
+    }
+}
+
+#[async_trait]
+impl Renamer for RcloneRenamer {
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError> {
+        let command = if self.copy { "copyto" } else { "moveto" };
+        let mut args = vec![OsStr::new(command), source.as_os_str(), dest.as_os_str()];
+        // Conflicts have been dealt with by the time a file gets here, so this only stops one
+        // that appeared since from being replaced. rclone skips it without failing, so it's
+        // asked to fail when nothing was transferred.
+        if !self.overwrite {
+            args.extend(["--ignore-existing", "--error-on-no-transfer"].map(OsStr::new));
+        }
+        if let Some(rate) = &self.bwlimit {
+            args.extend([OsStr::new("--bwlimit"), OsStr::new(rate)]);
+        }
+        match run("rclone", &args, dest).await {
+            Ok(_) => Ok(()),
+            // A move of a file that's the same as the one at the destination only removes the
+            // source, which is as good as moving it.
+            Err(RenameError::CommandFailed { status, .. })
+                if status.code() == Some(EXIT_NO_TRANSFER) =>
+            {
+                if self.copy || tokio::fs::symlink_metadata(source).await.is_ok() {
+                    Err(RenameError::DestExists(dest.to_path_buf()))
+                } else {
+                    Ok(())
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn transfer(&self) -> Transfer {
+        if self.copy {
+            Transfer::Copy
+        } else {
+            Transfer::Move
+        }
+    }
+
+    async fn exists(&self, dest: &Path) -> Result<bool, RenameError> {
+        let args = [OsStr::new("lsf"), dest.as_os_str()];
+        match run("rclone", &args, dest).await {
+            Ok(output) => Ok(!output.stdout.is_empty()),
+            Err(RenameError::CommandFailed { status, .. })
+                if matches!(status.code(), Some(EXIT_DIR_NOT_FOUND | EXIT_FILE_NOT_FOUND)) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn local(&self) -> bool {
+        false
+    }
+}
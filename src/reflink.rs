@@ -0,0 +1,62 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Errors that mean the filesystem can't clone these files, rather than that something's
+/// wrong: no clone support, or source and destination on different filesystems.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENOTSUP | libc::EXDEV | libc::EINVAL | libc::ENOTTY)
+    )
+}
+
+/// Makes the empty file `to` share `from`'s data, copy-on-write, as btrfs and XFS can. Returns
+/// false when the filesystem can't, so the caller can copy the data instead.
+#[cfg(target_os = "linux")]
+pub fn clone_data(from: &File, to: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: both are open file descriptors, and FICLONE takes the source's as its argument.
+    let result = unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) };
+    if result == 0 {
+        return Ok(true);
+    }
+    match io::Error::last_os_error() {
+        e if unsupported(&e) => Ok(false),
+        e => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn clone_data(_from: &File, _to: &File) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Creates `to` as a copy-on-write clone of `from`, as APFS can, along with its times,
+/// permissions and extended attributes. Returns false when the filesystem can't, so the
+/// caller can copy the file instead.
+#[cfg(target_os = "macos")]
+pub fn clone_file(from: &Path, to: &Path) -> io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    /// Clone a symlink itself rather than what it points at.
+    const CLONE_NOFOLLOW: u32 = 0x0001;
+    let from = CString::new(from.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let to = CString::new(to.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    // Safety: both paths are NUL-terminated.
+    if unsafe { libc::clonefile(from.as_ptr(), to.as_ptr(), CLONE_NOFOLLOW) } == 0 {
+        return Ok(true);
+    }
+    match io::Error::last_os_error() {
+        e if unsupported(&e) => Ok(false),
+        e => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn clone_file(_from: &Path, _to: &Path) -> io::Result<bool> {
+    Ok(false)
+}
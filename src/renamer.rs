@@ -0,0 +1,956 @@
+use std::io::{ErrorKind, Read};
+use std::ffi::{OsStr, OsString};
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::process::{ExitStatus, Output, Stdio};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+
+use crate::cli::{Backend, ConflictPolicy, RetryOn, SortOptions};
+use crate::config::Profile;
+use crate::exec::ExecRenamer;
+#[cfg(feature = "object-store")]
+use crate::objectstore::ObjectStoreRenamer;
+use crate::report::Transfer;
+use crate::rclone::RcloneRenamer;
+use crate::sort::Placement;
+use crate::webdav::WebdavRenamer;
+use crate::Camera;
+
+/// Why a file couldn't be moved into the library.
+#[derive(Error, Debug)]
+pub enum RenameError {
+    #[error("{} no longer exists", .0.display())]
+    SourceMissing(PathBuf),
+    #[error("{} already exists", .0.display())]
+    DestExists(PathBuf),
+    #[error("{} is on a different filesystem from {}", .0.display(), .1.display())]
+    CrossDevice(PathBuf, PathBuf),
+    #[error("Permission denied moving {} to {}", .0.display(), .1.display())]
+    PermissionDenied(PathBuf, PathBuf),
+    #[error("The copy of {} at {} doesn't match the original, so was removed", .0.display(), .1.display())]
+    ChecksumMismatch(PathBuf, PathBuf),
+    #[error("`{command}` failed ({status}): {stderr}")]
+    CommandFailed { command: String, status: ExitStatus, stderr: String },
+    #[error("{method} {url} failed with HTTP status {status}")]
+    Http { method: &'static str, url: String, status: u16 },
+    #[error("Could not move {} to {}: {2}", .0.display(), .1.display())]
+    Io(PathBuf, PathBuf, std::io::Error),
+    #[error("Gave up after {attempts} attempts: {last}")]
+    Exhausted { attempts: u32, last: Box<RenameError> },
+}
+
+impl RenameError {
+    /// Which `--retry-on` kind the error is, if it's one that could go away by trying again.
+    fn retry_kind(&self) -> Option<RetryOn> {
+        match self {
+            RenameError::Io(..) => Some(RetryOn::Io),
+            RenameError::PermissionDenied(..) => Some(RetryOn::Permission),
+            RenameError::CommandFailed { .. } => Some(RetryOn::Command),
+            RenameError::Http { status, .. } if *status >= 500 || matches!(status, 408 | 429) => {
+                Some(RetryOn::Http)
+            }
+            RenameError::ChecksumMismatch(..) => Some(RetryOn::Checksum),
+            _ => None,
+        }
+    }
+
+    /// Sorts an I/O error from moving `source` to `dest` into what went wrong.
+    pub fn from_io(err: std::io::Error, source: &Path, dest: &Path) -> RenameError {
+        let (source, dest) = (source.to_path_buf(), dest.to_path_buf());
+        match err.kind() {
+            // Also what a missing destination directory looks like.
+            ErrorKind::NotFound if std::fs::symlink_metadata(&source).is_err() => {
+                RenameError::SourceMissing(source)
+            }
+            ErrorKind::AlreadyExists => RenameError::DestExists(dest),
+            ErrorKind::CrossesDevices => RenameError::CrossDevice(source, dest),
+            ErrorKind::PermissionDenied => RenameError::PermissionDenied(source, dest),
+            _ => RenameError::Io(source, dest, err),
+        }
+    }
+}
+
+#[async_trait]
+pub trait Renamer: Send + Sync {
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError>;
+
+    /// What `rename` does with the file, for wording what's reported.
+    fn transfer(&self) -> Transfer {
+        Transfer::Move
+    }
+
+    /// Whether something's already at `dest`.
+    async fn exists(&self, dest: &Path) -> Result<bool, RenameError> {
+        Ok(tokio::fs::symlink_metadata(dest).await.is_ok())
+    }
+
+    /// Whether destinations are paths on this machine, with directories to create, rather than
+    /// keys in an object store.
+    fn local(&self) -> bool {
+        true
+    }
+
+    /// Whether `photosort undo` can reverse a move with plain file operations, which isn't so
+    /// for backends that record moves elsewhere or hand files to another program.
+    fn reversible(&self) -> bool {
+        false
+    }
+
+    /// Called after a file has been moved to `placement.dest`, for backends that record what it
+    /// was sorted by.
+    async fn annotate(&self, _placement: &Placement) -> Result<(), RenameError> {
+        Ok(())
+    }
+
+    /// Called once a batch of files has been moved, for backends that record each batch.
+    async fn finish(&self) -> Result<(), RenameError> {
+        Ok(())
+    }
+}
+
+pub struct FileRenamer {
+    /// Whether to checksum files copied across filesystems before removing the originals.
+    verify: bool,
+}
+
+impl FileRenamer {
+    pub fn new(verify: bool) -> Self {
+        Self { verify }
+    }
+}
+
+#[async_trait]
+impl Renamer for FileRenamer {
+    /// Renames the file, or where `dest` is on another filesystem (a NAS or external disk),
+    /// copies it there the way `--copy` does and then removes the original.
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError> {
+        let verify = self.verify;
+        // Something may have turned up at `dest` since it was planned, which isn't replaced
+        // but reported as `DestExists`, so the conflict policy gets a say.
+        blocking(source, dest, move |from, to| match rename_new(from, to) {
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+                debug!(dest = %to.display(), "Copying across filesystems");
+                copy_into_place(from, to, false, verify)?;
+                // Back to how things were, rather than the file in both places.
+                std::fs::remove_file(from)
+                    .inspect_err(|_| {
+                        let _ = std::fs::remove_file(to);
+                    })
+                    .map_err(|e| RenameError::from_io(e, from, to))
+            }
+            result => result.map_err(|e| RenameError::from_io(e, from, to)),
+        })
+        .await
+    }
+
+    fn reversible(&self) -> bool {
+        true
+    }
+}
+
+/// Moves files with `git mv` in the repository the library is in, and with `commit`, commits
+/// each batch.
+pub struct GitRenamer {
+    /// Top level of the repository's working tree, which git commands are run in.
+    root: PathBuf,
+    commit: bool,
+    /// Files moved since the last commit.
+    moved: Mutex<Vec<Moved>>,
+}
+
+/// A file moved into the library and waiting to be committed.
+struct Moved {
+    from: PathBuf,
+    to: PathBuf,
+    /// Whether `from` was in the repository, so its removal is committed along with `to`.
+    tracked: bool,
+}
+
+impl GitRenamer {
+    /// Finds the repository `library` is in. The library itself may not have been created yet,
+    /// so the search starts from the closest directory above it that exists.
+    pub fn new(library: &Path, commit: bool) -> Result<Self, RenameError> {
+        let start = library.ancestors().find(|dir| dir.is_dir()).unwrap_or(Path::new("."));
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(start)
+            .args(["rev-parse", "--show-toplevel"])
+            .output()
+            .map_err(|e| RenameError::Io(library.to_path_buf(), library.to_path_buf(), e))?;
+        if !output.status.success() {
+            return Err(command_failed("git rev-parse --show-toplevel".to_string(), &output));
+        }
+        let root = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim_end());
+        Ok(GitRenamer { root, commit, moved: Mutex::new(Vec::new()) })
+    }
+
+    /// Absolute forms of `source` and `dest`, whose directory must exist. Relative paths would be
+    /// taken from the repository rather than where photosort was run.
+    async fn resolve(&self, source: &Path, dest: &Path) -> Result<(PathBuf, PathBuf), RenameError> {
+        let io_error = |e| RenameError::from_io(e, source, dest);
+        let from = tokio::fs::canonicalize(source).await.map_err(io_error)?;
+        let dest_dir = dest.parent().unwrap_or(Path::new("."));
+        let to = tokio::fs::canonicalize(dest_dir)
+            .await
+            .map_err(io_error)?
+            .join(dest.file_name().unwrap_or_default());
+        Ok((from, to))
+    }
+
+    /// Whether git tracks the file at the absolute path `path`.
+    async fn is_tracked(&self, path: &Path) -> bool {
+        let args = [OsStr::new("ls-files"), OsStr::new("--error-unmatch"), path.as_os_str()];
+        path.starts_with(&self.root) && self.git(&args, &[]).await.is_ok()
+    }
+
+    /// Runs git in the repository with `args`, feeding it `stdin`.
+    async fn git(&self, args: &[&OsStr], stdin: &[u8]) -> Result<(), RenameError> {
+        let command = std::iter::once(OsStr::new("git"))
+            .chain(args.iter().copied())
+            .map(OsStr::to_string_lossy)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let io_error = |e| RenameError::Io(self.root.clone(), self.root.clone(), e);
+        let mut child = tokio::process::Command::new("git")
+            .args(args)
+            .current_dir(&self.root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(io_error)?;
+        if let Some(mut pipe) = child.stdin.take() {
+            pipe.write_all(stdin).await.map_err(io_error)?;
+        }
+        let output = child.wait_with_output().await.map_err(io_error)?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(command_failed(command, &output))
+        }
+    }
+}
+
+/// Retries another backend's moves that fail in ways that might not happen next time, waiting
+/// twice as long before each retry as before the last.
+pub struct RetryingRenamer {
+    inner: Box<dyn Renamer>,
+    retries: u32,
+    backoff: Duration,
+    retry_on: Vec<RetryOn>,
+}
+
+impl RetryingRenamer {
+    pub fn new(
+        inner: Box<dyn Renamer>,
+        retries: u32,
+        backoff: Duration,
+        retry_on: Vec<RetryOn>,
+    ) -> Self {
+        Self { inner, retries, backoff, retry_on }
+    }
+
+    async fn retry<T, F, Fut>(&self, mut attempt: F) -> Result<T, RenameError>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = Result<T, RenameError>> + Send,
+    {
+        let (mut retry, mut delay) = (0, self.backoff);
+        loop {
+            let e = match attempt().await {
+                Err(e) if e.retry_kind().is_some_and(|kind| self.retry_on.contains(&kind)) => e,
+                result => return result,
+            };
+            if retry == self.retries {
+                return Err(RenameError::Exhausted { attempts: retry + 1, last: Box::new(e) });
+            }
+            retry += 1;
+            warn!("Retrying in {}s ({} of {}): {}", delay.as_secs(), retry, self.retries, e);
+            tokio::time::delay_for(delay).await;
+            delay = delay.saturating_mul(2);
+        }
+    }
+}
+
+#[async_trait]
+impl Renamer for RetryingRenamer {
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError> {
+        self.retry(|| self.inner.rename(source, dest)).await
+    }
+
+    fn transfer(&self) -> Transfer {
+        self.inner.transfer()
+    }
+
+    async fn exists(&self, dest: &Path) -> Result<bool, RenameError> {
+        self.retry(|| self.inner.exists(dest)).await
+    }
+
+    fn local(&self) -> bool {
+        self.inner.local()
+    }
+
+    fn reversible(&self) -> bool {
+        self.inner.reversible()
+    }
+
+    async fn annotate(&self, placement: &Placement) -> Result<(), RenameError> {
+        self.inner.annotate(placement).await
+    }
+
+    async fn finish(&self) -> Result<(), RenameError> {
+        self.inner.finish().await
+    }
+}
+
+/// Runs `program`, a tool a backend hands files to on their way to `dest`, failing with what it
+/// printed to stderr if it exits unsuccessfully.
+pub async fn run(program: &str, args: &[&OsStr], dest: &Path) -> Result<Output, RenameError> {
+    let command = std::iter::once(OsStr::new(program))
+        .chain(args.iter().copied())
+        .map(OsStr::to_string_lossy)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| RenameError::Io(program.into(), dest.to_path_buf(), e))?;
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(command_failed(command, &output))
+    }
+}
+
+pub fn command_failed(command: String, output: &std::process::Output) -> RenameError {
+    RenameError::CommandFailed {
+        command,
+        status: output.status,
+        stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+    }
+}
+
+#[async_trait]
+impl Renamer for GitRenamer {
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError> {
+        let (from, to) = self.resolve(source, dest).await?;
+        self.git(&[OsStr::new("mv"), from.as_os_str(), to.as_os_str()], &[]).await?;
+        self.moved.lock().unwrap().push(Moved { from, to, tracked: true });
+        Ok(())
+    }
+
+    async fn finish(&self) -> Result<(), RenameError> {
+        let moved = std::mem::take(&mut *self.moved.lock().unwrap());
+        if !self.commit || moved.is_empty() {
+            return Ok(());
+        }
+        let mut message = format!(
+            "Sort {} file{} into the library\n\n",
+            moved.len(),
+            if moved.len() == 1 { "" } else { "s" }
+        );
+        let mut pathspecs = Vec::new();
+        for Moved { from, to, tracked } in &moved {
+            let relative = |path: &Path| path.strip_prefix(&self.root).unwrap_or(path).to_owned();
+            message += &format!("{} -> {}\n", relative(from).display(), relative(to).display());
+            let paths = if *tracked { vec![from, to] } else { vec![to] };
+            for path in paths {
+                pathspecs.extend_from_slice(&pathspec(path));
+                pathspecs.push(0);
+            }
+        }
+        let args = ["commit", "--quiet", "--pathspec-from-file=-", "--pathspec-file-nul", "-m"];
+        let args: Vec<&OsStr> =
+            args.iter().map(OsStr::new).chain(std::iter::once(OsStr::new(&message))).collect();
+        self.git(&args, &pathspecs).await
+    }
+}
+
+/// `path` as git reads it in a pathspec file. On unix that's its bytes as they are, so names that
+/// aren't valid UTF-8 still match the files they name.
+#[cfg(unix)]
+fn pathspec(path: &Path) -> std::borrow::Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().into()
+}
+
+/// Git for Windows reads pathspecs as UTF-8.
+#[cfg(not(unix))]
+fn pathspec(path: &Path) -> std::borrow::Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        std::borrow::Cow::Borrowed(path) => path.as_bytes().into(),
+        std::borrow::Cow::Owned(path) => path.into_bytes().into(),
+    }
+}
+
+/// Moves files into a git-annex repository. Files already in the repository are moved with
+/// `git mv`; new ones are moved into place and annexed with `git annex add`, so their content
+/// goes into the annex rather than being committed to git directly.
+pub struct GitAnnexRenamer {
+    git: GitRenamer,
+}
+
+impl GitAnnexRenamer {
+    pub fn new(library: &Path, commit: bool) -> Result<Self, RenameError> {
+        Ok(GitAnnexRenamer { git: GitRenamer::new(library, commit)? })
+    }
+}
+
+#[async_trait]
+impl Renamer for GitAnnexRenamer {
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError> {
+        let (from, to) = self.git.resolve(source, dest).await?;
+        let tracked = self.git.is_tracked(&from).await;
+        if tracked {
+            self.git.git(&[OsStr::new("mv"), from.as_os_str(), to.as_os_str()], &[]).await?;
+        } else {
+            tokio::fs::rename(&from, &to)
+                .await
+                .map_err(|e| RenameError::from_io(e, source, dest))?;
+            let add = ["annex", "add", "--quiet"].iter().map(OsStr::new);
+            let args: Vec<&OsStr> = add.chain(std::iter::once(to.as_os_str())).collect();
+            if let Err(e) = self.git.git(&args, &[]).await {
+                // Put the file back rather than leave it in the library unannexed.
+                let _ = tokio::fs::rename(&to, &from).await;
+                return Err(e);
+            }
+        }
+        self.git.moved.lock().unwrap().push(Moved { from, to, tracked });
+        Ok(())
+    }
+
+    /// Sets `year`, `month`, `datetime` and `camera` annex metadata on the file, for
+    /// `git annex view` to sort the library by.
+    async fn annotate(&self, placement: &Placement) -> Result<(), RenameError> {
+        let dest = &placement.dest;
+        let to = tokio::fs::canonicalize(dest)
+            .await
+            .map_err(|e| RenameError::from_io(e, dest, dest))?;
+        let date = &placement.date;
+        let mut fields = vec![
+            format!("year={:04}", date.year()),
+            format!("month={:02}", date.month()),
+            format!("datetime={}", date),
+        ];
+        if let Some(camera) = camera_name(&placement.camera) {
+            fields.push(format!("camera={}", camera));
+        }
+        let mut args = vec![OsStr::new("annex"), OsStr::new("metadata"), OsStr::new("--quiet")];
+        for field in &fields {
+            args.extend([OsStr::new("--set"), OsStr::new(field)].iter());
+        }
+        args.push(to.as_os_str());
+        self.git.git(&args, &[]).await
+    }
+
+    async fn finish(&self) -> Result<(), RenameError> {
+        self.git.finish().await
+    }
+}
+
+/// What to call the camera that took a photo: its make and model, without the make twice when
+/// the model already starts with it (as in "Canon" and "Canon EOS R5").
+fn camera_name(camera: &Camera) -> Option<String> {
+    match (camera.make.as_deref(), camera.model.as_deref()) {
+        (Some(make), Some(model)) if model.to_lowercase().starts_with(&make.to_lowercase()) => {
+            Some(model.to_string())
+        }
+        (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+        (make, model) => make.or(model).map(str::to_string),
+    }
+}
+
+/// Copies instead of moving, leaving the original in place (e.g. on a read-only SD card). The
+/// copy keeps the original's access and modification times, permissions and extended
+/// attributes; if any of them can't be carried over, the copy is removed and the move fails.
+pub struct CopyRenamer {
+    /// Whether to clone files copy-on-write where the filesystem can.
+    reflink: bool,
+    /// Whether to checksum copies against their originals.
+    verify: bool,
+}
+
+impl CopyRenamer {
+    pub fn new(verify: bool) -> Self {
+        Self { reflink: false, verify }
+    }
+
+    /// Copies that are copy-on-write clones on filesystems that support them (btrfs, XFS and
+    /// APFS), so they're instant and take no space until one side changes. Elsewhere, and
+    /// across filesystems, files are copied as usual.
+    pub fn reflink(verify: bool) -> Self {
+        Self { reflink: true, verify }
+    }
+}
+
+#[async_trait]
+impl Renamer for CopyRenamer {
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError> {
+        let (reflink, verify) = (self.reflink, self.verify);
+        blocking(source, dest, move |from, to| copy_into_place(from, to, reflink, verify)).await
+    }
+
+    fn transfer(&self) -> Transfer {
+        Transfer::Copy
+    }
+
+    fn reversible(&self) -> bool {
+        true
+    }
+}
+
+/// Runs `f`, which does blocking file I/O, on `source` and `dest` off the async runtime.
+async fn blocking<F>(source: &Path, dest: &Path, f: F) -> Result<(), RenameError>
+where
+    F: FnOnce(&Path, &Path) -> Result<(), RenameError> + Send + 'static,
+{
+    let (from, to) = (source.to_path_buf(), dest.to_path_buf());
+    tokio::task::spawn_blocking(move || f(&from, &to))
+        .await
+        .map_err(|e| RenameError::from_io(std::io::Error::other(e), source, dest))?
+}
+
+/// Copies `from` to `to` by way of a hidden temporary file next to `to`, which is only renamed
+/// into place once it's complete (and with `verify`, reads back the same as `from` did before
+/// it was copied), so an interrupted copy never leaves a partial file that looks like a photo.
+fn copy_into_place(
+    from: &Path,
+    to: &Path,
+    reflink: bool,
+    verify: bool,
+) -> Result<(), RenameError> {
+    let io_error = |e| RenameError::from_io(e, from, to);
+    let mut temp_name = OsString::from(".");
+    temp_name.push(to.file_name().unwrap_or_default());
+    temp_name.push(".photosort-tmp");
+    let temp = to.with_file_name(temp_name);
+    // Left behind by a copy that was interrupted.
+    let _ = std::fs::remove_file(&temp);
+    let expected = if verify { Some(checksum(from).map_err(io_error)?) } else { None };
+    copy_file(from, &temp, reflink).map_err(io_error)?;
+    let placed = match expected.map(|expected| (expected, checksum(&temp))) {
+        Some((_, Err(e))) => Err(io_error(e)),
+        Some((expected, Ok(actual))) if actual != expected => {
+            Err(RenameError::ChecksumMismatch(from.to_path_buf(), to.to_path_buf()))
+        }
+        _ => rename_new(&temp, to).map_err(io_error),
+    };
+    if placed.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    placed?;
+    // So the rename is on disk too. Not every filesystem can sync a directory, and the file's
+    // in place either way, so this is only a best effort.
+    #[cfg(unix)]
+    if let Some(dir) = to.parent().and_then(|dir| std::fs::File::open(dir).ok()) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Renames `from` to `to`, failing with `AlreadyExists` rather than replacing a file that's
+/// already there. Where the filesystem can't do that in one step, it's checked first.
+fn rename_new(from: &Path, to: &Path) -> std::io::Result<()> {
+    if rename_exclusive(from, to)? {
+        return Ok(());
+    }
+    if std::fs::symlink_metadata(to).is_ok() {
+        return Err(ErrorKind::AlreadyExists.into());
+    }
+    std::fs::rename(from, to)
+}
+
+/// Renames `from` to `to` unless something's already at `to`, in one step. Returns false when
+/// the filesystem can't.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn rename_exclusive(from: &Path, to: &Path) -> std::io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let from = CString::new(from.as_os_str().as_bytes()).map_err(std::io::Error::other)?;
+    let to = CString::new(to.as_os_str().as_bytes()).map_err(std::io::Error::other)?;
+    // Safety: both paths are NUL-terminated.
+    #[cfg(target_os = "linux")]
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    // Safety: as above.
+    #[cfg(target_os = "macos")]
+    let result = unsafe { libc::renamex_np(from.as_ptr(), to.as_ptr(), libc::RENAME_EXCL) };
+    if result == 0 {
+        return Ok(true);
+    }
+    match std::io::Error::last_os_error() {
+        e if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS | libc::ENOTSUP)) => {
+            Ok(false)
+        }
+        e => Err(e),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn rename_exclusive(_from: &Path, _to: &Path) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// The BLAKE3 hash of the file at `path`. Being cryptographic, it tells files apart even when
+/// one's been crafted to collide with the other, as a plain CRC can be.
+pub fn checksum(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hasher.finalize().into()),
+            n => hasher.update(&buf[..n]),
+        };
+    }
+}
+
+/// Copies `from` to the new file `to` along with its times, permissions and extended
+/// attributes, removing `to` again if any part of that fails. With `reflink`, the copy is a
+/// clone of `from` where the filesystem allows.
+fn copy_file(from: &Path, to: &Path, reflink: bool) -> std::io::Result<()> {
+    if reflink && crate::reflink::clone_file(from, to)? {
+        return Ok(());
+    }
+    let mut input = std::fs::File::open(from)?;
+    let metadata = input.metadata()?;
+    let mut output = std::fs::File::options().write(true).create_new(true).open(to)?;
+    let mut copy = || {
+        if !(reflink && crate::reflink::clone_data(&input, &output)?) {
+            std::io::copy(&mut input, &mut output)?;
+        }
+        crate::xattr::copy(&input, &output)?;
+        let times = std::fs::FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?);
+        output.set_times(times)?;
+        // After the rest, since it can take away write permission.
+        output.set_permissions(metadata.permissions())?;
+        // So the copy is on disk before anything relies on it, like removing the original.
+        output.sync_all()
+    };
+    copy().inspect_err(|_| {
+        let _ = std::fs::remove_file(to);
+    })
+}
+
+/// Leaves files where they are and puts a relative symlink to each in the library, for building
+/// a dated view of files that can't be moved.
+pub struct SymlinkRenamer;
+
+#[async_trait]
+impl Renamer for SymlinkRenamer {
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError> {
+        let io_error = |e| RenameError::from_io(e, source, dest);
+        let from = tokio::fs::canonicalize(source).await.map_err(io_error)?;
+        let dest_dir = tokio::fs::canonicalize(dest.parent().unwrap_or(Path::new(".")))
+            .await
+            .map_err(io_error)?;
+        let target = relative_path(&dest_dir, &from);
+        #[cfg(unix)]
+        let linked = std::os::unix::fs::symlink(&target, dest);
+        #[cfg(windows)]
+        let linked = std::os::windows::fs::symlink_file(&target, dest);
+        linked.map_err(io_error)
+    }
+
+    fn transfer(&self) -> Transfer {
+        Transfer::Link
+    }
+
+    fn reversible(&self) -> bool {
+        true
+    }
+}
+
+/// The path to `target` from the directory `dir`, both absolute, like `../../2019/IMG_0001.CR2`.
+fn relative_path(dir: &Path, target: &Path) -> PathBuf {
+    let common = dir.components().zip(target.components()).take_while(|(a, b)| a == b).count();
+    let up = dir.components().count() - common;
+    std::iter::repeat_n(Component::ParentDir, up)
+        .chain(target.components().skip(common))
+        .collect()
+}
+
+pub fn get_renamer(
+    options: &SortOptions,
+    profile: &Profile,
+    library: &Path,
+) -> Result<Box<dyn Renamer>> {
+    let renamer = backend_renamer(options, profile, library)?;
+    if options.retries == 0 {
+        return Ok(renamer);
+    }
+    let retry_on = match &options.retry_on[..] {
+        [] => vec![RetryOn::Io, RetryOn::Command, RetryOn::Http],
+        kinds => kinds.to_vec(),
+    };
+    let backoff = Duration::from_secs(options.retry_backoff);
+    Ok(Box::new(RetryingRenamer::new(renamer, options.retries, backoff, retry_on)))
+}
+
+fn backend_renamer(
+    options: &SortOptions,
+    profile: &Profile,
+    library: &Path,
+) -> Result<Box<dyn Renamer>> {
+    let commit = options.git_commit || profile.git_commit.unwrap_or(false);
+    let verify = options.verify || profile.verify.unwrap_or(false);
+    let backend = options.backend.or(profile.backend).unwrap_or(Backend::File);
+    let overwrite = options.on_conflict.or(profile.on_conflict) == Some(ConflictPolicy::Overwrite);
+    if commit && !matches!(backend, Backend::Git | Backend::GitAnnex) {
+        bail!("--git-commit needs the git or git-annex backend");
+    }
+    let remote = options.remote.as_ref().or(profile.remote.as_ref());
+    match (backend, remote) {
+        (Backend::Rclone, None) => bail!("The rclone backend needs --remote"),
+        (Backend::Rclone, Some(_)) | (_, None) => {}
+        (_, Some(_)) => bail!("--remote is only used by the rclone backend"),
+    }
+    let exec_cmd = options.exec_cmd.as_ref().or(profile.exec_cmd.as_ref());
+    match (backend, exec_cmd) {
+        (Backend::Exec, None) => bail!("The exec backend needs --exec-cmd"),
+        (Backend::Exec, Some(_)) | (_, None) => {}
+        (_, Some(_)) => bail!("--exec-cmd is only used by the exec backend"),
+    }
+    // The aws and gcloud CLIs behind the object-store backend take no bandwidth limit on the
+    // command line. The AWS CLI reads one from its own config file, which isn't ours to edit, and
+    // the Google Cloud CLI has none at all.
+    if options.bwlimit.is_some() && backend == Backend::ObjectStore {
+        bail!(
+            "--bwlimit is not supported with the object-store backend. For s3:// URLs, set a \
+             limit with `aws configure set default.s3.max_bandwidth RATE` instead"
+        );
+    }
+    if options.bwlimit.is_some() && !matches!(backend, Backend::Webdav | Backend::Rclone) {
+        bail!("--bwlimit needs the webdav or rclone backend");
+    }
+    if verify && !matches!(backend, Backend::File | Backend::Reflink) {
+        bail!("--verify needs the file or reflink backend");
+    }
+    match (backend, options.copy) {
+        (Backend::File, false) => Ok(Box::new(FileRenamer::new(verify))),
+        (Backend::File, true) => Ok(Box::new(CopyRenamer::new(verify))),
+        (Backend::Git, false) => Ok(Box::new(
+            GitRenamer::new(library, commit)
+                .context("The git backend needs the library to be in a git repository")?,
+        )),
+        (Backend::GitAnnex, false) => Ok(Box::new(
+            GitAnnexRenamer::new(library, commit)
+                .context("The git-annex backend needs the library to be in a git repository")?,
+        )),
+        (Backend::Git, true) => bail!("--copy is not supported with the git backend"),
+        (Backend::GitAnnex, true) => bail!("--copy is not supported with the git-annex backend"),
+        (Backend::Symlink, false) => Ok(Box::new(SymlinkRenamer)),
+        (Backend::Symlink, true) => bail!("--copy is not supported with the symlink backend"),
+        // Clones are copies already.
+        (Backend::Reflink, _) => Ok(Box::new(CopyRenamer::reflink(verify))),
+        #[cfg(feature = "object-store")]
+        (Backend::ObjectStore, copy) => Ok(Box::new(
+            ObjectStoreRenamer::new(library, copy)
+                .context("The object-store backend needs --dest to be an s3:// or gs:// URL")?,
+        )),
+        #[cfg(not(feature = "object-store"))]
+        (Backend::ObjectStore, _) => bail!("photosort was built without the object-store feature"),
+        (Backend::Rclone, copy) => Ok(Box::new(RcloneRenamer::new(copy, overwrite, options.bwlimit))),
+        (Backend::Exec, copy) => Ok(Box::new(
+            ExecRenamer::new(exec_cmd.map_or("", String::as_str), copy)
+                .map_err(anyhow::Error::msg)
+                .context("Invalid --exec-cmd")?,
+        )),
+        (Backend::Webdav, copy) => Ok(Box::new(
+            WebdavRenamer::new(library, copy, overwrite, options.bwlimit)
+                .context("The webdav backend needs --dest to be an http:// or https:// URL")?,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    /// A photo in a new temporary directory, modified at a set time, and where to copy it.
+    fn photo() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("a.jpg");
+        std::fs::write(&source, b"photo data").unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let file = std::fs::File::options().write(true).open(&source).unwrap();
+        file.set_modified(modified).unwrap();
+        let dest = dir.path().join("b.jpg");
+        (dir, source, dest)
+    }
+
+    #[tokio::test]
+    async fn copy_keeps_the_original_and_its_times() {
+        let (_dir, source, dest) = photo();
+        CopyRenamer::new(true).rename(&source, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&source).unwrap(), b"photo data");
+        assert_eq!(std::fs::read(&dest).unwrap(), b"photo data");
+        let modified = |path: &Path| std::fs::metadata(path).unwrap().modified().unwrap();
+        assert_eq!(modified(&dest), modified(&source));
+        // No temporary file is left behind.
+        assert_eq!(std::fs::read_dir(source.parent().unwrap()).unwrap().count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn copy_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_dir, source, dest) = photo();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o440)).unwrap();
+        CopyRenamer::new(false).rename(&source, &dest).await.unwrap();
+        let mode = std::fs::metadata(&dest).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o440);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn copy_keeps_extended_attributes() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let (_dir, source, dest) = photo();
+        let path = |path: &Path| CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CString::new("user.photosort.test").unwrap();
+        let value = b"tagged";
+        // Safety: the path and name are NUL-terminated and the value is valid for its length.
+        let set = unsafe {
+            let value_ptr = value.as_ptr() as *const libc::c_void;
+            libc::setxattr(path(&source).as_ptr(), name.as_ptr(), value_ptr, value.len(), 0)
+        };
+        if set != 0 {
+            // The temporary directory's filesystem doesn't take user attributes.
+            return;
+        }
+        CopyRenamer::new(false).rename(&source, &dest).await.unwrap();
+        let mut buf = [0u8; 16];
+        // Safety: as above, with `buf` valid for its length.
+        let len = unsafe {
+            let buf_ptr = buf.as_mut_ptr() as *mut libc::c_void;
+            libc::getxattr(path(&dest).as_ptr(), name.as_ptr(), buf_ptr, buf.len())
+        };
+        assert_eq!(&buf[..len.max(0) as usize], value);
+    }
+
+    #[tokio::test]
+    async fn copy_refuses_an_existing_destination() {
+        let (_dir, source, dest) = photo();
+        std::fs::write(&dest, b"another photo").unwrap();
+        let result = CopyRenamer::new(false).rename(&source, &dest).await;
+        assert!(matches!(result, Err(RenameError::DestExists(_))), "{:?}", result);
+        assert_eq!(std::fs::read(&dest).unwrap(), b"another photo");
+        assert_eq!(std::fs::read(&source).unwrap(), b"photo data");
+        assert_eq!(std::fs::read_dir(source.parent().unwrap()).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn move_refuses_a_destination_that_appeared_since_planning() {
+        let (_dir, source, dest) = photo();
+        std::fs::write(&dest, b"another photo").unwrap();
+        let result = FileRenamer::new(false).rename(&source, &dest).await;
+        assert!(matches!(result, Err(RenameError::DestExists(_))), "{:?}", result);
+        assert_eq!(std::fs::read(&dest).unwrap(), b"another photo");
+        assert_eq!(std::fs::read(&source).unwrap(), b"photo data");
+    }
+
+    #[tokio::test]
+    async fn reflink_copies_where_it_cant_clone() {
+        let (_dir, source, dest) = photo();
+        CopyRenamer::reflink(true).rename(&source, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"photo data");
+        assert!(source.exists());
+    }
+
+    #[test]
+    fn checksums_are_blake3() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc");
+        std::fs::write(&path, "abc").unwrap();
+        let hex = blake3::Hash::from(checksum(&path).unwrap()).to_hex();
+        let abc = "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85";
+        assert_eq!(hex.as_str(), abc);
+    }
+
+    #[tokio::test]
+    async fn verified_copies_match() {
+        let (_dir, source, dest) = photo();
+        CopyRenamer::new(true).rename(&source, &dest).await.unwrap();
+        assert_eq!(checksum(&source).unwrap(), checksum(&dest).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn git_commits_names_that_arent_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&OsStr]| {
+            let output = std::process::Command::new("git")
+                .arg("-C")
+                .arg(dir.path())
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            output.stdout
+        };
+        let source = dir.path().join(OsStr::from_bytes(b"IMG_\xff.jpg"));
+        std::fs::write(&source, b"photo data").unwrap();
+        git(&[OsStr::new("init"), OsStr::new("--quiet")]);
+        for (key, value) in [("user.name", "Test"), ("user.email", "test@example.com")] {
+            git(&[OsStr::new("config"), OsStr::new(key), OsStr::new(value)]);
+        }
+        git(&[OsStr::new("add"), source.as_os_str()]);
+        git(&[OsStr::new("commit"), OsStr::new("--quiet"), OsStr::new("-m"), OsStr::new("Add")]);
+        std::fs::create_dir(dir.path().join("2020")).unwrap();
+        let dest = dir.path().join("2020").join(OsStr::from_bytes(b"IMG_\xff.jpg"));
+        let renamer = GitRenamer::new(dir.path(), true).unwrap();
+        renamer.rename(&source, &dest).await.unwrap();
+        renamer.finish().await.unwrap();
+        assert!(git(&[OsStr::new("status"), OsStr::new("--porcelain")]).is_empty());
+    }
+
+    /// Sort options parsed from `args`, the way they'd be given on the command line.
+    fn sort_options(args: &[&str]) -> SortOptions {
+        #[derive(clap::Parser)]
+        struct Command {
+            #[command(flatten)]
+            options: SortOptions,
+        }
+        let args = std::iter::once("sort").chain(args.iter().copied());
+        <Command as clap::Parser>::parse_from(args).options
+    }
+
+    #[test]
+    fn bwlimit_is_refused_where_it_cant_be_passed_on() {
+        let library = Path::new("s3://bucket/photos");
+        let options = sort_options(&["--backend", "object-store", "--bwlimit", "1M"]);
+        let err = get_renamer(&options, &Profile::default(), library).err().unwrap();
+        assert!(err.to_string().contains("s3.max_bandwidth"), "{}", err);
+        let options = sort_options(&["--bwlimit", "1M"]);
+        let err = get_renamer(&options, &Profile::default(), library).err().unwrap();
+        assert!(err.to_string().contains("webdav or rclone"), "{}", err);
+    }
+}
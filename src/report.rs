@@ -0,0 +1,355 @@
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::process::ExitCode;
+
+use clap::ColorChoice;
+use console::style;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use serde::Serialize;
+
+use crate::cli::{DateSource, OutputFormat};
+use crate::sort::Placement;
+
+/// Exit status when some files in a batch could not be sorted.
+pub const EXIT_PARTIAL: u8 = 3;
+/// Exit status when no file in a batch could be sorted.
+pub const EXIT_ALL_FAILED: u8 = 4;
+
+/// Turns colored output on or off for stdout and stderr. With `auto` each stream is colored
+/// when it's a terminal, unless `NO_COLOR` is set to anything but an empty string.
+pub fn init_color(choice: ColorChoice) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let (stdout, stderr) = match choice {
+        ColorChoice::Always => (true, true),
+        ColorChoice::Never => (false, false),
+        ColorChoice::Auto => (
+            !no_color && std::io::stdout().is_terminal(),
+            !no_color && std::io::stderr().is_terminal(),
+        ),
+    };
+    console::set_colors_enabled(stdout);
+    console::set_colors_enabled_stderr(stderr);
+}
+
+/// Running totals for a batch.
+#[derive(Debug, Default, Clone)]
+pub struct Summary {
+    pub processed: usize,
+    pub moved: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// `failed` broken down by what went wrong.
+    pub failures: BTreeMap<FailureKind, usize>,
+    /// `moved` broken down by which date source each file was dated by.
+    pub sources: BTreeMap<&'static str, usize>,
+    pub bytes: u64,
+}
+
+impl Summary {
+    /// Success when nothing failed, otherwise tells partial failures apart from a batch where
+    /// every file failed.
+    pub fn exit_code(&self) -> ExitCode {
+        match self.failed {
+            0 => ExitCode::SUCCESS,
+            n if n == self.processed => ExitCode::from(EXIT_ALL_FAILED),
+            _ => ExitCode::from(EXIT_PARTIAL),
+        }
+    }
+}
+
+/// Broad reason a file couldn't be sorted, for the error summary.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    /// An input couldn't be walked or a glob pattern didn't match.
+    Walk,
+    /// The file couldn't be opened or read.
+    Unreadable,
+    /// The file isn't in a format photosort can read dates from.
+    Unsupported,
+    /// The file was read but no capture date could be found in it.
+    NoDate,
+    /// The destination was taken and `--on-conflict error` is in effect.
+    Conflict,
+    /// Moving or copying the file into the library failed.
+    Transfer,
+    /// The file changed between `photosort plan` and `photosort apply`.
+    Changed,
+    /// Moving the file kept failing, however many times `--retries` allowed.
+    Exhausted,
+}
+
+impl FailureKind {
+    fn label(self) -> &'static str {
+        match self {
+            FailureKind::Walk => "couldn't walk input",
+            FailureKind::Unreadable => "unreadable file",
+            FailureKind::Unsupported => "unsupported file",
+            FailureKind::NoDate => "no capture date",
+            FailureKind::Conflict => "destination exists",
+            FailureKind::Transfer => "move failed",
+            FailureKind::Changed => "changed since planned",
+            FailureKind::Exhausted => "gave up after retries",
+        }
+    }
+}
+
+/// How files get into the library, for wording what's reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    Move,
+    /// `--copy`, which leaves the original where it is.
+    Copy,
+    /// The symlink backend, which also leaves the original where it is.
+    Link,
+}
+
+/// Reply to an `--interactive` prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    Yes,
+    No,
+    /// Yes to this file and every one after it.
+    All,
+    /// Stop the batch here.
+    Quit,
+}
+
+/// One line of `--output json`.
+#[derive(Serialize)]
+struct Record<'a> {
+    source: Option<String>,
+    destination: Option<String>,
+    date: Option<String>,
+    extractor: Option<&'a str>,
+    camera_make: Option<&'a str>,
+    camera_model: Option<&'a str>,
+    lens: Option<&'a str>,
+    focal_length: Option<f64>,
+    iso: Option<u32>,
+    action: &'a str,
+    reason: Option<&'a str>,
+    category: Option<FailureKind>,
+    error: Option<String>,
+}
+
+/// Reports per-file results as a batch runs and a summary once it's done. In human mode a
+/// progress bar is drawn when stderr is a terminal; in JSON mode stdout only gets records.
+pub struct Reporter {
+    bar: ProgressBar,
+    summary: Summary,
+    output: OutputFormat,
+    dry_run: bool,
+    transfer: Transfer,
+}
+
+/// Counts files into a [`Reporter`]'s total, from [`Reporter::found`].
+#[derive(Clone)]
+pub struct Found(ProgressBar);
+
+impl Found {
+    pub fn add(&self, files: usize) {
+        self.0.inc_length(files as u64);
+    }
+}
+
+impl Reporter {
+    pub fn new(total: usize, output: OutputFormat, dry_run: bool, transfer: Transfer) -> Reporter {
+        let bar = match output {
+            OutputFormat::Human => ProgressBar::new(total as u64),
+            OutputFormat::Json => ProgressBar::hidden(),
+        };
+        bar.set_style(
+            ProgressStyle::with_template("{bar:40} {pos}/{len} {msg}")
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        Reporter { bar, summary: Summary::default(), output, dry_run, transfer }
+    }
+
+    /// A handle for counting files into the progress bar's total as they're found, for batches
+    /// that are sorted while they're still being listed.
+    pub fn found(&self) -> Found {
+        Found(self.bar.clone())
+    }
+
+    pub fn sorted(&mut self, source: &Path, placement: &Placement) {
+        let fallback = placement.extractor == DateSource::Mtime.name();
+        match self.output {
+            OutputFormat::Human => self.bar.suspend(|| {
+                let note = if fallback { " (dated by modification time)" } else { "" };
+                println!(
+                    "{} {} -> {}{}",
+                    style(self.verb()).green(),
+                    source.display(),
+                    placement.dest.display(),
+                    style(note).yellow()
+                )
+            }),
+            OutputFormat::Json => emit(&Record {
+                source: Some(source.to_string_lossy().into_owned()),
+                destination: Some(placement.dest.to_string_lossy().into_owned()),
+                date: Some(placement.date.to_string()),
+                extractor: Some(placement.extractor),
+                camera_make: placement.camera.make.as_deref(),
+                camera_model: placement.camera.model.as_deref(),
+                lens: placement.camera.lens.as_deref(),
+                focal_length: placement.camera.focal_length,
+                iso: placement.camera.iso,
+                action: match (self.dry_run, self.transfer) {
+                    (true, _) => "planned",
+                    (false, Transfer::Move) => "moved",
+                    (false, Transfer::Copy) => "copied",
+                    (false, Transfer::Link) => "linked",
+                },
+                reason: None,
+                category: None,
+                error: None,
+            }),
+        }
+        self.summary.moved += 1;
+        *self.summary.sources.entry(placement.extractor).or_default() += 1;
+        self.summary.bytes += placement.bytes;
+        self.advance();
+    }
+
+    /// Records a file that was deliberately left where it is.
+    pub fn skipped(&mut self, source: &Path, placement: &Placement, reason: &str) {
+        match self.output {
+            OutputFormat::Human => self.bar.suspend(|| {
+                eprintln!("{} {} ({})", style("Skipped").yellow().for_stderr(), source.display(), reason)
+            }),
+            OutputFormat::Json => emit(&Record {
+                source: Some(source.to_string_lossy().into_owned()),
+                destination: Some(placement.dest.to_string_lossy().into_owned()),
+                date: Some(placement.date.to_string()),
+                extractor: Some(placement.extractor),
+                camera_make: placement.camera.make.as_deref(),
+                camera_model: placement.camera.model.as_deref(),
+                lens: placement.camera.lens.as_deref(),
+                focal_length: placement.camera.focal_length,
+                iso: placement.camera.iso,
+                action: "skipped",
+                reason: Some(reason),
+                category: None,
+                error: None,
+            }),
+        }
+        self.summary.skipped += 1;
+        self.advance();
+    }
+
+    /// Asks on stderr whether to move `source` to `dest`, reading the answer from stdin. End of
+    /// input counts as quitting.
+    pub fn confirm(&self, source: &Path, dest: &Path) -> Answer {
+        self.bar.suspend(|| loop {
+            let verb = match self.transfer {
+                Transfer::Move => "Move",
+                Transfer::Copy => "Copy",
+                Transfer::Link => "Link",
+            };
+            eprint!("{} {} -> {}? [y/n/a/q] ", verb, source.display(), dest.display());
+            let mut line = String::new();
+            match std::io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => return Answer::Quit,
+                Ok(_) => {}
+            }
+            match line.trim().to_ascii_lowercase().as_str() {
+                "y" | "yes" => return Answer::Yes,
+                "n" | "no" => return Answer::No,
+                "a" | "all" => return Answer::All,
+                "q" | "quit" => return Answer::Quit,
+                _ => eprintln!("Please answer y(es), n(o), a(ll) or q(uit)"),
+            }
+        })
+    }
+
+    pub fn failed(&mut self, source: Option<&Path>, kind: FailureKind, error: &anyhow::Error) {
+        match self.output {
+            OutputFormat::Human => self.bar.suspend(|| match source {
+                Some(source) => eprintln!(
+                    "{} {}: {:#}",
+                    style("Failed to sort").red().for_stderr(),
+                    source.display(),
+                    error
+                ),
+                None => eprintln!("{} {:#}", style("Error:").red().for_stderr(), error),
+            }),
+            OutputFormat::Json => emit(&Record {
+                source: source.map(|s| s.to_string_lossy().into_owned()),
+                destination: None,
+                date: None,
+                extractor: None,
+                camera_make: None,
+                camera_model: None,
+                lens: None,
+                focal_length: None,
+                iso: None,
+                action: "failed",
+                reason: None,
+                category: Some(kind),
+                error: Some(format!("{:#}", error)),
+            }),
+        }
+        self.summary.failed += 1;
+        *self.summary.failures.entry(kind).or_default() += 1;
+        self.advance();
+    }
+
+    fn advance(&mut self) {
+        self.summary.processed += 1;
+        self.bar.set_message(format!(
+            "{} {}, {} skipped, {} errors, {}",
+            self.verb().to_lowercase(),
+            self.summary.moved,
+            self.summary.skipped,
+            self.summary.failed,
+            HumanBytes(self.summary.bytes)
+        ));
+        self.bar.inc(1);
+    }
+
+    fn verb(&self) -> &'static str {
+        match (self.dry_run, self.transfer) {
+            (true, Transfer::Move) => "Would move",
+            (true, Transfer::Copy) => "Would copy",
+            (true, Transfer::Link) => "Would link",
+            (false, Transfer::Move) => "Moved",
+            (false, Transfer::Copy) => "Copied",
+            (false, Transfer::Link) => "Linked",
+        }
+    }
+
+    /// Clears the progress bar and, in human mode, prints the summary table to stderr.
+    pub fn finish(self) -> Summary {
+        self.bar.finish_and_clear();
+        if self.output == OutputFormat::Human {
+            let s = &self.summary;
+            eprintln!("{:<12} {:>8}", "Processed:", s.processed);
+            eprintln!("{:<12} {:>8}", format!("{}:", self.verb()), s.moved);
+            for (source, count) in &s.sources {
+                eprintln!("{:>21}  dated by {}", count, source);
+            }
+            eprintln!("{:<12} {:>8}", "Skipped:", s.skipped);
+            let failed = match s.failed {
+                0 => style(s.failed).for_stderr(),
+                _ => style(s.failed).red().for_stderr(),
+            };
+            eprintln!("{:<12} {:>8}", "Errors:", failed);
+            for (kind, count) in &s.failures {
+                eprintln!("{:>21}  {}", count, kind.label());
+            }
+            eprintln!(
+                "{:<12} {:>8}",
+                if self.dry_run { "To transfer:" } else { "Transferred:" },
+                HumanBytes(s.bytes).to_string()
+            );
+        }
+        self.summary
+    }
+}
+
+fn emit(record: &Record) {
+    println!("{}", serde_json::to_string(record).expect("records always serialize"));
+}
@@ -0,0 +1,251 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{Date, Tags};
+
+/// XMP properties that can hold the capture time, most specific first.
+const XMP_DATE_PROPERTIES: &[&str] = &["exif:DateTimeOriginal", "photoshop:DateCreated"];
+
+/// XMP properties holding a file's star rating and its keywords, the latter as an `rdf:Bag`.
+const XMP_RATING: &str = "xmp:Rating";
+const XMP_KEYWORDS: &str = "dc:subject";
+
+/// Google Takeout cuts the names of its metadata files down to this many characters, plus
+/// `.json`, when the photo's own name is long.
+const TAKEOUT_MAX_STEM: usize = 46;
+
+/// Extensions of the sidecars moved along with a photo, unless `--sidecars` says otherwise:
+/// XMP edits, the adjustments iOS keeps in `.AAE` files, and GPS tracks.
+pub const DEFAULT_COMPANIONS: &[&str] = &["xmp", "aae", "gpx"];
+
+/// The part of a Google Takeout metadata file photosort cares about.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TakeoutMetadata {
+    photo_taken_time: Option<TakeoutTime>,
+}
+
+#[derive(Deserialize)]
+struct TakeoutTime {
+    /// Unix timestamp, written as a string.
+    timestamp: String,
+}
+
+/// Where an XMP sidecar for `path` could be: `IMG_1234.CR2.xmp`, as darktable writes it, or
+/// `IMG_1234.xmp`, as Lightroom does.
+fn xmp_paths(path: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for ext in ["xmp", "XMP"] {
+        let mut appended = path.as_os_str().to_os_string();
+        appended.push(".");
+        appended.push(ext);
+        paths.push(PathBuf::from(appended));
+        if path.extension().is_some() {
+            paths.push(path.with_extension(ext));
+        }
+    }
+    paths
+}
+
+/// Value of an XMP property, written either as an attribute (`exif:DateTimeOriginal="..."`) or
+/// as an element of its own (`<exif:DateTimeOriginal>...</exif:DateTimeOriginal>`).
+fn xmp_property<'a>(xmp: &'a str, name: &str) -> Option<&'a str> {
+    if let Some(start) = xmp.find(&format!("{}=", name)) {
+        let rest = &xmp[start + name.len() + 1..];
+        let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        return rest[1..].split(quote).next();
+    }
+    let open = format!("<{}>", name);
+    let start = xmp.find(&open)? + open.len();
+    let len = xmp[start..].find('<')?;
+    Some(&xmp[start..start + len])
+}
+
+/// Items of an XMP property holding a list (`<dc:subject><rdf:Bag><rdf:li>...`).
+fn xmp_list(xmp: &str, name: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    let list = match xmp.find(&open) {
+        Some(start) => &xmp[start + open.len()..],
+        None => return Vec::new(),
+    };
+    let list = &list[..list.find(&close).unwrap_or(list.len())];
+    list.split("<rdf:li")
+        .skip(1)
+        .filter_map(|item| {
+            let item = &item[item.find('>')? + 1..];
+            Some(unescape(item[..item.find('<')?].trim()))
+        })
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Undoes the escaping of XML's predefined entities.
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Rating and keywords from an XMP sidecar next to `path`, if there is one.
+pub async fn xmp_tags(path: &Path) -> Option<Tags> {
+    for sidecar in xmp_paths(path) {
+        let xmp = match tokio::fs::read(&sidecar).await {
+            Ok(xmp) => xmp,
+            Err(_) => continue,
+        };
+        let xmp = String::from_utf8_lossy(&xmp);
+        let rating = xmp_property(&xmp, XMP_RATING).and_then(|r| r.trim().parse().ok());
+        return Some(Tags { rating, keywords: xmp_list(&xmp, XMP_KEYWORDS) });
+    }
+    None
+}
+
+/// Capture date from an XMP sidecar next to `path`, if there is one that records it.
+pub async fn xmp_date(path: &Path) -> Option<Date> {
+    for sidecar in xmp_paths(path) {
+        let xmp = match tokio::fs::read(&sidecar).await {
+            Ok(xmp) => xmp,
+            Err(_) => continue,
+        };
+        let xmp = String::from_utf8_lossy(&xmp);
+        let date = XMP_DATE_PROPERTIES
+            .iter()
+            .find_map(|name| xmp_property(&xmp, name).and_then(Date::from_text));
+        if date.is_some() {
+            debug!(sidecar = %sidecar.display(), "Read date from XMP sidecar");
+            return date;
+        }
+    }
+    None
+}
+
+/// A sidecar found next to a photo, to be sorted along with it.
+#[derive(Debug)]
+pub struct Companion {
+    pub path: PathBuf,
+    naming: Naming,
+}
+
+/// How a sidecar is named after its photo.
+#[derive(Debug, Clone, Copy)]
+enum Naming {
+    /// With its extension added to the photo's name, as in `IMG_1234.CR2.xmp`.
+    Appended,
+    /// With its extension in place of the photo's, as in `IMG_1234.xmp`.
+    Replaced,
+    /// With `._` in front of the photo's name, as macOS names AppleDouble files.
+    AppleDouble,
+}
+
+impl Companion {
+    /// Where the sidecar goes when the photo goes to `dest`: beside it, named after it the same
+    /// way it was named after the original.
+    pub fn dest(&self, dest: &Path) -> PathBuf {
+        let ext = self.path.extension().unwrap_or_default();
+        match self.naming {
+            Naming::Appended => {
+                let mut appended = dest.as_os_str().to_os_string();
+                appended.push(".");
+                appended.push(ext);
+                PathBuf::from(appended)
+            }
+            Naming::Replaced => dest.with_extension(ext),
+            Naming::AppleDouble => {
+                let mut name = OsString::from("._");
+                name.push(dest.file_name().unwrap_or_default());
+                dest.with_file_name(name)
+            }
+        }
+    }
+}
+
+/// Files next to `path` named after it with one of `extensions`, in lower or upper case, like
+/// `IMG_1234.xmp`, `IMG_1234.AAE` or `IMG_1234.CR2.xmp`.
+pub async fn companions(path: &Path, extensions: &[String]) -> Vec<Companion> {
+    let mut cases: Vec<String> = Vec::new();
+    for ext in extensions {
+        let ext = ext.trim_start_matches('.');
+        for ext in [ext.to_string(), ext.to_lowercase(), ext.to_uppercase()] {
+            if !cases.contains(&ext) {
+                cases.push(ext);
+            }
+        }
+    }
+    let mut companions = Vec::new();
+    for ext in cases {
+        let mut appended = path.as_os_str().to_os_string();
+        appended.push(".");
+        appended.push(&ext);
+        let mut candidates =
+            vec![Companion { path: PathBuf::from(appended), naming: Naming::Appended }];
+        if path.extension().is_some() {
+            let path = path.with_extension(&ext);
+            candidates.push(Companion { path, naming: Naming::Replaced });
+        }
+        for candidate in candidates {
+            // The photo itself, when it has one of the extensions.
+            if candidate.path == path {
+                continue;
+            }
+            let metadata = tokio::fs::symlink_metadata(&candidate.path).await;
+            if metadata.is_ok_and(|m| m.is_file()) {
+                companions.push(candidate);
+            }
+        }
+    }
+    companions
+}
+
+/// The AppleDouble file macOS leaves next to `path` on filesystems without resource forks, like
+/// the FAT on a memory card, if there is one: `._IMG_1234.JPG` for `IMG_1234.JPG`.
+pub async fn apple_double(path: &Path) -> Option<Companion> {
+    let mut name = OsString::from("._");
+    name.push(path.file_name()?);
+    let companion = Companion { path: path.with_file_name(name), naming: Naming::AppleDouble };
+    let metadata = tokio::fs::symlink_metadata(&companion.path).await;
+    metadata.is_ok_and(|m| m.is_file()).then_some(companion)
+}
+
+/// Where Google Takeout could have put the metadata for `path`:
+/// `photo.jpg.supplemental-metadata.json`, possibly cut short, or `photo.jpg.json` in older
+/// exports.
+fn takeout_paths(path: &Path) -> Vec<PathBuf> {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return Vec::new(),
+    };
+    let stem = format!("{}.supplemental-metadata", name);
+    let short: String = stem.chars().take(TAKEOUT_MAX_STEM).collect();
+    let mut names = vec![format!("{}.json", stem), format!("{}.json", name)];
+    if short != stem {
+        names.insert(1, format!("{}.json", short));
+    }
+    names.into_iter().map(|name| path.with_file_name(name)).collect()
+}
+
+/// Capture date, in UTC, from the metadata file Google Takeout exports next to `path`, if there
+/// is one.
+pub async fn takeout_date(path: &Path) -> Option<Date> {
+    for sidecar in takeout_paths(path) {
+        let json = match tokio::fs::read(&sidecar).await {
+            Ok(json) => json,
+            Err(_) => continue,
+        };
+        let date = serde_json::from_slice::<TakeoutMetadata>(&json)
+            .ok()
+            .and_then(|metadata| metadata.photo_taken_time)
+            .and_then(|time| time.timestamp.parse::<i64>().ok())
+            .filter(|&timestamp| timestamp > 0)
+            .and_then(Date::from_timestamp);
+        if let Some(date) = date {
+            debug!(sidecar = %sidecar.display(), "Read date from Google Takeout metadata");
+            return Some(date);
+        }
+    }
+    None
+}
@@ -0,0 +1,1734 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use chrono_tz::Tz;
+use clap::ValueEnum;
+use futures::channel::mpsc::Receiver;
+use futures::future::{self, Future};
+use futures::stream::{self, Stream, StreamExt};
+use indicatif::HumanBytes;
+use thiserror::Error;
+use tokio::task::JoinError;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::archive::{self, ArchiveError, Listing};
+use crate::cli::{
+    AppleDoublePolicy, Backend, ConflictPolicy, DateOptions, DateSource, Fallback, LayoutOptions,
+    MonthLocale, MonthStyle, OutputFormat, Pairing, SortOptions,
+};
+use crate::config::Profile;
+use crate::exif::ExifError;
+use crate::exiftool;
+use crate::extract::{get_extractor, DateExtractor, Probe};
+use crate::filename::FilenamePatterns;
+use crate::journal::{Journal, Run};
+use crate::lock::LibraryLock;
+use crate::pairs::{self, Partner, Unit};
+use crate::perms::Permissions;
+use crate::plan::{Manifest, PlannedFile};
+use crate::renamer::{checksum, get_renamer, FileRenamer, RenameError, Renamer};
+use crate::report::{Answer, FailureKind, Found, Reporter, Summary, Transfer};
+use crate::sidecar::{self, Companion, DEFAULT_COMPANIONS};
+use crate::space;
+use crate::template::{
+    month_name, Template, TemplateError, Vars, DEFAULT_NAME_TEMPLATE, DEFAULT_TEMPLATE,
+};
+use crate::trash;
+use crate::walk::{WalkError, Walker};
+use crate::watermark::Watermarks;
+use crate::{Camera, Date, FileParseError, Metadata, Tags};
+
+pub fn library_root(dest: Option<&Path>) -> Result<PathBuf> {
+    if let Some(dest) = dest {
+        return Ok(dest.to_path_buf());
+    }
+    let home = dirs::home_dir().context("Could not find the home directory")?;
+    Ok(home.join("annex").join("photos"))
+}
+
+/// Decides where in the library files belong.
+pub struct Layout {
+    library: PathBuf,
+    template: Template,
+    name_template: Template,
+    month_style: MonthStyle,
+    month_locale: MonthLocale,
+    normalize_ext: bool,
+}
+
+impl Layout {
+    pub fn new(library: PathBuf, options: &LayoutOptions, profile: &Profile) -> Result<Layout> {
+        // Flags beat the profile, and within each an explicit template beats a preset.
+        let template = if let Some(t) = &options.template {
+            t.clone()
+        } else if let Some(preset) = options.layout {
+            preset.template().parse().unwrap()
+        } else if let Some(t) = &profile.template {
+            t.parse().context("Invalid template in config profile")?
+        } else {
+            profile.layout.map_or(DEFAULT_TEMPLATE, |p| p.template()).parse().unwrap()
+        };
+        let name_template = match (&options.name_template, &profile.name_template) {
+            (Some(t), _) => t.clone(),
+            (None, Some(t)) => {
+                Template::parse_name(t).context("Invalid name_template in config profile")?
+            }
+            (None, None) => Template::parse_name(DEFAULT_NAME_TEMPLATE).unwrap(),
+        };
+        let normalize_ext = options.normalize_ext || profile.normalize_ext.unwrap_or(false);
+        Ok(Layout {
+            library,
+            template,
+            name_template,
+            month_style: options.month_style.or(profile.month_style).unwrap_or(MonthStyle::Number),
+            month_locale: options.month_locale.or(profile.month_locale).unwrap_or(MonthLocale::En),
+            normalize_ext,
+        })
+    }
+
+    pub fn destination_for(
+        &self,
+        filename: &Path,
+        date: &Date,
+        camera: &Camera,
+    ) -> Result<PathBuf, TemplateError> {
+        let original = filename.file_name().unwrap_or(filename.as_os_str());
+        let month_name = month_name(date.month(), self.month_locale);
+        let number = format!("{:02}", date.month());
+        // --month-style is about directory names, so file names always get the plain number.
+        let mut name = self.name_template.render_name(&Vars {
+            date,
+            camera,
+            month: &number,
+            month_name,
+            filename: OsStr::new(""),
+            original,
+        })?;
+        if self.normalize_ext {
+            name = normalize_extension(&name);
+        }
+        let month = match self.month_style {
+            MonthStyle::Number => number,
+            MonthStyle::NumberName => format!("{}-{}", number, month_name),
+            MonthStyle::Name => month_name.to_string(),
+        };
+        let new_path = self.template.render_path(&Vars {
+            date,
+            camera,
+            month: &month,
+            month_name,
+            filename: &name,
+            original,
+        })?;
+        Ok(self.library.join(new_path))
+    }
+}
+
+/// Lowercases a file name's extension and settles on one spelling for formats that have several,
+/// so `IMG_0001.JPEG` and `IMG_0002.jpg` end up looking alike.
+fn normalize_extension(name: &OsStr) -> OsString {
+    let path = Path::new(name);
+    let ext = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.to_ascii_lowercase(),
+        None => return name.to_os_string(),
+    };
+    let ext = match ext.as_str() {
+        "jpeg" | "jpe" => "jpg",
+        "tif" => "tiff",
+        other => other,
+    };
+    path.with_extension(ext).into_os_string()
+}
+
+/// When a file was taken, and with what.
+pub struct Capture {
+    pub date: Date,
+    /// Which date source the date came from.
+    pub extractor: &'static str,
+    pub camera: Camera,
+    /// Rating and keywords from the file's own metadata.
+    pub tags: Tags,
+}
+
+/// One date source's answer for a file, from [`DateReader::candidates`].
+pub struct Candidate {
+    pub source: &'static str,
+    /// The date it found, if any, as the file records it.
+    pub date: Result<Option<Date>>,
+    /// Whether the source is one dates are read from.
+    pub used: bool,
+    /// Whether the date is passed over as implausible.
+    pub suspect: bool,
+}
+
+/// Works out when files were taken, from their metadata or whatever else there is to go on.
+pub struct DateReader {
+    /// Where to look for a date, in order.
+    extractors: Vec<Box<dyn DateExtractor>>,
+    /// What the filename date source matches against.
+    filename_patterns: FilenamePatterns,
+    /// Dates before this are taken to come from a camera whose clock was never set.
+    suspect_before: Option<Date>,
+    /// Zone to convert dates to, and the one to assume for dates with no UTC offset.
+    tz: Option<Tz>,
+    assume_tz: Option<Tz>,
+    /// Whether to convert dates to the zone at the file's GPS position instead, where it has one.
+    tz_from_gps: bool,
+}
+
+impl DateReader {
+    pub fn new(options: &DateOptions, profile: &Profile) -> Result<DateReader> {
+        let patterns = profile.filename_patterns.as_deref().unwrap_or_default();
+        let filename_patterns = FilenamePatterns::new(patterns)
+            .context("Invalid filename_patterns in config profile")?;
+        let mut sources = match (&options.date_sources, &profile.date_sources) {
+            (flag, _) if !flag.is_empty() => flag.clone(),
+            (_, Some(sources)) => sources.clone(),
+            _ => DateSource::DEFAULT.to_vec(),
+        };
+        let use_exiftool = options.use_exiftool || profile.use_exiftool.unwrap_or(false);
+        if use_exiftool && !sources.contains(&DateSource::Exiftool) {
+            // Right after the sources the built-in parsers provide.
+            let builtin = |s: &DateSource| matches!(s, DateSource::Exif | DateSource::Gps);
+            let after = sources.iter().rposition(builtin);
+            sources.insert(after.map_or(sources.len(), |i| i + 1), DateSource::Exiftool);
+        }
+        if sources.contains(&DateSource::Exiftool) {
+            exiftool::check()?;
+        }
+        let fallback = options.fallback.or(profile.fallback) == Some(Fallback::Mtime);
+        if fallback && !sources.contains(&DateSource::Mtime) {
+            sources.push(DateSource::Mtime);
+        }
+        let suspect_before = match (&options.suspect_before, &profile.suspect_before) {
+            (Some(date), _) => Some(date.clone()),
+            (None, Some(date)) => Some(
+                Date::parse_arg(date, "00:00:00")
+                    .map_err(anyhow::Error::msg)
+                    .context("Invalid suspect_before in config profile")?,
+            ),
+            (None, None) => None,
+        };
+        let tz = options.tz.or(profile.tz);
+        let assume_tz = options.assume_tz.or(profile.assume_tz);
+        let tz_from_gps = options.tz_from_gps || profile.tz_from_gps.unwrap_or(false);
+        if assume_tz.is_some() && tz.is_none() && !tz_from_gps {
+            bail!("--assume-tz needs --tz or --tz-from-gps to say which zone to convert to");
+        }
+        let extractors =
+            sources.into_iter().map(|s| get_extractor(s, &filename_patterns)).collect();
+        Ok(DateReader { extractors, filename_patterns, suspect_before, tz, assume_tz, tz_from_gps })
+    }
+
+    /// Reads when a file was taken, along with the name of the source that said so and the
+    /// camera its metadata names. Sources are tried in order and the first with a date wins.
+    ///
+    /// By default an XMP sidecar wins over the file's own metadata, since that's where editors
+    /// like Lightroom save corrected dates, and the metadata's GPS time is only used without a
+    /// DateTimeOriginal. Google Takeout metadata and then the file name are only used when the
+    /// file has no date of its own: the former is in UTC rather than the local time the camera
+    /// recorded, and the latter is just a guess.
+    ///
+    /// Dates that can't be right, like `0000:00:00`, or that are before `--suspect-before`, are
+    /// passed over as if the source had none, and so are sources that fail, like exiftool when
+    /// it can't be run. The date that's found is then converted to the zone given by `--tz` or
+    /// `--tz-from-gps`.
+    pub async fn read(&self, filename: &Path) -> Result<Capture> {
+        let mut probe = Probe::new(filename);
+        let mut rejected = Vec::new();
+        let mut failed = Vec::new();
+        for extractor in &self.extractors {
+            let date = match extractor.extract(&mut probe).await {
+                Ok(date) => date,
+                Err(e) => {
+                    warn!(source = extractor.name(), "Failed to read date: {:#}", e);
+                    failed.push(format!("{}: {:#}", extractor.name(), e));
+                    continue;
+                }
+            };
+            match date {
+                Some(date) if self.is_suspect(&date) => {
+                    debug!(%date, source = extractor.name(), "Passing over implausible date");
+                    rejected.push(format!("{} from {}", date, extractor.name()));
+                }
+                Some(date) => {
+                    // Dates from elsewhere still want the camera and position from the metadata.
+                    let metadata = probe.metadata().await;
+                    return Ok(Capture {
+                        date: self.localize(date, metadata),
+                        extractor: extractor.name(),
+                        camera: metadata.map(|m| m.camera.clone()).unwrap_or_default(),
+                        tags: metadata.map(|m| m.tags.clone()).unwrap_or_default(),
+                    });
+                }
+                None => (),
+            }
+        }
+        // The metadata's error says more about why there's no date than anything else would,
+        // unless there were dates and they were all wrong.
+        let e = match probe.into_metadata() {
+            _ if !rejected.is_empty() => FileParseError::DateParseError(format!(
+                "only found implausible dates ({})",
+                rejected.join(", ")
+            )),
+            Some(Err(e)) => e,
+            Some(Ok(_)) => ExifError::NoDate.into(),
+            None if !failed.is_empty() => FileParseError::DateParseError(format!(
+                "no date source could be read ({})",
+                failed.join("; ")
+            )),
+            None => FileParseError::DateParseError(format!(
+                "none of the date sources ({}) had a date",
+                self.extractors.iter().map(|e| e.name()).collect::<Vec<_>>().join(",")
+            )),
+        };
+        Err(e).context("Error in reading date out of input file")
+    }
+
+    /// What every date source, including ones dates aren't read from, has to say about a file,
+    /// along with the result of reading its metadata. This is for seeing why a file was dated the
+    /// way it was; it doesn't stop at the first date and asks exiftool even when it isn't in use.
+    pub async fn candidates(
+        &self,
+        filename: &Path,
+    ) -> (Vec<Candidate>, Option<Result<Metadata, FileParseError>>) {
+        let mut probe = Probe::new(filename);
+        let mut candidates = Vec::new();
+        for &source in DateSource::value_variants() {
+            let extractor = get_extractor(source, &self.filename_patterns);
+            let date = extractor.extract(&mut probe).await;
+            let suspect = matches!(&date, Ok(Some(date)) if self.is_suspect(date));
+            candidates.push(Candidate {
+                source: extractor.name(),
+                used: self.extractors.iter().any(|e| e.name() == extractor.name()),
+                suspect,
+                date,
+            });
+        }
+        // Make sure the metadata's been read, even if every source that wants it is elsewhere.
+        probe.metadata().await;
+        (candidates, probe.into_metadata())
+    }
+
+    /// Converts `date` to the time zone it's filed in: with `--tz-from-gps`, the one where the file
+    /// was taken, if it has a GPS position, and otherwise `--tz`. Without either it's left as is.
+    fn localize(&self, date: Date, metadata: Option<&Metadata>) -> Date {
+        let position = metadata.and_then(|m| m.position).filter(|_| self.tz_from_gps);
+        let local_zone = position
+            .and_then(|(latitude, longitude)| tz_search::lookup(latitude, longitude))
+            .and_then(|name| name.parse::<Tz>().ok());
+        match local_zone.or(self.tz) {
+            Some(zone) => date.in_zone(zone, self.assume_tz),
+            None => date,
+        }
+    }
+
+    fn is_suspect(&self, date: &Date) -> bool {
+        !date.is_valid() || self.suspect_before.as_ref().is_some_and(|cutoff| date < cutoff)
+    }
+}
+
+/// Returned for a taken destination under `--on-conflict error`.
+#[derive(Error, Debug)]
+#[error("Destination {} already exists", .0.display())]
+pub struct ConflictError(PathBuf);
+
+/// Sorts a failure from [`Sorter::plan`] into a [`FailureKind`].
+fn plan_failure_kind(error: &anyhow::Error) -> FailureKind {
+    if error.is::<ConflictError>() {
+        return FailureKind::Conflict;
+    }
+    match error.chain().find_map(|e| e.downcast_ref::<FileParseError>()) {
+        Some(FileParseError::FileError(_)) | None => FailureKind::Unreadable,
+        Some(FileParseError::UnsupportedError(_)) => FailureKind::Unsupported,
+        Some(_) => FailureKind::NoDate,
+    }
+}
+
+/// Sorts a failure from [`Sorter::apply`] into a [`FailureKind`].
+fn apply_failure_kind(error: &anyhow::Error) -> FailureKind {
+    match error.chain().find_map(|e| e.downcast_ref::<RenameError>()) {
+        // Something else took the destination after it was planned.
+        Some(RenameError::DestExists(_)) => FailureKind::Conflict,
+        Some(RenameError::SourceMissing(_)) => FailureKind::Unreadable,
+        Some(RenameError::Exhausted { .. }) => FailureKind::Exhausted,
+        _ => FailureKind::Transfer,
+    }
+}
+
+/// How something that couldn't be listed is reported: a zip archive that couldn't be unpacked
+/// under its own path, and the rest as walk errors without one.
+fn walk_failure(error: WalkError) -> (Option<PathBuf>, SortResult) {
+    let (path, kind) = match &error {
+        WalkError::Archive(ArchiveError::Open(path, _) | ArchiveError::Zip(path, _)) => {
+            (Some(path.clone()), FailureKind::Unreadable)
+        }
+        WalkError::Archive(ArchiveError::NoExtractDir(path)) => {
+            (Some(path.clone()), FailureKind::Unsupported)
+        }
+        _ => (None, FailureKind::Walk),
+    };
+    (path, Err((kind, error.into())))
+}
+
+/// Leaves out the files that `watermarks`, if there are any, say the last run listed, checking
+/// their modification times on the blocking thread pool.
+async fn unseen(watermarks: &mut Option<Watermarks>, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut marks = match watermarks.take() {
+        Some(marks) => marks,
+        None => return Ok(files),
+    };
+    let (marks, files) = tokio::task::spawn_blocking(move || {
+        let files = marks.filter(files);
+        (marks, files)
+    })
+    .await?;
+    *watermarks = Some(marks);
+    Ok(files)
+}
+
+/// One stage of [`Sorter::run`]: runs `task` on each of `items` in a task of its own, and yields
+/// their results in the order of `items`. The tasks are spawned from a task of their own, which
+/// waits for room in a channel holding `limit` of them before spawning each, so no more than
+/// `limit` get ahead of the receiver, and `items` isn't read any faster than they're taken.
+fn stage<T, F, Fut>(
+    items: impl Stream<Item = T> + Send + 'static,
+    limit: usize,
+    mut task: F,
+) -> impl Stream<Item = Result<Fut::Output, JoinError>>
+where
+    T: Send + 'static,
+    F: FnMut(T) -> Fut + Send + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    let (mut tx, rx) = tokio::sync::mpsc::channel(limit);
+    tokio::spawn(async move {
+        futures::pin_mut!(items);
+        while future::poll_fn(|cx| tx.poll_ready(cx)).await.is_ok() {
+            let item = match items.next().await {
+                Some(item) => item,
+                None => break,
+            };
+            if tx.try_send(tokio::spawn(task(item))).is_err() {
+                break;
+            }
+        }
+    });
+    rx.then(|task| task)
+}
+
+/// Where a file belongs in the library, and what that was decided from.
+pub struct Placement {
+    pub dest: PathBuf,
+    pub date: Date,
+    /// Which date source the date came from.
+    pub extractor: &'static str,
+    pub camera: Camera,
+    /// Size of the file, for reporting how much data was moved.
+    pub bytes: u64,
+    /// Set when the file should be left where it is, saying why.
+    pub skip: Option<&'static str>,
+}
+
+/// A file's placement, or what kind of failure stopped it and why.
+pub type SortResult = Result<Placement, (FailureKind, anyhow::Error)>;
+
+/// Moves files to where the layout says they belong.
+pub struct Sorter {
+    layout: Layout,
+    dates: DateReader,
+    renamer: Box<dyn Renamer>,
+    walker: Walker,
+    /// How many files are read at once.
+    jobs: usize,
+    /// How many files are moved at once.
+    transfer_jobs: usize,
+    output: OutputFormat,
+    on_conflict: ConflictPolicy,
+    /// Destinations already handed out in this run, so two files that map to the same name
+    /// are treated as a conflict even before either has been moved.
+    claimed: Mutex<HashSet<PathBuf>>,
+    since: Option<Date>,
+    until: Option<Date>,
+    min_rating: Option<i32>,
+    /// Lowercased, for matching regardless of case.
+    keywords: Vec<String>,
+    pub dry_run: bool,
+    /// Whether to flush each file and its directories to disk once it's sorted.
+    fsync: bool,
+    /// Whether to set each sorted file's modification time to its date.
+    set_mtime: bool,
+    permissions: Permissions,
+    /// Whether to make sure there's room in the library before copying into it.
+    space_check: bool,
+    /// Extensions of the sidecars sorted along with their photos.
+    sidecars: Vec<String>,
+    pairing: Option<Pairing>,
+    /// Whether Live Photos' videos are sorted along with their stills.
+    live_photos: bool,
+    /// Whether AppleDouble files are sorted along with the files they belong to.
+    apple_double: bool,
+    /// How the backend gets files into the library.
+    pub transfer: Transfer,
+    /// Where each move is recorded before and after it's made.
+    journal: Option<Journal>,
+    /// Files an interrupted run being resumed had already sorted, which are left out.
+    moved_before: HashSet<PathBuf>,
+    /// Held for as long as the sorter is, once it's locked the library.
+    lock: Option<LibraryLock>,
+}
+
+impl Sorter {
+    pub fn new(library: PathBuf, options: &SortOptions, profile: &Profile) -> Result<Sorter> {
+        // An rclone remote is the library, wherever --dest would have put it.
+        let library = match options.remote.as_ref().or(profile.remote.as_ref()) {
+            Some(remote) => PathBuf::from(remote),
+            None => library,
+        };
+        let renamer = get_renamer(options, profile, &library)?;
+        let transfer = renamer.transfer();
+        // Reflinked copies share their originals' data, and remote libraries don't say how much
+        // room they have.
+        let reflink = options.backend.or(profile.backend) == Some(Backend::Reflink);
+        let space_check =
+            transfer == Transfer::Copy && renamer.local() && !reflink && !options.no_space_check;
+        let jobs = options.jobs.map_or_else(default_jobs, |n| n.get());
+        Ok(Sorter {
+            layout: Layout::new(library, &options.layout, profile)?,
+            dates: DateReader::new(&options.dates, profile)?,
+            renamer,
+            walker: Walker::new(&options.traversal)?,
+            jobs,
+            transfer_jobs: options.transfer_jobs.map_or(jobs, |n| n.get()),
+            output: options.output,
+            on_conflict: options
+                .on_conflict
+                .or(profile.on_conflict)
+                .unwrap_or(ConflictPolicy::Skip),
+            claimed: Mutex::new(HashSet::new()),
+            since: options.since.clone(),
+            until: options.until.clone(),
+            min_rating: options.min_rating.map(i32::from),
+            keywords: options.keyword.iter().map(|k| k.to_lowercase()).collect(),
+            dry_run: options.dry_run,
+            fsync: options.fsync || profile.fsync.unwrap_or(false),
+            set_mtime: options.set_mtime || profile.set_mtime.unwrap_or(false),
+            permissions: Permissions::new(options, profile)?,
+            space_check,
+            pairing: options.pair.or(profile.pair),
+            live_photos: !options.split_live_photos && profile.live_photos.unwrap_or(true),
+            apple_double: options.apple_double.or(profile.apple_double)
+                == Some(AppleDoublePolicy::Follow),
+            sidecars: match (&options.sidecars[..], &profile.sidecars) {
+                _ if options.no_sidecars => Vec::new(),
+                ([], Some(sidecars)) => sidecars.clone(),
+                ([], None) => DEFAULT_COMPANIONS.iter().map(|ext| ext.to_string()).collect(),
+                (sidecars, _) => sidecars.to_vec(),
+            },
+            transfer,
+            journal: None,
+            moved_before: HashSet::new(),
+            lock: None,
+        })
+    }
+
+    /// Locks the library against other runs, as `LibraryLock::acquire` does, unless it's a dry
+    /// run or the library isn't on this machine.
+    pub async fn lock(&mut self, wait: bool, force: bool) -> Result<()> {
+        if !self.dry_run && self.renamer.local() {
+            self.lock = Some(LibraryLock::acquire(&self.layout.library, wait, force).await?);
+        }
+        Ok(())
+    }
+
+    /// Starts recording the moves of a run sorting `inputs` in a new journal, unless it's a dry
+    /// run.
+    pub fn start_journal(&mut self, inputs: &[PathBuf]) -> Result<()> {
+        if !self.dry_run {
+            let journal = Journal::create(&self.layout.library, inputs)?;
+            debug!(journal = %journal.path().display(), "Started journal");
+            self.journal = Some(journal);
+        }
+        Ok(())
+    }
+
+    /// Carries on from where the interrupted `run` left off: finishes the moves it was in the
+    /// middle of, leaves out the files it had already sorted, and goes on recording in its
+    /// journal. The moves that hadn't got anywhere are made again along with the rest.
+    pub async fn resume(&mut self, run: &Run) -> Result<()> {
+        if run.finished {
+            bail!("The run in {} finished, so there's nothing to resume", run.path.display());
+        }
+        let journal = run.reopen()?;
+        self.moved_before = run.moved();
+        for made in run.moves.iter().filter(|m| !m.done) {
+            if self.finish_interrupted(&made.source, &made.dest).await? {
+                if let Err(e) = journal.done(&made.source, &made.dest) {
+                    warn!("Failed to write to the journal: {}", e);
+                }
+                self.moved_before.insert(made.source.clone());
+            }
+        }
+        self.journal = Some(journal);
+        Ok(())
+    }
+
+    /// Reverses the moves `run` made, newest first: files moved into the library go back where
+    /// they came from, and the copies and links made there are removed, along with directories
+    /// left empty. A file is left where it is when something else has taken its old place since,
+    /// or a copy no longer matches its original. Returns whether every move could be undone.
+    pub async fn undo(&self, run: &Run) -> Result<bool> {
+        if !self.renamer.reversible() {
+            bail!("Only runs with the file, reflink or symlink backends can be undone");
+        }
+        let journal = run.reopen()?;
+        let mut undone_all = true;
+        for made in run.moves.iter().rev().filter(|m| m.done && !m.undone) {
+            let (source, dest) = (&made.source, &made.dest);
+            if let Err(e) = self.undo_move(source, dest).await {
+                error!("Failed to undo {}: {:#}", dest.display(), e);
+                undone_all = false;
+                continue;
+            }
+            if let Err(e) = journal.undone(source, dest) {
+                warn!("Failed to write to the journal: {}", e);
+            }
+            for dir in dest.ancestors().skip(1).take_while(|dir| *dir != self.layout.library) {
+                if !dir.starts_with(&self.layout.library) || std::fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(undone_all)
+    }
+
+    async fn undo_move(&self, source: &Path, dest: &Path) -> Result<()> {
+        match self.transfer {
+            Transfer::Move => {
+                if let Some(dir) = source.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    tokio::fs::create_dir_all(dir)
+                        .await
+                        .with_context(|| format!("Failed to create {}", dir.display()))?;
+                }
+                // Doesn't replace whatever's at `source` now, even if it turned up mid-move.
+                match FileRenamer::new(false).rename(dest, source).await {
+                    Err(RenameError::DestExists(_)) => {
+                        bail!("{} has been taken since", source.display())
+                    }
+                    result => result.context("Failed to move the file back")?,
+                }
+                println!("Moved back {} -> {}", dest.display(), source.display());
+            }
+            Transfer::Copy => {
+                if !same_contents(source, dest).await? {
+                    bail!("it no longer matches {}", source.display());
+                }
+                tokio::fs::remove_file(dest).await.context("Failed to remove the copy")?;
+                println!("Removed copy {}", dest.display());
+            }
+            Transfer::Link => {
+                let metadata = tokio::fs::symlink_metadata(dest).await.context("Failed to stat")?;
+                if !metadata.file_type().is_symlink() {
+                    bail!("it's no longer a link");
+                }
+                tokio::fs::remove_file(dest).await.context("Failed to remove the link")?;
+                println!("Removed link {}", dest.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Works out how far an interrupted move of `source` to `dest` got, finishing it if it only
+    /// had the original left to remove. Returns whether the file's in the library now. Its
+    /// owner, mode and sidecars are left as the move left them.
+    async fn finish_interrupted(&self, source: &Path, dest: &Path) -> Result<bool> {
+        let source_there = tokio::fs::symlink_metadata(source).await.is_ok();
+        let dest_there = self
+            .renamer
+            .exists(dest)
+            .await
+            .context("Failed to check whether the destination exists")?;
+        match (source_there, dest_there) {
+            (true, false) => Ok(false),
+            (false, true) => {
+                info!(source = %source.display(), dest = %dest.display(), "Move had been made");
+                Ok(true)
+            }
+            (true, true) if self.transfer != Transfer::Move => Ok(true),
+            // Copied across filesystems, but not yet removed from where it was.
+            (true, true) if self.renamer.local() && same_contents(source, dest).await? => {
+                if !self.dry_run {
+                    tokio::fs::remove_file(source)
+                        .await
+                        .with_context(|| format!("Failed to remove {}", source.display()))?;
+                }
+                info!(source = %source.display(), dest = %dest.display(), "Finished move");
+                Ok(true)
+            }
+            (true, true) => {
+                warn!(
+                    source = %source.display(),
+                    dest = %dest.display(),
+                    "Interrupted move left a different file at its destination"
+                );
+                Ok(false)
+            }
+            (false, false) => {
+                let (source, dest) = (source.display(), dest.display());
+                warn!(source = %source, dest = %dest, "Interrupted move's file is gone");
+                Ok(false)
+            }
+        }
+    }
+
+    /// Lists the files named by `inputs` a directory at a time, walking directories and
+    /// expanding globs on the blocking thread pool while the receiver reads them. The walk keeps
+    /// at most `jobs` directories ahead of it.
+    pub fn list(&self, inputs: &[PathBuf]) -> Receiver<Result<Vec<PathBuf>, WalkError>> {
+        let (tx, rx) = futures::channel::mpsc::channel(self.jobs);
+        let walker = self.walker.clone();
+        let inputs = inputs.to_vec();
+        tokio::task::spawn_blocking(move || walker.list(&inputs, tx));
+        rx
+    }
+
+    /// Works out where a single file belongs without touching anything.
+    pub async fn plan(&self, filename: &Path) -> Result<Placement> {
+        let Capture { date, extractor, camera, tags } = self.dates.read(filename).await?;
+        debug!(%date, extractor, "Read capture date");
+        let dest = self.layout.destination_for(filename, &date, &camera)?;
+        let bytes = tokio::fs::metadata(filename).await.context("Failed to stat input file")?.len();
+        let skip = if self.since.as_ref().is_some_and(|since| date < *since) {
+            Some("before --since")
+        } else if self.until.as_ref().is_some_and(|until| date > *until) {
+            Some("after --until")
+        } else {
+            self.tag_filter(filename, tags).await
+        };
+        if skip.is_some() {
+            return Ok(Placement { dest, date, extractor, camera, bytes, skip });
+        }
+        let (dest, skip) = self.resolve_conflict(filename, dest).await?;
+        Ok(Placement { dest, date, extractor, camera, bytes, skip })
+    }
+
+    /// Why `--min-rating` or `--keyword` rules the file out, if either does. What an XMP sidecar
+    /// says wins over the file's own metadata.
+    async fn tag_filter(&self, filename: &Path, tags: Tags) -> Option<&'static str> {
+        if self.min_rating.is_none() && self.keywords.is_empty() {
+            return None;
+        }
+        let xmp = sidecar::xmp_tags(filename).await.unwrap_or_default();
+        let rating = xmp.rating.or(tags.rating).unwrap_or(0);
+        let keywords = if xmp.keywords.is_empty() { tags.keywords } else { xmp.keywords };
+        if self.min_rating.is_some_and(|min| rating < min) {
+            return Some("below --min-rating");
+        }
+        let wanted = |keyword: &String| self.keywords.contains(&keyword.to_lowercase());
+        if !self.keywords.is_empty() && !keywords.iter().any(wanted) {
+            return Some("no --keyword matched");
+        }
+        None
+    }
+
+    /// Applies the conflict policy when `dest` already exists or was claimed by an earlier file
+    /// in this run, returning the destination to use and a skip reason if there is none. A file
+    /// that's already there, or the same as the one that is, is skipped whatever the policy, so
+    /// sorting the library again changes nothing. Overwriting only replaces files from before the
+    /// run: two files this run places at the same name are renamed apart instead.
+    async fn resolve_conflict(
+        &self,
+        source: &Path,
+        dest: PathBuf,
+    ) -> Result<(PathBuf, Option<&'static str>)> {
+        let mut candidate = dest.clone();
+        let mut n = 0;
+        loop {
+            let exists = self
+                .renamer
+                .exists(&candidate)
+                .await
+                .context("Failed to check whether the destination exists")?;
+            // Claimed even when it exists, so only a file that was there before the run can be
+            // overwritten, never one this run put there.
+            let unclaimed = self.claimed.lock().unwrap().insert(candidate.clone());
+            if exists && self.already_sorted(source, &candidate).await {
+                return Ok((candidate, Some("already sorted")));
+            }
+            match (exists, unclaimed, self.on_conflict) {
+                (false, true, _) | (true, true, ConflictPolicy::Overwrite) => {
+                    return Ok((candidate, None))
+                }
+                (_, _, ConflictPolicy::Skip) => return Ok((candidate, Some("destination exists"))),
+                (_, _, ConflictPolicy::Error) => return Err(ConflictError(candidate).into()),
+                (_, _, ConflictPolicy::Rename) | (_, _, ConflictPolicy::Overwrite) => {
+                    n += 1;
+                    candidate = with_suffix(&dest, n);
+                }
+            }
+        }
+    }
+
+    /// Whether `source` is the file at `dest`, or has the same contents. Only files in a local
+    /// library can be compared; one that can't be read counts as different.
+    async fn already_sorted(&self, source: &Path, dest: &Path) -> bool {
+        if !self.renamer.local() {
+            return false;
+        }
+        let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.into());
+        if absolute(source) == absolute(dest) {
+            return true;
+        }
+        match same_contents(source, dest).await {
+            Ok(same) => same,
+            Err(e) => {
+                debug!(dest = %dest.display(), "Treating the destination as different: {:#}", e);
+                false
+            }
+        }
+    }
+
+    /// Moves (or with `--copy`, copies, or with the symlink backend, links) a file to its planned
+    /// destination. If a file has turned up there since it was planned, the conflict policy
+    /// applies again: the file goes to the next free name instead under `--on-conflict rename`,
+    /// and is left where it is with `placement.skip` set under `--on-conflict skip`.
+    pub async fn apply(&self, filename: &Path, placement: &mut Placement) -> Result<()> {
+        let mut companions = if self.sidecars.is_empty() {
+            Vec::new()
+        } else {
+            sidecar::companions(filename, &self.sidecars).await
+        };
+        if self.apple_double {
+            companions.extend(sidecar::apple_double(filename).await);
+        }
+        if !self.rename_planned(filename, placement).await? {
+            return Ok(());
+        }
+        let dest = &placement.dest;
+        let modified = placement.date.to_system_time().filter(|_| self.set_mtime);
+        self.settle(filename, dest, modified).await?;
+        // The file's in the library by now, so these are only worth a warning.
+        if let Err(e) = self.renamer.annotate(placement).await {
+            warn!(dest = %dest.display(), "Failed to record what the file was sorted by: {}", e);
+        }
+        for companion in companions {
+            if let Err(e) = self.apply_companion(&companion, dest).await {
+                warn!(sidecar = %companion.path.display(), "Failed to sort sidecar: {:#}", e);
+            }
+        }
+        debug!(dest = %dest.display(), transfer = ?self.transfer, "Sorted file");
+        Ok(())
+    }
+
+    /// Has the backend move `filename` to `placement.dest` for [`Sorter::apply`], returning false
+    /// when the file was left where it is.
+    async fn rename_planned(&self, filename: &Path, placement: &mut Placement) -> Result<bool> {
+        loop {
+            // An object store just replaces what's there, and has no directories to create.
+            if self.renamer.local() {
+                self.make_room(&placement.dest).await?;
+            }
+            let e = match self.journaled_rename(filename, &placement.dest).await {
+                Ok(()) => return Ok(true),
+                Err(e) => e,
+            };
+            let taken =
+                e.chain().any(|e| matches!(e.downcast_ref(), Some(RenameError::DestExists(_))));
+            match self.on_conflict {
+                ConflictPolicy::Rename if taken => {
+                    let dest = placement.dest.clone();
+                    match self.resolve_conflict(filename, dest).await? {
+                        (dest, None) => placement.dest = dest,
+                        (_, Some(reason)) => {
+                            placement.skip = Some(reason);
+                            return Ok(false);
+                        }
+                    }
+                }
+                ConflictPolicy::Skip if taken => {
+                    placement.skip = Some("destination exists");
+                    return Ok(false);
+                }
+                _ => return Err(e),
+            }
+        }
+    }
+
+    /// Moves a sidecar to sit next to its photo at `dest`. It's left where it is when its
+    /// destination is taken, unless the conflict policy is to overwrite.
+    async fn apply_companion(&self, companion: &Companion, dest: &Path) -> Result<()> {
+        let source = &companion.path;
+        // Gone when it was another spelling of one already moved, on a case-insensitive
+        // filesystem.
+        if tokio::fs::symlink_metadata(source).await.is_err() {
+            return Ok(());
+        }
+        let dest = companion.dest(dest);
+        let exists = self
+            .renamer
+            .exists(&dest)
+            .await
+            .context("Failed to check whether the destination exists")?;
+        if exists && self.on_conflict != ConflictPolicy::Overwrite {
+            bail!("{} already exists", dest.display());
+        }
+        if self.renamer.local() {
+            self.make_room(&dest).await?;
+        }
+        self.journaled_rename(source, &dest).await?;
+        self.settle(source, &dest, None).await?;
+        debug!(sidecar = %source.display(), dest = %dest.display(), "Sorted sidecar");
+        Ok(())
+    }
+
+    /// Has the backend move `source` to `dest`, recording the move in the journal before and
+    /// after.
+    async fn journaled_rename(&self, source: &Path, dest: &Path) -> Result<()> {
+        if let Some(journal) = &self.journal {
+            journal.intend(source, dest).context("Failed to write to the journal")?;
+        }
+        self.renamer.rename(source, dest).await.context("Failed to rename file")?;
+        if let Some(journal) = &self.journal {
+            // The file's moved either way, and resuming would find it.
+            if let Err(e) = journal.done(source, dest) {
+                warn!("Failed to write to the journal: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives a file just put in the library at `dest` its owner, mode and `modified` time, and
+    /// flushes it to disk, as the options ask. Files that stay remote are left as they are.
+    async fn settle(&self, source: &Path, dest: &Path, modified: Option<SystemTime>) -> Result<()> {
+        if !self.renamer.local() {
+            return Ok(());
+        }
+        if !self.permissions.is_empty() {
+            let (permissions, dest) = (self.permissions.clone(), dest.to_path_buf());
+            tokio::task::spawn_blocking(move || permissions.apply_to_file(&dest))
+                .await?
+                .context("Sorted the file, but failed to set its owner or mode")?;
+        }
+        if let Some(modified) = modified {
+            let dest = dest.to_path_buf();
+            tokio::task::spawn_blocking(move || set_mtime(&dest, modified))
+                .await?
+                .context("Sorted the file, but failed to set its modification time")?;
+        }
+        if self.fsync {
+            let (source, dest) = (source.to_path_buf(), dest.to_path_buf());
+            let moved = self.transfer == Transfer::Move;
+            tokio::task::spawn_blocking(move || sync_sorted(&source, &dest, moved))
+                .await?
+                .context("Sorted the file, but failed to flush it to disk")?;
+        }
+        Ok(())
+    }
+
+    /// Creates `dest`'s directory, and with `--on-conflict overwrite`, moves whatever's at
+    /// `dest` to the trash. Backends disagree on whether they replace an existing file, so it's
+    /// moved out of the way first.
+    async fn make_room(&self, dest: &Path) -> Result<()> {
+        let dest_dir = dest.parent().unwrap();
+        // The directories about to be made, which --chmod and --chown apply to as well.
+        let mut missing = Vec::new();
+        if !self.permissions.is_empty() {
+            for dir in dest_dir.ancestors() {
+                if dir.as_os_str().is_empty() || tokio::fs::symlink_metadata(dir).await.is_ok() {
+                    break;
+                }
+                missing.push(dir.to_path_buf());
+            }
+        }
+        tokio::fs::create_dir_all(&dest_dir).await.context("Failed to create dest dir")?;
+        for dir in missing.into_iter().rev() {
+            let permissions = self.permissions.clone();
+            tokio::task::spawn_blocking(move || permissions.apply_to_dir(&dir))
+                .await?
+                .context("Failed to set the owner or mode of a new directory")?;
+        }
+        let exists = tokio::fs::symlink_metadata(dest).await.is_ok();
+        if self.on_conflict == ConflictPolicy::Overwrite && exists {
+            let (library, existing) = (self.layout.library.clone(), dest.to_path_buf());
+            let trashed = tokio::task::spawn_blocking(move || trash::trash(&library, &existing))
+                .await?
+                .context("Failed to move existing destination to the trash")?;
+            debug!(dest = %dest.display(), trash = %trashed.display(), "Trashed existing file");
+        }
+        Ok(())
+    }
+
+    /// Fails when the library's filesystem hasn't room for `bytes` more with some to spare, so
+    /// that a copy doesn't run out of space partway through.
+    async fn check_space(&self, bytes: u64) -> Result<()> {
+        let library = self.layout.library.clone();
+        let available = tokio::task::spawn_blocking(move || space::available(&library))
+            .await?
+            .context("Failed to check how much room there is in the library")?;
+        // 5%, for directories and whatever else is written to the filesystem meanwhile.
+        let needed = bytes.saturating_add(bytes / 20);
+        if let Some(available) = available.filter(|&available| available < needed) {
+            bail!(
+                "Copying {} needs {} free in the library, counting a margin, but only {} is. \
+                 Pass --no-space-check to copy anyway",
+                HumanBytes(bytes),
+                HumanBytes(needed),
+                HumanBytes(available),
+            );
+        }
+        Ok(())
+    }
+
+    /// Lets the backend record the files moved since the last batch, like `--git-commit` does.
+    pub async fn finish_batch(&self) -> Result<()> {
+        self.renamer.finish().await.context("Failed to record the batch of moved files")?;
+        if let Some(journal) = &self.journal {
+            journal.finish().context("Failed to write to the journal")?;
+        }
+        Ok(())
+    }
+
+    /// Moves a single file into the library, returning where it ended up (or would have, with
+    /// `--dry-run`).
+    pub async fn sort_file(&self, filename: &Path) -> Result<Placement> {
+        let mut placement = self.plan(filename).await?;
+        if !self.dry_run && placement.skip.is_none() {
+            self.apply(filename, &mut placement).await?;
+        }
+        Ok(placement)
+    }
+
+    /// Like [`Sorter::sort_file`], but also says what kind of failure stopped the file.
+    async fn sort_entry(&self, filename: &Path) -> SortResult {
+        let placement = self.plan(filename).await.map_err(|e| (plan_failure_kind(&e), e))?;
+        self.apply_entry(filename, placement).await
+    }
+
+    /// Moves a planned file unless it's skipped or this is a dry run, saying what kind of failure
+    /// stopped it if one did.
+    async fn apply_entry(&self, filename: &Path, mut placement: Placement) -> SortResult {
+        if !self.dry_run && placement.skip.is_none() {
+            let applied = self.apply(filename, &mut placement).await;
+            applied.map_err(|e| (apply_failure_kind(&e), e))?;
+        }
+        Ok(placement)
+    }
+
+    /// Groups the files found in one directory with the ones sorted along with them: RAW
+    /// files' JPEGs with `--pair`, and Live Photos' videos unless they're split.
+    pub fn pair(&self, files: Vec<PathBuf>) -> Vec<Unit> {
+        let mut units = files.into_iter().map(Unit::from).collect();
+        if self.pairing.is_some() {
+            units = pairs::pair(units, Partner::Jpeg);
+        }
+        if self.live_photos {
+            units = pairs::pair(units, Partner::Motion);
+        }
+        units
+    }
+
+    /// Works out where a `kind` of partner to a file that's been planned as `leader` belongs:
+    /// next to it, named the same but for the extension, and left out when `leader` is. It's
+    /// dated by `leader` too, since a Live Photo's video can start a second before its still.
+    async fn plan_partner(
+        &self,
+        partner: &Path,
+        kind: Partner,
+        leader: &Placement,
+    ) -> Result<Placement> {
+        let bytes = tokio::fs::metadata(partner).await.context("Failed to stat input file")?.len();
+        // For the extension the layout gives the file, which --normalize-ext can change.
+        let own = self.layout.destination_for(partner, &leader.date, &leader.camera)?;
+        let dest = leader.dest.with_extension(own.extension().unwrap_or_default());
+        let placement = |dest, skip| Placement {
+            dest,
+            date: leader.date.clone(),
+            extractor: leader.extractor,
+            camera: leader.camera.clone(),
+            bytes,
+            skip,
+        };
+        if kind == Partner::Jpeg && self.pairing == Some(Pairing::PreferRaw) {
+            return Ok(placement(dest, Some("RAW file kept instead")));
+        }
+        if leader.skip.is_some() {
+            return Ok(placement(dest, leader.skip));
+        }
+        let (dest, skip) = self.resolve_conflict(partner, dest).await?;
+        Ok(placement(dest, skip))
+    }
+
+    /// Plans a file, then its partners to match, for the first stage of [`Sorter::sort_paths`].
+    /// When the first can't be placed, its partners are planned on their own.
+    async fn place_unit(&self, unit: Unit) -> Vec<(PathBuf, SortResult)> {
+        let result = self.plan(&unit.path).await.map_err(|e| (plan_failure_kind(&e), e));
+        let mut placed = Vec::new();
+        for (partner, kind) in unit.partners {
+            let span = info_span!("plan_partner", path = %partner.display());
+            let partner_result = match &result {
+                Ok(leader) => self.plan_partner(&partner, kind, leader).instrument(span).await,
+                Err(_) => self.plan(&partner).instrument(span).await,
+            };
+            placed.push((partner, partner_result.map_err(|e| (plan_failure_kind(&e), e))));
+        }
+        placed.insert(0, (unit.path, result));
+        placed
+    }
+
+    /// Moves the files [`Sorter::place_unit`] planned, for the second stage of
+    /// [`Sorter::sort_paths`]. When the first can't be moved, its partners are sorted on their
+    /// own instead.
+    async fn move_unit(&self, placed: Vec<(PathBuf, SortResult)>) -> Vec<(PathBuf, SortResult)> {
+        let mut sorted = Vec::new();
+        let mut leader_failed = false;
+        for (path, result) in placed {
+            let result = match result {
+                Ok(placement) if leader_failed => {
+                    if placement.skip.is_none() {
+                        self.claimed.lock().unwrap().remove(&placement.dest);
+                    }
+                    self.sort_entry(&path).await
+                }
+                Ok(placement) => {
+                    let result = self.apply_entry(&path, placement).await;
+                    leader_failed = sorted.is_empty() && result.is_err();
+                    result
+                }
+                Err(e) => Err(e),
+            };
+            sorted.push((path, result));
+        }
+        sorted
+    }
+
+    /// Sorts every file named by `inputs`, walking directories and expanding globs. Files are
+    /// listed, read and moved in separate stages, as [`Sorter::run`] describes, so the first
+    /// files are sorted while the walk goes on, and slow reads and slow transfers don't hold each
+    /// other up. Failures are reported per file and don't stop the rest of the batch; check the
+    /// returned summary to see whether any happened.
+    ///
+    /// Zip archives among `inputs` are unpacked into `extract_to` once the rest are listed, and
+    /// their photos sorted from there as they're unpacked.
+    ///
+    /// With `interactive`, the tasks only plan and each move is confirmed before it happens.
+    ///
+    /// With `since_last_run`, files in input directories that were listed by an earlier run
+    /// like it are left out, and the watermarks saying which were are moved past this run's
+    /// files once it's done, unless it's a dry run or was quit.
+    pub async fn sort_paths(
+        self: Arc<Self>,
+        inputs: &[PathBuf],
+        extract_to: Option<&Path>,
+        interactive: bool,
+        since_last_run: bool,
+    ) -> Result<Summary> {
+        let mut watermarks = if since_last_run { Some(Watermarks::load(inputs)?) } else { None };
+        if self.space_check && !self.dry_run {
+            self.check_space(self.input_bytes(inputs, &mut watermarks).await?).await?;
+        }
+        let deferred = interactive && !self.dry_run;
+        let mut ask = deferred;
+
+        let mut reporter = Reporter::new(0, self.output, self.dry_run, self.transfer);
+        let (tx, units) = tokio::sync::mpsc::channel(self.jobs);
+        let inputs = inputs.to_vec();
+        let extract_to = extract_to.map(Path::to_path_buf);
+        let found = reporter.found();
+        let feed =
+            tokio::spawn(self.clone().feed(inputs, watermarks, extract_to.clone(), found, tx));
+        let mut results = self.clone().run(units, deferred);
+        let mut quit = false;
+
+        while let Some(result) = results.next().await {
+            let (source, mut placement) = match result? {
+                (Some(source), Ok(placement)) => match placement.skip {
+                    Some(reason) => {
+                        reporter.skipped(&source, &placement, reason);
+                        continue;
+                    }
+                    None => (source, placement),
+                },
+                (source, Err((kind, e))) => {
+                    reporter.failed(source.as_deref(), kind, &e);
+                    continue;
+                }
+                (None, Ok(_)) => unreachable!("only walk errors come without a path"),
+            };
+            if ask {
+                match reporter.confirm(&source, &placement.dest) {
+                    Answer::Yes => {}
+                    Answer::All => ask = false,
+                    Answer::No => {
+                        reporter.skipped(&source, &placement, "declined");
+                        continue;
+                    }
+                    Answer::Quit => {
+                        quit = true;
+                        break;
+                    }
+                }
+            }
+            if deferred {
+                if let Err(e) = self.apply(&source, &mut placement).await {
+                    reporter.failed(Some(&source), apply_failure_kind(&e), &e);
+                    continue;
+                }
+                if let Some(reason) = placement.skip {
+                    reporter.skipped(&source, &placement, reason);
+                    continue;
+                }
+            }
+            reporter.sorted(&source, &placement);
+        }
+        // Stops the walk and any unpacking still going, after a quit.
+        drop(results);
+        let (watermarks, unpacked) = feed.await??;
+        if let (true, Some(dir)) = (self.dry_run, extract_to) {
+            tokio::task::spawn_blocking(move || archive::remove_extracted(&dir, &unpacked)).await?;
+        }
+        // After a quit, the files not asked about yet are still to be sorted next time.
+        if let (false, false, Some(watermarks)) = (self.dry_run, quit, watermarks) {
+            watermarks.save()?;
+        }
+        let summary = reporter.finish();
+        self.finish_batch().await?;
+        Ok(summary)
+    }
+
+    /// Lists the files named by `inputs` for [`Sorter::sort_paths`], sending them on `tx` a
+    /// directory at a time, paired up, and counting them into `found`. Files already sorted by
+    /// an interrupted run, or listed by the last one when there are `watermarks`, are left out.
+    /// Zip archives are unpacked into `extract_to` once everything else is listed, and their
+    /// photos sent as they're unpacked. This stops early if the receiver goes away.
+    ///
+    /// Returns the watermarks, moved past the files listed, and the files unpacked.
+    async fn feed(
+        self: Arc<Self>,
+        inputs: Vec<PathBuf>,
+        mut watermarks: Option<Watermarks>,
+        extract_to: Option<PathBuf>,
+        found: Found,
+        mut tx: tokio::sync::mpsc::Sender<Result<Unit, WalkError>>,
+    ) -> Result<(Option<Watermarks>, Vec<PathBuf>)> {
+        let mut archives = Vec::new();
+        let mut batches = self.list(&inputs);
+        while let Some(batch) = batches.next().await {
+            let mut files = match batch {
+                Ok(files) => files,
+                Err(e) => {
+                    found.add(1);
+                    if tx.send(Err(e)).await.is_err() {
+                        return Ok((watermarks, Vec::new()));
+                    }
+                    continue;
+                }
+            };
+            files.retain(|path| !self.moved_before.contains(path));
+            files = unseen(&mut watermarks, files).await?;
+            let (zips, files): (Vec<_>, Vec<_>) =
+                files.into_iter().partition(|path| archive::is_archive(path));
+            archives.extend(zips);
+            found.add(files.len());
+            for unit in self.pair(files) {
+                if tx.send(Ok(unit)).await.is_err() {
+                    return Ok((watermarks, Vec::new()));
+                }
+            }
+        }
+
+        let mut unpacked = Vec::new();
+        for path in archives {
+            let dir = match &extract_to {
+                Some(dir) => dir.clone(),
+                None => {
+                    found.add(1);
+                    let _ = tx.send(Err(ArchiveError::NoExtractDir(path).into())).await;
+                    continue;
+                }
+            };
+            let open = path.clone();
+            let listing = match tokio::task::spawn_blocking(move || Listing::open(&open)).await? {
+                Ok(listing) => listing,
+                Err(e) => {
+                    found.add(1);
+                    let _ = tx.send(Err(e.into())).await;
+                    continue;
+                }
+            };
+            found.add(listing.len());
+            let (files_tx, mut files) = futures::channel::mpsc::channel(self.jobs);
+            let extraction = tokio::task::spawn_blocking(move || listing.extract(&dir, files_tx));
+            while let Some(file) = files.next().await {
+                if tx.send(file.map(Unit::from).map_err(WalkError::from)).await.is_err() {
+                    break;
+                }
+            }
+            // Stops the unpacking, if the receiver went away.
+            drop(files);
+            unpacked.extend(extraction.await?);
+        }
+        Ok((watermarks, unpacked))
+    }
+
+    /// Adds up the sizes of the files [`Sorter::feed`] would send, for the space check, in a walk
+    /// of its own so the files don't have to be kept until the check is done. Zip archives are
+    /// left out, not being copied.
+    async fn input_bytes(
+        &self,
+        inputs: &[PathBuf],
+        watermarks: &mut Option<Watermarks>,
+    ) -> Result<u64> {
+        let mut bytes = 0;
+        let mut batches = self.list(inputs);
+        while let Some(batch) = batches.next().await {
+            let mut files = batch.unwrap_or_default();
+            files.retain(|path| !self.moved_before.contains(path) && !archive::is_archive(path));
+            let files = unseen(watermarks, files).await?;
+            bytes += tokio::task::spawn_blocking(move || {
+                let sizes = files.iter().filter_map(|path| std::fs::metadata(path).ok());
+                sizes.map(|m| m.len()).sum::<u64>()
+            })
+            .await?;
+        }
+        Ok(bytes)
+    }
+
+    /// Sorts `units` from [`Sorter::pair`] as they come, yielding each file's result once it and
+    /// the files listed before it are done, for programs embedding photosort to show progress
+    /// their own way. With `plan_only`, files are planned but left for the caller to
+    /// [`Sorter::apply`]. Walk errors come without a path, except for zip archives that couldn't
+    /// be unpacked, and the stream only yields an error when a task panicked. Call
+    /// [`Sorter::finish_batch`] once it's done.
+    ///
+    /// Files are planned up to `jobs` at once and then moved up to `transfer_jobs` at once, each
+    /// stage in tasks of its own, connected by bounded channels: see [`stage`]. A stage that
+    /// falls behind holds the one before it back rather than files piling up, all the way back to
+    /// whatever's listing `units`.
+    pub fn run(
+        self: Arc<Self>,
+        units: impl Stream<Item = Result<Unit, WalkError>> + Send + 'static,
+        plan_only: bool,
+    ) -> impl Stream<Item = Result<(Option<PathBuf>, SortResult)>> {
+        let (jobs, transfer_jobs) = (self.jobs, self.transfer_jobs);
+        let planner = self.clone();
+        let placed = stage(units, jobs, move |unit| {
+            let sorter = planner.clone();
+            async move {
+                let unit = unit?;
+                let span = info_span!("plan_file", path = %unit.path.display());
+                Ok::<_, WalkError>(sorter.place_unit(unit).instrument(span).await)
+            }
+        });
+        let moved = stage(placed, transfer_jobs, move |placed| {
+            let sorter = self.clone();
+            async move {
+                match placed {
+                    Ok(Ok(placed)) if !plan_only => {
+                        let span = info_span!("sort_file", path = %placed[0].0.display());
+                        Ok(Ok(sorter.move_unit(placed).instrument(span).await))
+                    }
+                    placed => placed,
+                }
+            }
+        });
+        moved.flat_map(|result| {
+            let results = match result.and_then(|result| result) {
+                Ok(Ok(sorted)) => {
+                    sorted.into_iter().map(|(path, result)| Ok((Some(path), result))).collect()
+                }
+                Ok(Err(e)) => vec![Ok(walk_failure(e))],
+                Err(e) => vec![Err(e.into())],
+            };
+            stream::iter(results)
+        })
+    }
+
+    /// Works out where every file named by `inputs` belongs, reporting it as `--dry-run` would,
+    /// and returns the moves as a manifest for [`Sorter::apply_manifest`] to make later. Each
+    /// file is checksummed so a change in between can be caught.
+    pub async fn plan_paths(self: Arc<Self>, inputs: &[PathBuf]) -> Result<(Manifest, Summary)> {
+        let mut reporter = Reporter::new(0, self.output, true, self.transfer);
+        let (found, sorter) = (reporter.found(), self.clone());
+        let units = self.list(inputs).flat_map(move |batch| {
+            let units = match batch {
+                Ok(files) => {
+                    found.add(files.len());
+                    sorter.pair(files).into_iter().map(Ok).collect()
+                }
+                Err(e) => {
+                    found.add(1);
+                    vec![Err(e)]
+                }
+            };
+            stream::iter(units)
+        });
+        let planner = self.clone();
+        let mut results = stage(units, self.jobs, move |unit| {
+            let sorter = planner.clone();
+            async move {
+                let unit = unit?;
+                let span = info_span!("plan_file", path = %unit.path.display());
+                Ok::<_, WalkError>(sorter.plan_unit(unit).instrument(span).await)
+            }
+        });
+
+        // So the plan can be applied from another directory. Remote destinations are URLs and
+        // the like, which aren't relative to this one.
+        let local = self.renamer.local();
+        let absolute = |path: &Path| {
+            let absolute = if local { std::path::absolute(path).ok() } else { None };
+            absolute.unwrap_or_else(|| path.to_path_buf())
+        };
+        let mut files = Vec::new();
+        while let Some(result) = results.next().await {
+            let planned = match result? {
+                Ok(planned) => planned,
+                Err(e) => {
+                    reporter.failed(None, FailureKind::Walk, &e.into());
+                    continue;
+                }
+            };
+            for (source, result) in planned {
+                match result {
+                    Ok((placement, Some(checksum))) => {
+                        reporter.sorted(&source, &placement);
+                        let mut file = PlannedFile::new(&source, &placement, checksum);
+                        file.dest = absolute(&file.dest);
+                        files.push(file);
+                    }
+                    Ok((placement, None)) => {
+                        reporter.skipped(&source, &placement, placement.skip.unwrap_or_default())
+                    }
+                    Err((kind, e)) => reporter.failed(Some(&source), kind, &e),
+                }
+            }
+        }
+        let manifest = Manifest { library: absolute(&self.layout.library), files };
+        Ok((manifest, reporter.finish()))
+    }
+
+    /// Plans a file and its partners for [`Sorter::plan_paths`], the way
+    /// [`Sorter::place_unit`] would place them.
+    async fn plan_unit(
+        &self,
+        unit: Unit,
+    ) -> Vec<(PathBuf, Result<(Placement, Option<[u8; 32]>), (FailureKind, anyhow::Error)>)> {
+        let result = self.plan_checksummed(&unit.path).await;
+        let mut planned = Vec::new();
+        for (partner, kind) in unit.partners {
+            let partner_result = match &result {
+                Ok((leader, _)) => match self.plan_partner(&partner, kind, leader).await {
+                    Ok(placement) if placement.skip.is_some() => Ok((placement, None)),
+                    Ok(placement) => {
+                        checksum_file(&partner).await.map(|checksum| (placement, Some(checksum)))
+                    }
+                    Err(e) => Err((plan_failure_kind(&e), e)),
+                },
+                Err(_) => self.plan_checksummed(&partner).await,
+            };
+            planned.push((partner, partner_result));
+        }
+        planned.insert(0, (unit.path, result));
+        planned
+    }
+
+    /// Plans a file for [`Sorter::plan_paths`], along with its checksum unless it's skipped.
+    async fn plan_checksummed(
+        &self,
+        filename: &Path,
+    ) -> Result<(Placement, Option<[u8; 32]>), (FailureKind, anyhow::Error)> {
+        if archive::is_archive(filename) {
+            let e = anyhow::anyhow!("zip archives can't be planned; unpack them first");
+            return Err((FailureKind::Unsupported, e));
+        }
+        let placement = self.plan(filename).await.map_err(|e| (plan_failure_kind(&e), e))?;
+        if placement.skip.is_some() {
+            return Ok((placement, None));
+        }
+        Ok((placement, Some(checksum_file(filename).await?)))
+    }
+
+    /// Makes the moves in a manifest written by [`Sorter::plan_paths`], in order. A file that's
+    /// changed since it was planned is left where it is, and the conflict policy applies to
+    /// destinations taken in the meantime.
+    ///
+    /// Files are checksummed up to `jobs` ahead of the one being moved, each on the blocking
+    /// thread pool, so checking a plan of large RAW files keeps every core busy.
+    pub async fn apply_manifest(&self, manifest: &Manifest) -> Result<Summary> {
+        let planned = manifest
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| !self.moved_before.contains(&file.source))
+            .map(|(i, file)| {
+                let placement = file.placement();
+                Ok((file, placement.with_context(|| format!("Invalid file {} in plan", i))?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if self.space_check && !self.dry_run {
+            self.check_space(planned.iter().map(|(_, (placement, _))| placement.bytes).sum())
+                .await?;
+        }
+        let mut reporter = Reporter::new(planned.len(), self.output, self.dry_run, self.transfer);
+        let mut checked = stream::iter(planned)
+            .map(|(file, (placement, checksum))| async move {
+                let unchanged = checksum_file(&file.source).await.map(|actual| actual == checksum);
+                (file, placement, unchanged)
+            })
+            .buffered(self.jobs);
+        while let Some((file, placement, unchanged)) = checked.next().await {
+            let span = info_span!("apply_file", path = %file.source.display());
+            match self.apply_planned(&file.source, placement, unchanged).instrument(span).await {
+                Ok(placement) => match placement.skip {
+                    Some(reason) => reporter.skipped(&file.source, &placement, reason),
+                    None => reporter.sorted(&file.source, &placement),
+                },
+                Err((kind, e)) => reporter.failed(Some(&file.source), kind, &e),
+            }
+        }
+        let summary = reporter.finish();
+        self.finish_batch().await?;
+        Ok(summary)
+    }
+
+    /// Makes a planned move, given whether the file's checksum still matches the plan's.
+    async fn apply_planned(
+        &self,
+        source: &Path,
+        placement: Placement,
+        unchanged: Result<bool, (FailureKind, anyhow::Error)>,
+    ) -> Result<Placement, (FailureKind, anyhow::Error)> {
+        if !unchanged? {
+            let e = anyhow::anyhow!("{} has changed since it was planned", source.display());
+            return Err((FailureKind::Changed, e));
+        }
+        let (dest, skip) = self
+            .resolve_conflict(source, placement.dest)
+            .await
+            .map_err(|e| (plan_failure_kind(&e), e))?;
+        let mut placement = Placement { dest, skip, ..placement };
+        if !self.dry_run && placement.skip.is_none() {
+            let applied = self.apply(source, &mut placement).await;
+            applied.map_err(|e| (apply_failure_kind(&e), e))?;
+        }
+        Ok(placement)
+    }
+}
+
+/// Sets `dest`'s modification time, unless it's a symlink, whose target isn't ours to change.
+fn set_mtime(dest: &Path, modified: SystemTime) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(dest)?.file_type().is_symlink() {
+        return Ok(());
+    }
+    // Windows needs write access to change a file's times; elsewhere owning it is enough, which
+    // works for read-only files too.
+    let file = if cfg!(windows) {
+        std::fs::File::options().write(true).open(dest)?
+    } else {
+        std::fs::File::open(dest)?
+    };
+    file.set_times(std::fs::FileTimes::new().set_modified(modified))
+}
+
+/// Flushes a sorted file and its new directory to disk, along with the directory it left when
+/// it was `moved`.
+fn sync_sorted(source: &Path, dest: &Path, moved: bool) -> std::io::Result<()> {
+    std::fs::File::open(dest)?.sync_all()?;
+    // Directories can only be opened to sync them on Unix.
+    if cfg!(unix) {
+        let source_dir = source.parent().filter(|_| moved);
+        for dir in dest.parent().into_iter().chain(source_dir) {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the files at `a` and `b` hold the same bytes. Their sizes are compared first, so
+/// files of different sizes are told apart without reading either, and reading stops at the
+/// first difference.
+async fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let (a, b) = (a.to_path_buf(), b.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        if std::fs::metadata(&a)?.len() != std::fs::metadata(&b)?.len() {
+            return Ok(false);
+        }
+        let (mut a, mut b) = (File::open(&a)?, File::open(&b)?);
+        let (mut a_buf, mut b_buf) = (vec![0; 1 << 16], vec![0; 1 << 16]);
+        loop {
+            let (a_len, b_len) = (fill(&mut a, &mut a_buf)?, fill(&mut b, &mut b_buf)?);
+            if a_buf[..a_len] != b_buf[..b_len] {
+                return Ok(false);
+            }
+            if a_len == 0 {
+                return Ok::<_, std::io::Error>(true);
+            }
+        }
+    })
+    .await?
+    .context("Failed to compare files")
+}
+
+/// Reads from `reader` until `buf` is full or the file ends, returning how much was read.
+fn fill(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// The hash a plan records for a file, to tell whether it's changed since.
+async fn checksum_file(path: &Path) -> Result<[u8; 32], (FailureKind, anyhow::Error)> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || checksum(&path))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|checksum| checksum.context("Failed to checksum file"))
+        .map_err(|e| (FailureKind::Unreadable, e))
+}
+
+/// `IMG_0001.CR2` -> `IMG_0001_1.CR2`
+pub fn with_suffix(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!("_{}", n));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+
+    use super::*;
+
+    /// A date source with a set answer.
+    struct Fixed(&'static str, Result<Option<&'static str>, &'static str>);
+
+    #[async_trait]
+    impl DateExtractor for Fixed {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        async fn extract(&self, _probe: &mut Probe<'_>) -> Result<Option<Date>> {
+            match self.1 {
+                Ok(date) => Ok(date.map(|date| Date::try_from(date.to_string()).unwrap())),
+                Err(e) => Err(anyhow::anyhow!(e)),
+            }
+        }
+    }
+
+    fn reader(sources: Vec<Fixed>) -> DateReader {
+        DateReader {
+            extractors: sources
+                .into_iter()
+                .map(|source| Box::new(source) as Box<dyn DateExtractor>)
+                .collect(),
+            filename_patterns: FilenamePatterns::new(&[]).unwrap(),
+            suspect_before: None,
+            tz: None,
+            assume_tz: None,
+            tz_from_gps: false,
+        }
+    }
+
+    async fn read(sources: Vec<Fixed>) -> Result<(String, &'static str)> {
+        let capture = reader(sources).read(Path::new("/nonexistent/photosort/a.jpg")).await?;
+        Ok((capture.date.to_string(), capture.extractor))
+    }
+
+    #[tokio::test]
+    async fn first_source_with_a_date_wins() {
+        let sources = vec![
+            Fixed("xmp", Ok(None)),
+            Fixed("exif", Ok(Some("2020:01:02 03:04:05"))),
+            Fixed("mtime", Ok(Some("2021:01:01 00:00:00"))),
+        ];
+        assert_eq!(read(sources).await.unwrap(), ("2020-01-02T03:04:05".into(), "exif"));
+    }
+
+    #[tokio::test]
+    async fn failing_sources_are_passed_over() {
+        let sources = vec![
+            Fixed("exiftool", Err("exiftool isn't installed")),
+            Fixed("mtime", Ok(Some("2021:01:01 00:00:00"))),
+        ];
+        assert_eq!(read(sources).await.unwrap(), ("2021-01-01T00:00:00".into(), "mtime"));
+    }
+
+    #[tokio::test]
+    async fn every_source_failing_says_why() {
+        let sources =
+            vec![Fixed("exiftool", Err("exiftool isn't installed")), Fixed("xmp", Ok(None))];
+        let e = format!("{:#}", read(sources).await.unwrap_err());
+        assert!(e.contains("exiftool: exiftool isn't installed"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn implausible_dates_are_passed_over() {
+        let sources = vec![
+            Fixed("exif", Ok(Some("1800:01:01 00:00:00"))),
+            Fixed("filename", Ok(Some("2019:05:06 00:00:00"))),
+        ];
+        assert_eq!(read(sources).await.unwrap(), ("2019-05-06T00:00:00".into(), "filename"));
+        let e = read(vec![Fixed("exif", Ok(Some("1800:01:01 00:00:00")))]).await.unwrap_err();
+        assert!(format!("{:#}", e).contains("implausible"));
+    }
+
+    #[tokio::test]
+    async fn stages_keep_order_and_hold_their_input_back() {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let counter = pulled.clone();
+        let items = stream::iter(0..100).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut results = stage(items, 4, |n| async move { n * 2 });
+        assert_eq!(results.next().await.unwrap().unwrap(), 0);
+        tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+        // The one taken, and the channel's worth after it.
+        assert!(pulled.load(Ordering::SeqCst) <= 5);
+        let rest: Vec<_> = results.map(Result::unwrap).collect().await;
+        assert_eq!(rest, (1..100).map(|n| n * 2).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn same_contents_compares_every_byte() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, contents: &[u8]| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+        let big = vec![7; 200_000];
+        let mut last_differs = big.clone();
+        *last_differs.last_mut().unwrap() = 8;
+        let (a, b) = (file("a", &big), file("b", &big));
+        let (c, d) = (file("c", &last_differs), file("d", &big[1..]));
+        assert!(same_contents(&a, &b).await.unwrap());
+        assert!(!same_contents(&a, &c).await.unwrap());
+        assert!(!same_contents(&a, &d).await.unwrap());
+        assert!(same_contents(&a, &dir.path().join("missing")).await.is_err());
+    }
+}
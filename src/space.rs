@@ -0,0 +1,46 @@
+use std::io;
+use std::path::Path;
+
+/// Bytes free to an unprivileged user on the filesystem `path` is on, going by the nearest of
+/// its parents that exists when it doesn't yet. None where that can't be found out.
+pub fn available(path: &Path) -> io::Result<Option<u64>> {
+    let existing = path
+        .ancestors()
+        .map(|dir| if dir.as_os_str().is_empty() { Path::new(".") } else { dir })
+        .find(|dir| dir.exists());
+    match existing {
+        Some(dir) => sys::available(dir),
+        None => Ok(None),
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    pub fn available(path: &Path) -> io::Result<Option<u64>> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        // Safety: statvfs is plain data, all zeroes until statvfs fills it in from a
+        // NUL-terminated path.
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[allow(clippy::useless_conversion)]
+        let bytes = u64::from(stats.f_bavail).saturating_mul(u64::from(stats.f_frsize));
+        Ok(Some(bytes))
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    pub fn available(_path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
@@ -0,0 +1,501 @@
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::cli::MonthLocale;
+use crate::{Camera, Date};
+
+/// Layout photosort has always used: `~/annex/photos/2020/02/01/IMG_0001.CR2`.
+pub const DEFAULT_TEMPLATE: &str = "{year}/{month}/{day}/{filename}";
+
+/// Keeps the file's original name.
+pub const DEFAULT_NAME_TEMPLATE: &str = "{original}";
+
+/// What camera variables like `{camera_model}` become for files that don't say.
+const UNKNOWN: &str = "Unknown";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    #[error("Template is empty")]
+    Empty,
+    #[error("Template must be a relative path, found {0:?}")]
+    NotRelative(String),
+    #[error("Template may not contain '..' path components")]
+    ParentComponent,
+    #[error("Template must use {{filename}} or {{original}}, or every file taken in the same \
+             period would be given the same name")]
+    NoFileName,
+    #[error("Template rendered '..' as a path component, which would lead out of the library")]
+    RenderedParent,
+    #[error("File name template rendered {0:?}, which can't be a file name")]
+    RenderedNoName(String),
+    #[error("Unknown template variable {{{0}}}")]
+    UnknownVariable(String),
+    #[error("Unclosed '{{' at position {0} (use '{{{{' for a literal brace)")]
+    UnclosedBrace(usize),
+    #[error("Unmatched '}}' at position {0} (use '}}}}' for a literal brace)")]
+    UnmatchedBrace(usize),
+    #[error("File name template may not contain path separators")]
+    NameSeparator,
+    #[error("File name template can't use {{filename}}, which is what it produces (use {{original}})")]
+    NameSelfReference,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Var {
+    Year,
+    Month,
+    MonthName,
+    Day,
+    IsoYear,
+    IsoWeek,
+    Hour,
+    Minute,
+    Second,
+    /// Fraction of a second, for telling burst frames apart.
+    Subsec,
+    CameraMake,
+    CameraModel,
+    Lens,
+    /// In millimetres, without the unit.
+    FocalLength,
+    Iso,
+    /// The name produced by the file name template.
+    Filename,
+    /// The file's name before sorting.
+    Original,
+}
+
+impl Var {
+    fn from_name(name: &str) -> Option<Var> {
+        match name {
+            "year" => Some(Var::Year),
+            "month" => Some(Var::Month),
+            "month_name" => Some(Var::MonthName),
+            "day" => Some(Var::Day),
+            "iso_year" => Some(Var::IsoYear),
+            "iso_week" => Some(Var::IsoWeek),
+            "hour" => Some(Var::Hour),
+            "minute" => Some(Var::Minute),
+            "second" => Some(Var::Second),
+            "subsec" => Some(Var::Subsec),
+            "camera_make" => Some(Var::CameraMake),
+            "camera_model" => Some(Var::CameraModel),
+            "lens" => Some(Var::Lens),
+            "focal_length" => Some(Var::FocalLength),
+            "iso" => Some(Var::Iso),
+            "filename" => Some(Var::Filename),
+            "original" => Some(Var::Original),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Var(Var),
+}
+
+/// Values a template can refer to for a single file.
+pub struct Vars<'a> {
+    pub date: &'a Date,
+    pub camera: &'a Camera,
+    /// How `{month}` is written, which depends on `--month-style`.
+    pub month: &'a str,
+    pub month_name: &'a str,
+    /// Output of the file name template; empty while rendering that template.
+    pub filename: &'a OsStr,
+    /// Kept as an `OsStr` so names that aren't valid UTF-8 come through unchanged.
+    pub original: &'a OsStr,
+}
+
+/// A destination layout relative to the library root, e.g. `{year}/{month}/{day}/{filename}`.
+///
+/// Variables are written in braces; `{{` and `}}` produce literal braces.
+#[derive(Debug, Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(src: &str) -> Result<Self, TemplateError> {
+        let template = Template::parse(src)?;
+        let names = [Segment::Var(Var::Filename), Segment::Var(Var::Original)];
+        if !template.segments.iter().any(|segment| names.contains(segment)) {
+            return Err(TemplateError::NoFileName);
+        }
+        Ok(template)
+    }
+}
+
+impl Template {
+    /// Parses either kind of template, leaving what it has to contain to the caller.
+    fn parse(src: &str) -> Result<Template, TemplateError> {
+        if src.is_empty() {
+            return Err(TemplateError::Empty);
+        }
+        if src.starts_with(['/', '\\']) || has_drive_prefix(src) {
+            return Err(TemplateError::NotRelative(src.into()));
+        }
+        if src.split(['/', '\\']).any(|component| component == "..") {
+            return Err(TemplateError::ParentComponent);
+        }
+
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = src.char_indices().peekable();
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|&(_, c)| c) == Some('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => name.push(c),
+                            None => return Err(TemplateError::UnclosedBrace(pos)),
+                        }
+                    }
+                    let var = Var::from_name(&name).ok_or(TemplateError::UnknownVariable(name))?;
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Var(var));
+                }
+                '}' if chars.peek().map(|&(_, c)| c) == Some('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => return Err(TemplateError::UnmatchedBrace(pos)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Template { segments })
+    }
+
+    /// Parses a template for the file name alone, e.g. `{year}{month}{day}_{original}`.
+    pub fn parse_name(src: &str) -> Result<Template, TemplateError> {
+        if src.contains(['/', '\\']) {
+            return Err(TemplateError::NameSeparator);
+        }
+        let template = Template::parse(src)?;
+        if template.segments.contains(&Segment::Var(Var::Filename)) {
+            return Err(TemplateError::NameSelfReference);
+        }
+        Ok(template)
+    }
+
+    /// Renders a file name template. Fails if the name comes out empty, `.` or `..`, as one made
+    /// of nothing but `{subsec}` does for a photo without sub-second times, since the file would
+    /// then take the place of the directory it was meant to go in.
+    pub fn render_name(&self, vars: &Vars) -> Result<OsString, TemplateError> {
+        let mut out = OsString::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => out.push(s),
+                Segment::Var(var) => out.push(var.value(vars)),
+            }
+        }
+        if out.is_empty() || out == "." || out == ".." {
+            return Err(TemplateError::RenderedNoName(out.to_string_lossy().into_owned()));
+        }
+        Ok(out)
+    }
+
+    /// Renders a destination template into a relative path. Either `/` or `\` in the template
+    /// separates directories, so one template works on every platform, while separators that
+    /// come from a variable's value (such as an odd original file name) stay part of the name.
+    /// Fails if a component comes out as `..`, as one made of nothing but a camera model could.
+    pub fn render_path(&self, vars: &Vars) -> Result<PathBuf, TemplateError> {
+        let mut path = PathBuf::new();
+        let mut component = OsString::new();
+        let push = |path: &mut PathBuf, component: OsString| {
+            if component == ".." {
+                return Err(TemplateError::RenderedParent);
+            }
+            path.push(component);
+            Ok(())
+        };
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => {
+                    let mut parts = s.split(['/', '\\']);
+                    component.push(parts.next().unwrap_or_default());
+                    for part in parts {
+                        if !component.is_empty() {
+                            push(&mut path, std::mem::take(&mut component))?;
+                        }
+                        component.push(part);
+                    }
+                }
+                Segment::Var(var) => component.push(var.value(vars)),
+            }
+        }
+        if !component.is_empty() {
+            push(&mut path, component)?;
+        }
+        Ok(path)
+    }
+}
+
+impl Var {
+    fn value<'a>(self, vars: &Vars<'a>) -> Cow<'a, OsStr> {
+        let two_digits = |n: u32| OsString::from(format!("{:02}", n)).into();
+        let s = match self {
+            Var::Year => return OsString::from(format!("{:04}", vars.date.year())).into(),
+            Var::Month => vars.month,
+            Var::MonthName => vars.month_name,
+            Var::Day => return two_digits(vars.date.day()),
+            Var::IsoYear => return OsString::from(vars.date.iso_week().0.to_string()).into(),
+            Var::IsoWeek => return two_digits(vars.date.iso_week().1),
+            Var::Hour => return two_digits(vars.date.hour()),
+            Var::Minute => return two_digits(vars.date.minute()),
+            Var::Second => return two_digits(vars.date.second()),
+            Var::Subsec => vars.date.subsec(),
+            Var::CameraMake => return camera_value(vars.camera.make.as_deref()),
+            Var::CameraModel => return camera_value(vars.camera.model.as_deref()),
+            Var::Lens => return camera_value(vars.camera.lens.as_deref()),
+            Var::FocalLength => {
+                let mm = vars.camera.focal_length.map(format_focal_length);
+                return camera_value(mm.as_deref()).into_owned().into();
+            }
+            Var::Iso => {
+                let iso = vars.camera.iso.map(|iso| iso.to_string());
+                return camera_value(iso.as_deref()).into_owned().into();
+            }
+            Var::Filename => return vars.filename.into(),
+            Var::Original => return vars.original.into(),
+        };
+        OsStr::new(s).into()
+    }
+}
+
+/// What the camera recorded, with path separators replaced so a model like `E-M1/II` doesn't
+/// start a new directory. Files that don't say get `Unknown`.
+fn camera_value(value: Option<&str>) -> Cow<'_, OsStr> {
+    match value {
+        Some(value) if value.contains(['/', '\\']) => {
+            OsString::from(value.replace(['/', '\\'], "-")).into()
+        }
+        Some(value) => OsStr::new(value).into(),
+        None => OsStr::new(UNKNOWN).into(),
+    }
+}
+
+/// Focal length in millimetres, with a decimal place only when it isn't whole: `50`, `4.2`.
+fn format_focal_length(mm: f64) -> String {
+    if mm.fract() == 0.0 {
+        format!("{}", mm)
+    } else {
+        format!("{:.1}", mm)
+    }
+}
+
+/// `C:` or `C:\photos`, which would escape the library on Windows.
+fn has_drive_prefix(src: &str) -> bool {
+    let bytes = src.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+/// Name of `month` (1-12) in the given language, capitalized as it would be at the start of a
+/// sentence.
+pub fn month_name(month: u32, locale: MonthLocale) -> &'static str {
+    const EN: [&str; 12] = [
+        "January", "February", "March", "April", "May", "June", "July", "August", "September",
+        "October", "November", "December",
+    ];
+    const DE: [&str; 12] = [
+        "Januar", "Februar", "März", "April", "Mai", "Juni", "Juli", "August", "September",
+        "Oktober", "November", "Dezember",
+    ];
+    const FR: [&str; 12] = [
+        "Janvier", "Février", "Mars", "Avril", "Mai", "Juin", "Juillet", "Août", "Septembre",
+        "Octobre", "Novembre", "Décembre",
+    ];
+    const ES: [&str; 12] = [
+        "Enero", "Febrero", "Marzo", "Abril", "Mayo", "Junio", "Julio", "Agosto", "Septiembre",
+        "Octubre", "Noviembre", "Diciembre",
+    ];
+    const IT: [&str; 12] = [
+        "Gennaio", "Febbraio", "Marzo", "Aprile", "Maggio", "Giugno", "Luglio", "Agosto",
+        "Settembre", "Ottobre", "Novembre", "Dicembre",
+    ];
+    const NL: [&str; 12] = [
+        "Januari", "Februari", "Maart", "April", "Mei", "Juni", "Juli", "Augustus", "September",
+        "Oktober", "November", "December",
+    ];
+    const PT: [&str; 12] = [
+        "Janeiro", "Fevereiro", "Março", "Abril", "Maio", "Junho", "Julho", "Agosto", "Setembro",
+        "Outubro", "Novembro", "Dezembro",
+    ];
+    let names = match locale {
+        MonthLocale::En => &EN,
+        MonthLocale::De => &DE,
+        MonthLocale::Fr => &FR,
+        MonthLocale::Es => &ES,
+        MonthLocale::It => &IT,
+        MonthLocale::Nl => &NL,
+        MonthLocale::Pt => &PT,
+    };
+    names[(month.clamp(1, 12) - 1) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::path::Path;
+
+    use super::*;
+
+    /// Renders `template` for a photo taken at 2020-01-02 03:04:05 with `camera`, and named
+    /// `original` both before and after sorting.
+    fn render(template: &str, camera: &Camera, original: &str) -> Result<PathBuf, TemplateError> {
+        let date = Date::try_from("2020:01:02 03:04:05".to_string()).unwrap();
+        let original = OsStr::new(original);
+        let vars = Vars {
+            date: &date,
+            camera,
+            month: "01",
+            month_name: "January",
+            filename: original,
+            original,
+        };
+        template.parse::<Template>()?.render_path(&vars)
+    }
+
+    fn parse(template: &str) -> Result<Template, TemplateError> {
+        template.parse()
+    }
+
+    #[test]
+    fn parse_refuses_malformed_templates() {
+        let errors = [
+            ("", TemplateError::Empty),
+            ("/photos/{filename}", TemplateError::NotRelative("/photos/{filename}".into())),
+            ("\\{filename}", TemplateError::NotRelative("\\{filename}".into())),
+            ("C:{filename}", TemplateError::NotRelative("C:{filename}".into())),
+            ("{year}/../{filename}", TemplateError::ParentComponent),
+            ("{year}\\..\\{filename}", TemplateError::ParentComponent),
+            ("{yaer}/{filename}", TemplateError::UnknownVariable("yaer".into())),
+            ("{filename}/{year", TemplateError::UnclosedBrace(11)),
+            ("é{year", TemplateError::UnclosedBrace(2)),
+            ("{filename}}x", TemplateError::UnmatchedBrace(10)),
+        ];
+        for (template, error) in errors {
+            assert_eq!(parse(template).err(), Some(error), "{:?}", template);
+        }
+        let err = Template::parse_name("{year}/{original}").err();
+        assert_eq!(err, Some(TemplateError::NameSeparator));
+        let err = Template::parse_name("{year}_{filename}").err();
+        assert_eq!(err, Some(TemplateError::NameSelfReference));
+    }
+
+    #[test]
+    fn render_path_splits_at_either_separator() {
+        let camera = Camera::default();
+        let path = render(DEFAULT_TEMPLATE, &camera, "IMG_0001.JPG").unwrap();
+        assert_eq!(path, Path::new("2020/01/02/IMG_0001.JPG"));
+        // Empty components are dropped, and doubled braces are literal.
+        let path = render("{year}\\{{{month}}}//{filename}", &camera, "IMG_0001.JPG").unwrap();
+        assert_eq!(path, Path::new("2020/{01}/IMG_0001.JPG"));
+        // A separator in a variable's value doesn't start a directory.
+        let path = render("{year}/{original}", &camera, "a\\b.jpg").unwrap();
+        assert_eq!(path.components().count(), 2);
+    }
+
+    #[test]
+    fn render_path_fills_in_every_variable() {
+        let camera = Camera {
+            make: Some("OLYMPUS".into()),
+            model: Some("E-M1/II".into()),
+            lens: None,
+            focal_length: Some(4.2),
+            iso: Some(200),
+        };
+        let template = "{camera_make}/{camera_model}/{lens}_{focal_length}mm_{iso}/{filename}";
+        let path = render(template, &camera, "P1.ORF").unwrap();
+        assert_eq!(path, Path::new("OLYMPUS/E-M1-II/Unknown_4.2mm_200/P1.ORF"));
+        let template = concat!(
+            "{iso_year}/W{iso_week}/{month_name}/",
+            "{hour}{minute}{second}{subsec}_{filename}"
+        );
+        let path = render(template, &Camera::default(), "P1.ORF").unwrap();
+        assert_eq!(path, Path::new("2020/W01/January/030405_P1.ORF"));
+        let camera = Camera { focal_length: Some(50.0), ..Camera::default() };
+        let path = render("{focal_length}/{iso}/{filename}", &camera, "P1.ORF").unwrap();
+        assert_eq!(path, Path::new("50/Unknown/P1.ORF"));
+    }
+
+    #[test]
+    fn render_keeps_the_name_in_one_piece() {
+        let date = Date::try_from("2020:01:02 03:04:05".to_string()).unwrap();
+        let vars = Vars {
+            date: &date,
+            camera: &Camera::default(),
+            month: "01",
+            month_name: "January",
+            filename: OsStr::new(""),
+            original: OsStr::new("IMG_0001.JPG"),
+        };
+        let template = Template::parse_name("{year}{month}{day}_{{{original}}}").unwrap();
+        assert_eq!(template.render_name(&vars).unwrap(), "20200102_{IMG_0001.JPG}");
+    }
+
+    #[test]
+    fn rendered_names_that_arent_names_are_refused() {
+        let date = Date::try_from("2020:01:02 03:04:05".to_string()).unwrap();
+        let camera = Camera { model: Some(".".into()), ..Camera::default() };
+        let vars = Vars {
+            date: &date,
+            camera: &camera,
+            month: "01",
+            month_name: "January",
+            filename: OsStr::new(""),
+            original: OsStr::new("IMG_0001.JPG"),
+        };
+        let render = |src| Template::parse_name(src).unwrap().render_name(&vars);
+        assert_eq!(render("{subsec}").err(), Some(TemplateError::RenderedNoName("".into())));
+        assert_eq!(render("{camera_model}").err(), Some(TemplateError::RenderedNoName(".".into())));
+        let err = render("{camera_model}.").err();
+        assert_eq!(err, Some(TemplateError::RenderedNoName("..".into())));
+        assert_eq!(render("{subsec}{original}").unwrap(), "IMG_0001.JPG");
+    }
+
+    #[test]
+    fn month_names_stay_in_range() {
+        assert_eq!(month_name(2, MonthLocale::De), "Februar");
+        assert_eq!(month_name(0, MonthLocale::En), "January");
+        assert_eq!(month_name(13, MonthLocale::Fr), "Décembre");
+    }
+
+    #[test]
+    fn path_templates_must_name_the_file() {
+        assert_eq!(parse("{year}/{month}").err(), Some(TemplateError::NoFileName));
+        assert!(parse("{year}/{original}").is_ok());
+        assert!(parse("{year}/{filename}").is_ok());
+        // The file name template is what {filename} stands for, so it needn't.
+        assert!(Template::parse_name("{year}{month}{day}_{hour}{minute}{second}").is_ok());
+    }
+
+    #[test]
+    fn rendered_parent_components_are_refused() {
+        let camera = Camera { model: Some("..".into()), ..Camera::default() };
+        let err = render("{camera_model}/{filename}", &camera, "IMG_0001.JPG").err();
+        assert_eq!(err, Some(TemplateError::RenderedParent));
+        let path = render("{year}/{camera_model}.x/{filename}", &camera, "IMG_0001.JPG").unwrap();
+        assert_eq!(path, Path::new("2020/...x/IMG_0001.JPG"));
+    }
+}
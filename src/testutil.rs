@@ -0,0 +1,189 @@
+// Small files built byte by byte for the parsers' tests, so they don't need sample photos.
+
+/// One IFD of a TIFF structure to build, and the one chained after it.
+#[derive(Default)]
+pub struct Ifd {
+    pub entries: Vec<(u16, Value)>,
+    pub next: Option<Box<Ifd>>,
+}
+
+pub enum Value {
+    Byte(Vec<u8>),
+    Ascii(&'static str),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    /// Numerators and denominators.
+    Rational(Vec<(u32, u32)>),
+    /// Pointers to IFDs of their own, like the Exif IFD or SubIFDs.
+    Ifds(Vec<Ifd>),
+}
+
+impl Ifd {
+    pub fn new(entries: Vec<(u16, Value)>) -> Ifd {
+        Ifd { entries, next: None }
+    }
+}
+
+/// A TIFF structure with `ifd0` and everything it points at, in either byte order.
+pub fn tiff(ifd0: &Ifd, big_endian: bool) -> Vec<u8> {
+    let mut writer = Writer { data: Vec::new(), big_endian };
+    writer.data.extend_from_slice(if big_endian { b"MM\0*" } else { b"II*\0" });
+    writer.data.extend_from_slice(&[0; 4]);
+    let offset = writer.ifd(ifd0);
+    writer.put_u32(4, offset);
+    writer.data
+}
+
+/// A TIFF structure whose IFD0 names the camera and points at an Exif IFD with `date` as its
+/// DateTimeOriginal.
+pub fn exif_tiff(date: &'static str) -> Vec<u8> {
+    let exif = Ifd::new(vec![(0x9003, Value::Ascii(date))]);
+    let ifd0 = Ifd::new(vec![
+        (0x010f, Value::Ascii("Acme")),
+        (0x0110, Value::Ascii("Model 1")),
+        (0x8769, Value::Ifds(vec![exif])),
+    ]);
+    tiff(&ifd0, false)
+}
+
+/// A TIFF structure laid out the way A7-series bodies write ARW files, trimmed to its IFDs: IFD0
+/// describes the preview JPEG and points at the raw data in a SubIFD and at the Exif IFD holding
+/// `date`, and IFD1, chained after it, holds the thumbnail. The image data itself is left out, so
+/// the offsets pointing at it lead past the end. `xmp` pads IFD0 out with an XMP packet of that
+/// many bytes ahead of the IFDs it points at.
+pub fn arw(model: &'static str, date: &'static str, xmp: usize) -> Vec<u8> {
+    let raw = Ifd::new(vec![
+        (0x00fe, Value::Long(vec![0])),
+        (0x0100, Value::Long(vec![6048])),
+        (0x0101, Value::Long(vec![4024])),
+        (0x0103, Value::Short(vec![32767])),
+        (0x0106, Value::Short(vec![32803])),
+        (0x0111, Value::Long(vec![0x0150_0000])),
+        (0x0117, Value::Long(vec![24_330_240])),
+    ]);
+    let exif = Ifd::new(vec![(0x9003, Value::Ascii(date))]);
+    let thumbnail = Ifd::new(vec![
+        (0x0103, Value::Short(vec![6])),
+        (0x0201, Value::Long(vec![0x0001_2000])),
+        (0x0202, Value::Long(vec![9_000])),
+    ]);
+    let mut ifd0 = Ifd::new(vec![
+        (0x00fe, Value::Long(vec![1])),
+        (0x0103, Value::Short(vec![6])),
+        (0x010f, Value::Ascii("SONY")),
+        (0x0110, Value::Ascii(model)),
+        (0x0112, Value::Short(vec![1])),
+        (0x014a, Value::Ifds(vec![raw])),
+        (0x0201, Value::Long(vec![0x0002_0000])),
+        (0x0202, Value::Long(vec![400_000])),
+        (0x02bc, Value::Byte(vec![b' '; xmp])),
+        (0x8769, Value::Ifds(vec![exif])),
+    ]);
+    ifd0.next = Some(Box::new(thumbnail));
+    tiff(&ifd0, false)
+}
+
+/// A JPEG holding `tiff` in its APP1 segment, with an empty scan after it.
+pub fn jpeg(tiff: &[u8]) -> Vec<u8> {
+    let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+    jpeg.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+    jpeg.extend_from_slice(b"Exif\0\0");
+    jpeg.extend_from_slice(tiff);
+    jpeg.extend_from_slice(&[0xff, 0xda, 0x00, 0x02, 0xff, 0xd9]);
+    jpeg
+}
+
+struct Writer {
+    data: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Writer {
+    fn u16_bytes(&self, value: u16) -> [u8; 2] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn u32_bytes(&self, value: u32) -> [u8; 4] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn put_u16(&mut self, at: usize, value: u16) {
+        let bytes = self.u16_bytes(value);
+        self.data[at..at + 2].copy_from_slice(&bytes);
+    }
+
+    fn put_u32(&mut self, at: usize, value: u32) {
+        let bytes = self.u32_bytes(value);
+        self.data[at..at + 4].copy_from_slice(&bytes);
+    }
+
+    /// Appends `bytes` on a word boundary, returning where they start.
+    fn append(&mut self, bytes: &[u8]) -> u32 {
+        if self.data.len() % 2 == 1 {
+            self.data.push(0);
+        }
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(bytes);
+        offset
+    }
+
+    /// Writes `ifd` and everything it points at, returning its offset.
+    fn ifd(&mut self, ifd: &Ifd) -> u32 {
+        let start = self.append(&vec![0; 2 + ifd.entries.len() * 12 + 4]) as usize;
+        self.put_u16(start, ifd.entries.len() as u16);
+        for (i, (tag, value)) in ifd.entries.iter().enumerate() {
+            let pos = start + 2 + i * 12;
+            let (kind, count, bytes) = match value {
+                Value::Byte(values) => (1, values.len(), values.clone()),
+                Value::Ascii(text) => {
+                    let mut bytes = text.as_bytes().to_vec();
+                    bytes.push(0);
+                    (2, bytes.len(), bytes)
+                }
+                Value::Short(values) => {
+                    let bytes = values.iter().flat_map(|&v| self.u16_bytes(v)).collect();
+                    (3, values.len(), bytes)
+                }
+                Value::Long(values) => {
+                    let bytes = values.iter().flat_map(|&v| self.u32_bytes(v)).collect();
+                    (4, values.len(), bytes)
+                }
+                Value::Rational(values) => {
+                    let bytes = values
+                        .iter()
+                        .flat_map(|&(num, den)| [self.u32_bytes(num), self.u32_bytes(den)])
+                        .flatten()
+                        .collect();
+                    (5, values.len(), bytes)
+                }
+                Value::Ifds(ifds) => {
+                    let offsets: Vec<u32> = ifds.iter().map(|ifd| self.ifd(ifd)).collect();
+                    let bytes = offsets.iter().flat_map(|&o| self.u32_bytes(o)).collect();
+                    (4, offsets.len(), bytes)
+                }
+            };
+            self.put_u16(pos, *tag);
+            self.put_u16(pos + 2, kind);
+            self.put_u32(pos + 4, count as u32);
+            if bytes.len() <= 4 {
+                self.data[pos + 8..pos + 8 + bytes.len()].copy_from_slice(&bytes);
+            } else {
+                let offset = self.append(&bytes);
+                self.put_u32(pos + 8, offset);
+            }
+        }
+        if let Some(next) = &ifd.next {
+            let offset = self.ifd(next);
+            self.put_u32(start + 2 + ifd.entries.len() * 12, offset);
+        }
+        start as u32
+    }
+}
@@ -0,0 +1,62 @@
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::cli::ThumbCommand;
+use crate::{bmff, exif, mmap, FileParseError};
+
+#[derive(Error, Debug)]
+pub enum ThumbError {
+    #[error("Could not read {}: {1}", .0.display())]
+    Read(PathBuf, FileParseError),
+    #[error("{} is not a RAW or HEIF file", .0.display())]
+    Unsupported(PathBuf),
+    #[error("{} has no embedded JPEG preview", .0.display())]
+    NoPreview(PathBuf),
+    #[error("Could not write {}: {1}", .0.display())]
+    Write(PathBuf, std::io::Error),
+    #[error("Reading task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// Writes the largest JPEG preview embedded in `cmd.input` to `cmd.output`.
+pub async fn thumb(cmd: &ThumbCommand) -> Result<(), ThumbError> {
+    let (input, output) = (cmd.input.clone(), cmd.output.clone());
+    tokio::task::spawn_blocking(move || {
+        let map = mmap::map(&input).map_err(|e| ThumbError::Read(input.clone(), e.into()))?;
+        let preview = find_preview(&input, &map)?;
+        std::fs::write(&output, preview).map_err(|e| ThumbError::Write(output, e))
+    })
+    .await?
+}
+
+/// The largest JPEG preview in the RAW or HEIF file `data`. RAF files point at theirs from the
+/// header; TIFF-based RAW files keep theirs in IFDs, and HEIF images can have a thumbnail in the
+/// EXIF of their Exif item. (HEIF's own thumbnail items are HEVC, not JPEG, so aren't used.)
+fn find_preview<'a>(path: &Path, data: &'a [u8]) -> Result<&'a [u8], ThumbError> {
+    let read_error = |e: FileParseError| ThumbError::Read(path.to_path_buf(), e);
+    let no_preview = || ThumbError::NoPreview(path.to_path_buf());
+    if data.starts_with(exif::RAF_MAGIC) {
+        let offset = exif::raf_jpeg_offset(data).map_err(|e| read_error(e.into()))?;
+        let len = exif::raf_jpeg_len(data).map_err(|e| read_error(e.into()))?;
+        return (offset as usize)
+            .checked_add(len as usize)
+            .and_then(|end| data.get(offset as usize..end))
+            .filter(|jpeg| jpeg.starts_with(&exif::JPEG_SOI))
+            .ok_or_else(no_preview);
+    }
+    let tiff = if exif::is_tiff(data) {
+        data
+    } else if bmff::has_brand(data, bmff::HEIF_BRANDS) {
+        mmap::heif_exif_tiff(data).map_err(read_error)?
+    } else {
+        return Err(ThumbError::Unsupported(path.to_path_buf()));
+    };
+    exif::previews(tiff)
+        .map_err(|e| read_error(e.into()))?
+        .into_iter()
+        .filter_map(|(offset, len)| tiff.get(offset..offset.checked_add(len)?))
+        .filter(|jpeg| jpeg.starts_with(&exif::JPEG_SOI) && !exif::is_lossless_jpeg(jpeg))
+        .max_by_key(|jpeg| jpeg.len())
+        .ok_or_else(no_preview)
+}
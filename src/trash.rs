@@ -0,0 +1,103 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::sort::with_suffix;
+
+/// Directory in the library that displaced files go to when the desktop's trash can't take
+/// them. Never walked, so they aren't sorted back into the library.
+pub const LIBRARY_TRASH: &str = ".photosort-trash";
+
+/// Moves `path`, a file in `library` about to be replaced, to the trash so it can be got back,
+/// returning where it went. That's the XDG trash where there is one on the same filesystem, and
+/// otherwise the same place under `.photosort-trash/` in the library.
+pub fn trash(library: &Path, path: &Path) -> io::Result<PathBuf> {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    if let Some(trashed) = xdg_trash(path)? {
+        return Ok(trashed);
+    }
+    let relative = match path.strip_prefix(library) {
+        Ok(relative) => relative,
+        Err(_) => Path::new(path.file_name().unwrap_or_default()),
+    };
+    let dest = library.join(LIBRARY_TRASH).join(relative);
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut trashed = dest.clone();
+    for n in 1.. {
+        if std::fs::symlink_metadata(&trashed).is_err() {
+            break;
+        }
+        trashed = with_suffix(&dest, n);
+    }
+    std::fs::rename(path, &trashed)?;
+    Ok(trashed)
+}
+
+/// Moves `path` to the home trash as the freedesktop.org trash spec lays it out, with a
+/// `.trashinfo` file saying where it came from so file managers can restore it. Returns None
+/// when there's no home trash or it's on another filesystem.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn xdg_trash(path: &Path) -> io::Result<Option<PathBuf>> {
+    let trash = match dirs::data_dir() {
+        Some(data) => data.join("Trash"),
+        None => return Ok(None),
+    };
+    let (files, info) = (trash.join("files"), trash.join("info"));
+    if std::fs::create_dir_all(&files).and_then(|_| std::fs::create_dir_all(&info)).is_err() {
+        return Ok(None);
+    }
+    let original = std::fs::canonicalize(path)?;
+    let name = Path::new(original.file_name().unwrap_or_default());
+    let mut n = 0;
+    loop {
+        let trashed = if n == 0 { files.join(name) } else { with_suffix(&files.join(name), n) };
+        n += 1;
+        let mut info_name = trashed.file_name().unwrap_or_default().to_os_string();
+        info_name.push(".trashinfo");
+        let info_path = info.join(info_name);
+        // Creating the info file claims the name, as the spec has it.
+        let mut info_file =
+            match std::fs::File::options().write(true).create_new(true).open(&info_path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                result => result?,
+            };
+        if std::fs::symlink_metadata(&trashed).is_ok() {
+            let _ = std::fs::remove_file(&info_path);
+            continue;
+        }
+        let written = write!(
+            info_file,
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            url_escape(&original),
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S"),
+        );
+        match written.and_then(|_| std::fs::rename(path, &trashed)) {
+            Ok(()) => return Ok(Some(trashed)),
+            Err(e) => {
+                let _ = std::fs::remove_file(&info_path);
+                return match e.kind() {
+                    io::ErrorKind::CrossesDevices => Ok(None),
+                    _ => Err(e),
+                };
+            }
+        }
+    }
+}
+
+/// Percent-encodes `path` for a `.trashinfo` file's `Path=` line.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn url_escape(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut escaped = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                escaped.push(byte as char)
+            }
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
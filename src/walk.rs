@@ -0,0 +1,512 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ffi::OsString;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc::Sender;
+use futures::executor::block_on;
+use futures::SinkExt;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{ParallelVisitor, ParallelVisitorBuilder, WalkBuilder, WalkState};
+use thiserror::Error;
+
+use crate::archive::ArchiveError;
+use crate::cli::TraversalOptions;
+use crate::trash;
+
+/// Per-directory ignore file, using gitignore syntax.
+pub const IGNORE_FILENAME: &str = ".photosortignore";
+
+#[derive(Error, Debug)]
+pub enum WalkError {
+    #[error("{0}")]
+    Walk(#[from] ignore::Error),
+    #[error("Invalid glob pattern {0:?}: {1}")]
+    Pattern(String, glob::PatternError),
+    #[error("{0}")]
+    Glob(#[from] glob::GlobError),
+    #[error("No files match {0:?}")]
+    NoMatches(String),
+    #[error("Invalid exclude pattern: {0}")]
+    Exclude(ignore::Error),
+    #[error(transparent)]
+    Archive(#[from] ArchiveError),
+}
+
+/// Extensions of the photos and videos photosort knows how to read a date out of.
+pub const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "3gp", "arw", "avi", "avif", "cr2", "dng", "heic", "heif", "jpg", "jpeg", "m2ts", "m4v", "mov",
+    "mp4", "mts", "nef", "orf", "png", "raf", "rw2", "tif", "tiff", "webp",
+];
+
+/// Thumbnails and proxies cameras write next to the real files. They're never worth sorting.
+pub const THUMBNAIL_EXTENSIONS: &[&str] = &["thm", "lrv"];
+
+/// Files macOS and Windows leave in the directories they've shown, which aren't photos whatever
+/// they're named.
+const OS_JUNK_FILES: &[&str] = &[".DS_Store", ".localized", "Thumbs.db", "desktop.ini"];
+
+/// Directories macOS and Windows keep on the drives they've mounted, like memory cards.
+const OS_JUNK_DIRS: &[&str] = &[
+    ".Spotlight-V100",
+    ".Trashes",
+    ".fseventsd",
+    ".TemporaryItems",
+    "System Volume Information",
+    "$RECYCLE.BIN",
+];
+
+/// Whether `path` is a file the operating system left behind rather than a photo, including
+/// the AppleDouble files macOS writes next to each file on FAT memory cards (`._IMG_1234.JPG`),
+/// which have the same extension as the photo. They're never sorted, nor reported.
+pub fn is_os_junk(path: &Path) -> bool {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    name.starts_with("._") || OS_JUNK_FILES.contains(&name)
+}
+
+pub fn is_supported(path: &Path) -> bool {
+    has_extension(path, SUPPORTED_EXTENSIONS)
+}
+
+pub fn has_extension<S: AsRef<str>>(path: &Path, extensions: &[S]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.iter().any(|s| s.as_ref().eq_ignore_ascii_case(ext)))
+        .unwrap_or(false)
+}
+
+/// Finds the files to sort, applying `--exclude` patterns and `.photosortignore` files while
+/// walking directories.
+#[derive(Debug, Clone)]
+pub struct Walker {
+    excludes: Vec<String>,
+    ignore_files: bool,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    min_size: u64,
+    only: Vec<String>,
+    include_hidden: bool,
+}
+
+impl Walker {
+    pub fn new(options: &TraversalOptions) -> Result<Walker, WalkError> {
+        let walker = Walker {
+            excludes: options.exclude.clone(),
+            ignore_files: !options.no_ignore_files,
+            max_depth: options.max_depth,
+            follow_symlinks: options.follow_symlinks,
+            min_size: options.min_size.unwrap_or(0),
+            only: options.only.iter().map(|ext| ext.trim_start_matches('.').to_string()).collect(),
+            include_hidden: options.include_hidden,
+        };
+        // Check the patterns up front so a typo is reported once instead of for every directory.
+        walker.excludes_for(Path::new(".")).map_err(WalkError::Exclude)?;
+        Ok(walker)
+    }
+
+    /// Lists the files to sort for the given inputs, each of which may be a file, a directory or
+    /// a glob pattern, sending them on `tx` as they're found. Patterns are expanded here rather
+    /// than relying on the shell, since not every shell does it (Windows). Files reachable from
+    /// more than one input are listed once.
+    ///
+    /// This blocks: a send waits for room in the channel, so the walk only gets as far ahead of
+    /// sorting as the channel holds, and it stops if the receiver goes away.
+    pub fn list(&self, inputs: &[PathBuf], mut tx: Sender<Result<Vec<PathBuf>, WalkError>>) {
+        let mut roots = Vec::new();
+        for input in inputs {
+            for root in expand(input) {
+                match root {
+                    Ok(root) => roots.push((root.is_dir(), root)),
+                    Err(e) => {
+                        if block_on(tx.send(Err(e))).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+        // Files found under a directory that no other input is in or contains can't be found
+        // again, so only the rest are remembered.
+        let overlaps = |i: usize, dir: &Path| {
+            let mut others = roots.iter().enumerate().filter(|&(j, _)| j != i);
+            others.any(|(_, (_, root))| root.starts_with(dir) || dir.starts_with(root))
+        };
+        let seen = Mutex::new(HashSet::new());
+        for (i, (is_dir, root)) in roots.iter().enumerate() {
+            let remember = !is_dir || overlaps(i, root);
+            let listed = if *is_dir {
+                self.walk(root, remember.then_some(&seen), &tx)
+            } else {
+                send_files(vec![root.clone()], Some(&seen), &mut tx)
+            };
+            if !listed {
+                return;
+            }
+        }
+    }
+
+    /// Builds the `--exclude` matcher for a walk from `root`. Patterns use gitignore syntax, so
+    /// ones containing a slash are anchored to the directory being walked.
+    fn excludes_for(&self, root: &Path) -> Result<Gitignore, ignore::Error> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in &self.excludes {
+            builder.add_line(None, pattern)?;
+        }
+        builder.build()
+    }
+
+    /// Lists the files to sort under the directory `root` that pass the extension, size and
+    /// hidden file filters, returning false if the receiver went away.
+    ///
+    /// Directories are read on several threads at once, which matters for libraries of hundreds
+    /// of thousands of files, on network shares most of all. Each directory's files are sent
+    /// together, in name order, and the directories in the order a walk down the tree in name
+    /// order would reach them, however the threads divide it up. See [`Order`].
+    fn walk(
+        &self,
+        root: &Path,
+        seen: Option<&Mutex<HashSet<PathBuf>>>,
+        tx: &Sender<Result<Vec<PathBuf>, WalkError>>,
+    ) -> bool {
+        let excludes = match self.excludes_for(root) {
+            Ok(excludes) => excludes,
+            Err(e) => return block_on(tx.clone().send(Err(e.into()))).is_ok(),
+        };
+        let order = Arc::new(Mutex::new(Order {
+            unread: BTreeSet::from([root.to_path_buf()]),
+            held: BTreeMap::new(),
+            tx: tx.clone(),
+        }));
+        let walker = self.clone();
+        let found = order.clone();
+        let mut builder = WalkBuilder::new(root);
+        builder
+            .standard_filters(false)
+            .hidden(!self.include_hidden)
+            .max_depth(self.max_depth)
+            .follow_links(self.follow_symlinks)
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                let name = entry.file_name();
+                let junk = OS_JUNK_DIRS.iter().any(|dir| name == *dir);
+                let skip = is_dir && (junk || name == trash::LIBRARY_TRASH);
+                if entry.depth() == 0 {
+                    return true;
+                }
+                if skip || excludes.matched(entry.path(), is_dir).is_ignore() {
+                    return false;
+                }
+                // Files are picked up here, on the thread reading their directory, rather than
+                // being handed to the visitors one by one. See `Lister`.
+                if !is_dir && walker.wanted(entry) {
+                    FOUND.with(|found| found.borrow_mut().push(entry.path().to_path_buf()));
+                } else if is_dir {
+                    found.lock().unwrap().unread.insert(entry.path().to_path_buf());
+                }
+                is_dir
+            });
+        if self.ignore_files {
+            builder.add_custom_ignore_filename(IGNORE_FILENAME);
+        }
+        let gone = AtomicBool::new(false);
+        builder.build_parallel().visit(&mut Listers { seen, order, gone: &gone });
+        !gone.into_inner()
+    }
+
+    fn wanted(&self, entry: &ignore::DirEntry) -> bool {
+        let path = entry.path();
+        let is_file = entry.file_type().is_some_and(|t| t.is_file());
+        if !is_file || has_extension(path, THUMBNAIL_EXTENSIONS) {
+            return false;
+        }
+        let extension_ok = if self.only.is_empty() {
+            is_supported(path)
+        } else {
+            has_extension(path, &self.only)
+        };
+        let big_enough = || entry.metadata().is_ok_and(|m| m.len() >= self.min_size);
+        extension_ok && (self.min_size == 0 || big_enough())
+    }
+}
+
+thread_local! {
+    /// Files found in the directory last read on this thread, until the next one is.
+    static FOUND: RefCell<Vec<PathBuf>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Puts the directories of a walk back in order. What's found in a directory is held until
+/// every directory before it (each one before what's under it, and those in name order) has been
+/// read, so the files are listed in the same order on every run, and so are reports and which of
+/// two clashing files gets renamed.
+///
+/// A directory that hasn't been found yet is under one that hasn't been read yet, which comes
+/// before it, so nothing is sent while something before it could still turn up.
+struct Order {
+    /// Directories found but not read yet, or still being read.
+    unread: BTreeSet<PathBuf>,
+    /// What's been found in directories read before ones that come before them.
+    held: BTreeMap<PathBuf, Found>,
+    tx: Sender<Result<Vec<PathBuf>, WalkError>>,
+}
+
+/// What was found in one directory: the errors reading it, and its files.
+type Found = (Vec<WalkError>, Vec<PathBuf>);
+
+impl Order {
+    /// Records what was found in `dir`, then sends everything that's next in order. Returns
+    /// false if the receiver has gone away.
+    ///
+    /// The sends happen with the order locked, so they can't overtake each other, and a walk
+    /// that's waiting for room in the channel stops reading directories soon after.
+    fn read(&mut self, dir: PathBuf, found: Found, seen: Option<&Mutex<HashSet<PathBuf>>>) -> bool {
+        self.unread.remove(&dir);
+        self.held.insert(dir, found);
+        while let Some(next) = self.held.first_entry() {
+            if self.unread.first().is_some_and(|unread| unread < next.key()) {
+                break;
+            }
+            let (errors, files) = next.remove();
+            for e in errors {
+                if block_on(self.tx.send(Err(e))).is_err() {
+                    return false;
+                }
+            }
+            if !send_files(files, seen, &mut self.tx) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Makes a [`Lister`] for each thread of a walk.
+struct Listers<'a> {
+    seen: Option<&'a Mutex<HashSet<PathBuf>>>,
+    order: Arc<Mutex<Order>>,
+    /// Set once the receiver has gone away.
+    gone: &'a AtomicBool,
+}
+
+impl<'a> ParallelVisitorBuilder<'a> for Listers<'a> {
+    fn build(&mut self) -> Box<dyn ParallelVisitor + 'a> {
+        Box::new(Lister {
+            seen: self.seen,
+            order: self.order.clone(),
+            dir: None,
+            errors: Vec::new(),
+            gone: self.gone,
+        })
+    }
+}
+
+/// Collects the files found on one thread of a walk a directory at a time. The walk reads a
+/// directory on the same thread it's just visited the directory on, and the filter picks its
+/// files up as it goes, so by the time the next directory is visited (or the thread runs out of
+/// them) the last one's files have all been found.
+struct Lister<'a> {
+    seen: Option<&'a Mutex<HashSet<PathBuf>>>,
+    order: Arc<Mutex<Order>>,
+    /// The directory last visited on this thread, which is being read.
+    dir: Option<PathBuf>,
+    /// Errors reading `dir`, or before the first directory, the walk's root.
+    errors: Vec<WalkError>,
+    gone: &'a AtomicBool,
+}
+
+impl Lister<'_> {
+    fn flush(&mut self) -> bool {
+        let dir = match self.dir.take() {
+            Some(dir) => dir,
+            None => return true,
+        };
+        let found = FOUND.with(|found| std::mem::take(&mut *found.borrow_mut()));
+        let errors = std::mem::take(&mut self.errors);
+        self.order.lock().unwrap().read(dir, (errors, found), self.seen)
+    }
+}
+
+impl ParallelVisitor for Lister<'_> {
+    fn visit(&mut self, entry: Result<ignore::DirEntry, ignore::Error>) -> WalkState {
+        // Only directories get past the filter.
+        let sent = match entry {
+            Ok(entry) => {
+                let sent = self.flush();
+                self.dir = Some(entry.into_path());
+                sent
+            }
+            Err(e) => {
+                self.errors.push(e.into());
+                true
+            }
+        };
+        if sent {
+            WalkState::Continue
+        } else {
+            self.gone.store(true, Ordering::Relaxed);
+            WalkState::Quit
+        }
+    }
+}
+
+impl Drop for Lister<'_> {
+    fn drop(&mut self) {
+        if !self.flush() {
+            self.gone.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sends the files found in one directory in name order, leaving out OS junk and, when `seen`
+/// is given, files already listed. Returns false if the receiver has gone away.
+fn send_files(
+    mut files: Vec<PathBuf>,
+    seen: Option<&Mutex<HashSet<PathBuf>>>,
+    tx: &mut Sender<Result<Vec<PathBuf>, WalkError>>,
+) -> bool {
+    // Named on purpose or not, as by a glob like `*.JPG`.
+    files.retain(|f| !is_os_junk(f));
+    if let Some(seen) = seen {
+        let mut seen = seen.lock().unwrap();
+        files.retain(|f| seen.insert(f.clone()));
+    }
+    if files.is_empty() {
+        return true;
+    }
+    files.sort();
+    block_on(tx.send(Ok(files))).is_ok()
+}
+
+/// Reads a list of paths separated by `separator`, as written by `find -print0` (with `b'\0'`)
+/// or plain `find` (with `b'\n'`). Empty entries are skipped, and so are the carriage returns
+/// in lists saved with Windows line endings.
+pub fn read_file_list(mut reader: impl BufRead, separator: u8) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let mut entry = Vec::new();
+    while reader.read_until(separator, &mut entry)? > 0 {
+        if entry.last() == Some(&separator) {
+            entry.pop();
+        }
+        if separator == b'\n' && entry.last() == Some(&b'\r') {
+            entry.pop();
+        }
+        if !entry.is_empty() {
+            paths.push(path_from_bytes(std::mem::take(&mut entry)));
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(OsString::from(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+fn expand(input: &Path) -> Vec<Result<PathBuf, WalkError>> {
+    let pattern = match input.to_str() {
+        Some(p) if !input.exists() && p.contains(['*', '?', '[']) => p,
+        _ => return vec![Ok(input.to_path_buf())],
+    };
+    let paths = match glob::glob(pattern) {
+        Ok(paths) => paths,
+        Err(e) => return vec![Err(WalkError::Pattern(pattern.into(), e))],
+    };
+    let matches: Vec<_> = paths.map(|p| p.map_err(WalkError::from)).collect();
+    if matches.is_empty() {
+        return vec![Err(WalkError::NoMatches(pattern.into()))];
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn tree(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "photo data").unwrap();
+        }
+        dir
+    }
+
+    /// What listing `inputs` sends.
+    fn list(root: &Path, inputs: &[&str]) -> Vec<Result<Vec<PathBuf>, WalkError>> {
+        let walker = Walker::new(&TraversalOptions::default()).unwrap();
+        let inputs: Vec<PathBuf> = inputs.iter().map(|input| root.join(input)).collect();
+        let (tx, rx) = futures::channel::mpsc::channel(1);
+        std::thread::scope(|s| {
+            s.spawn(|| walker.list(&inputs, tx));
+            block_on(rx.collect())
+        })
+    }
+
+    fn files(root: &Path, names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(|name| root.join(name)).collect()
+    }
+
+    #[test]
+    fn sends_each_directory_in_name_order() {
+        let dir = tree(&["a/2.jpg", "a/1.jpg", "a/b/3.jpg", "a/b/notes.txt", "a/.4.jpg"]);
+        let batches: Vec<_> = list(dir.path(), &["a"]).into_iter().map(Result::unwrap).collect();
+        let root = dir.path();
+        assert_eq!(batches, [files(root, &["a/1.jpg", "a/2.jpg"]), files(root, &["a/b/3.jpg"])]);
+    }
+
+    #[test]
+    fn sends_directories_in_the_same_order_every_time() {
+        let dir = tree(&[
+            "a/1.jpg", "a/b/2.jpg", "a/b/c/3.jpg", "a/b/d/4.jpg", "a/e/5.jpg", "a/e/f/6.jpg",
+            "a/g/7.jpg", "a/h/8.jpg", "a/i/9.jpg", "a/i/j/10.jpg", "a/k/11.jpg", "a/l/12.jpg",
+        ]);
+        let root = dir.path();
+        let expected: Vec<_> = [
+            "a/1.jpg", "a/b/2.jpg", "a/b/c/3.jpg", "a/b/d/4.jpg", "a/e/5.jpg", "a/e/f/6.jpg",
+            "a/g/7.jpg", "a/h/8.jpg", "a/i/9.jpg", "a/i/j/10.jpg", "a/k/11.jpg", "a/l/12.jpg",
+        ]
+        .iter()
+        .map(|name| files(root, &[name]))
+        .collect();
+        for _ in 0..20 {
+            let batches: Vec<_> = list(root, &["a"]).into_iter().map(Result::unwrap).collect();
+            assert_eq!(batches, expected);
+        }
+    }
+
+    #[test]
+    fn lists_files_under_overlapping_inputs_once() {
+        let dir = tree(&["a/1.jpg", "a/b/2.jpg", "c/3.jpg"]);
+        let batches = list(dir.path(), &["a", "a/b", "a/b/2.jpg", "c"]);
+        let mut listed: Vec<_> = batches.into_iter().flat_map(Result::unwrap).collect();
+        listed.sort();
+        assert_eq!(listed, files(dir.path(), &["a/1.jpg", "a/b/2.jpg", "c/3.jpg"]));
+    }
+
+    #[test]
+    fn sends_patterns_that_match_nothing_as_errors() {
+        let dir = tree(&["a/1.jpg"]);
+        let batches = list(dir.path(), &["*.png", "a"]);
+        assert!(matches!(batches[0], Err(WalkError::NoMatches(_))));
+        assert_eq!(batches[1].as_ref().unwrap(), &files(dir.path(), &["a/1.jpg"]));
+    }
+
+    #[test]
+    fn stops_when_the_receiver_goes_away() {
+        let dir = tree(&["a/1.jpg", "b/2.jpg", "c/3.jpg"]);
+        let walker = Walker::new(&TraversalOptions::default()).unwrap();
+        let (tx, rx) = futures::channel::mpsc::channel(0);
+        drop(rx);
+        assert!(!walker.walk(dir.path(), None, &tx));
+    }
+}
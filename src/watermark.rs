@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// What `--since-last-run` remembers of each directory it has sorted from, kept in
+/// `last-run.json` in photosort's data directory.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Watermarks {
+    #[serde(skip)]
+    path: PathBuf,
+    dirs: BTreeMap<PathBuf, Watermark>,
+    /// The directories being sorted from this run, absolute.
+    #[serde(skip)]
+    inputs: Vec<PathBuf>,
+    /// How far this run's files move each directory's watermark, once it's saved.
+    #[serde(skip)]
+    newest: BTreeMap<PathBuf, Watermark>,
+}
+
+/// The newest modification time of the files listed in a directory, and which of them had it,
+/// so that a file modified in the same instant as the last ones but listed later isn't missed.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Watermark {
+    modified: SystemTime,
+    paths: Vec<PathBuf>,
+}
+
+impl Watermarks {
+    /// Loads the watermarks for a run sorting from `inputs`.
+    pub fn load(inputs: &[PathBuf]) -> Result<Watermarks> {
+        let path = config::data_dir()
+            .context("Could not find a data directory to remember the last run in")?
+            .join("last-run.json");
+        let mut watermarks = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("Invalid last run state {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Watermarks::default(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        watermarks.path = path;
+        watermarks.inputs =
+            inputs.iter().filter(|input| input.is_dir()).map(|dir| absolute(dir)).collect();
+        Ok(watermarks)
+    }
+
+    /// Drops the files in `files` under one of the input directories that were already listed
+    /// by an earlier run, going by their modification times, and notes how far the rest move
+    /// those directories' watermarks for [`Watermarks::save`]. Files named directly are left in.
+    pub fn filter(&mut self, files: Vec<PathBuf>) -> Vec<PathBuf> {
+        let mut unseen = Vec::new();
+        for file in files {
+            let path = absolute(&file);
+            let dir = self.inputs.iter().find(|dir| path.starts_with(dir));
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            let (dir, modified) = match (dir, modified) {
+                (Some(dir), Some(modified)) => (dir, modified),
+                // Left for sorting to report whatever's wrong with it.
+                _ => {
+                    unseen.push(file);
+                    continue;
+                }
+            };
+            if self.dirs.get(dir).is_some_and(|mark| mark.covers(&path, modified)) {
+                continue;
+            }
+            let mark = self
+                .newest
+                .entry(dir.clone())
+                .or_insert_with(|| Watermark { modified, paths: Vec::new() });
+            mark.advance(path, modified);
+            unseen.push(file);
+        }
+        unseen
+    }
+
+    /// Moves the watermarks past the files this run listed and saves them, by way of a temporary
+    /// file so an interrupted write can't lose the ones already there.
+    pub fn save(mut self) -> Result<()> {
+        for (dir, new) in std::mem::take(&mut self.newest) {
+            match self.dirs.get_mut(&dir) {
+                Some(mark) => {
+                    for path in new.paths {
+                        mark.advance(path, new.modified);
+                    }
+                }
+                None => {
+                    self.dirs.insert(dir, new);
+                }
+            }
+        }
+        let context = || format!("Failed to save the last run state to {}", self.path.display());
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).with_context(context)?;
+        }
+        let mut text = serde_json::to_string_pretty(&self).with_context(context)?;
+        text.push('\n');
+        let temp = self.path.with_extension("json.tmp");
+        std::fs::write(&temp, text).with_context(context)?;
+        std::fs::rename(&temp, &self.path).with_context(context)
+    }
+}
+
+impl Watermark {
+    /// Whether a file with this path and modification time was listed by an earlier run.
+    fn covers(&self, path: &Path, modified: SystemTime) -> bool {
+        let listed = || self.paths.iter().any(|p| p == path);
+        modified < self.modified || (modified == self.modified && listed())
+    }
+
+    fn advance(&mut self, path: PathBuf, modified: SystemTime) {
+        if modified > self.modified {
+            self.modified = modified;
+            self.paths.clear();
+        }
+        if modified == self.modified && !self.paths.contains(&path) {
+            self.paths.push(path);
+        }
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
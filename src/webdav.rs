@@ -0,0 +1,168 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::{Component, Path};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use crate::renamer::{run, RenameError, Renamer};
+use crate::report::Transfer;
+
+/// Uploads files to a WebDAV server, like a Nextcloud or ownCloud folder, with curl. Date
+/// directories are made with MKCOL as needed, and uploads are conditional PUTs that fail rather
+/// than replace a file unless `overwrite` is set. Credentials come from `~/.netrc`. Files are
+/// removed locally once uploaded, unless they're being copied.
+pub struct WebdavRenamer {
+    /// URL of the library's folder, without a trailing slash.
+    root: String,
+    copy: bool,
+    overwrite: bool,
+    /// curl's `--limit-rate` for uploads, in bytes per second.
+    limit_rate: Option<String>,
+    /// Collections known to exist, so each is only made once.
+    collections: Mutex<HashSet<String>>,
+}
+
+impl WebdavRenamer {
+    /// Returns None unless `library` is an `http://` or `https://` URL.
+    pub fn new(library: &Path, copy: bool, overwrite: bool, bwlimit: Option<u64>) -> Option<Self> {
+        let url = library.to_str()?;
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return None;
+        }
+        let root = url.trim_end_matches('/').to_string();
+        Some(Self {
+            root,
+            copy,
+            overwrite,
+            limit_rate: bwlimit.map(|rate| rate.to_string()),
+            collections: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// `dest`'s URL, with each part of its path under the library percent-encoded, along with
+    /// the URLs of the collections it's in, outermost first.
+    fn urls(&self, dest: &Path) -> (String, Vec<String>) {
+        let relative = dest.strip_prefix(&self.root).unwrap_or(dest);
+        let mut url = self.root.clone();
+        let mut collections = Vec::new();
+        for component in relative.components() {
+            if let Component::Normal(part) = component {
+                if url != self.root {
+                    collections.push(url.clone());
+                }
+                url.push('/');
+                url.push_str(&url_escape(&part.to_string_lossy()));
+            }
+        }
+        (url, collections)
+    }
+
+    /// Sends a `method` request to `url` with curl, returning the HTTP status.
+    async fn request(
+        &self,
+        method: &str,
+        url: &str,
+        extra: &[&OsStr],
+        dest: &Path,
+    ) -> Result<u16, RenameError> {
+        let null = if cfg!(windows) { "NUL" } else { "/dev/null" };
+        let mut args: Vec<&OsStr> = ["--silent", "--show-error", "--netrc-optional", "--request"]
+            .iter()
+            .map(OsStr::new)
+            .collect();
+        args.extend([method, "--output", null, "--write-out", "%{http_code}"].map(OsStr::new));
+        args.extend(extra);
+        args.push(OsStr::new(url));
+        let output = run("curl", &args, dest).await?;
+        String::from_utf8_lossy(&output.stdout).trim().parse().map_err(|_| {
+            let e = std::io::Error::other("curl didn't print an HTTP status");
+            RenameError::Io("curl".into(), dest.to_path_buf(), e)
+        })
+    }
+
+    /// Makes each collection in `collections` that isn't known to exist yet.
+    async fn make_collections(
+        &self,
+        collections: &[String],
+        dest: &Path,
+    ) -> Result<(), RenameError> {
+        for collection in collections {
+            if self.collections.lock().unwrap().contains(collection) {
+                continue;
+            }
+            match self.request("MKCOL", collection, &[], dest).await? {
+                // 405 is what MKCOL gets when the collection is already there.
+                201 | 405 => {}
+                status => {
+                    let url = collection.clone();
+                    return Err(RenameError::Http { method: "MKCOL", url, status });
+                }
+            }
+            self.collections.lock().unwrap().insert(collection.clone());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Renamer for WebdavRenamer {
+    async fn rename(&self, source: &Path, dest: &Path) -> Result<(), RenameError> {
+        let (url, collections) = self.urls(dest);
+        self.make_collections(&collections, dest).await?;
+        let mut extra = vec![OsStr::new("--upload-file"), source.as_os_str()];
+        if !self.overwrite {
+            extra.extend([OsStr::new("--header"), OsStr::new("If-None-Match: *")]);
+        }
+        if let Some(rate) = &self.limit_rate {
+            extra.extend([OsStr::new("--limit-rate"), OsStr::new(rate)]);
+        }
+        match self.request("PUT", &url, &extra, dest).await? {
+            200 | 201 | 204 => {}
+            412 => return Err(RenameError::DestExists(dest.to_path_buf())),
+            status => return Err(RenameError::Http { method: "PUT", url, status }),
+        }
+        if !self.copy {
+            tokio::fs::remove_file(source)
+                .await
+                .map_err(|e| RenameError::from_io(e, source, dest))?;
+        }
+        Ok(())
+    }
+
+    fn transfer(&self) -> Transfer {
+        if self.copy {
+            Transfer::Copy
+        } else {
+            Transfer::Move
+        }
+    }
+
+    async fn exists(&self, dest: &Path) -> Result<bool, RenameError> {
+        let (url, _) = self.urls(dest);
+        let depth = [OsStr::new("--header"), OsStr::new("Depth: 0")];
+        match self.request("PROPFIND", &url, &depth, dest).await? {
+            207 => Ok(true),
+            404 => Ok(false),
+            status => Err(RenameError::Http { method: "PROPFIND", url, status }),
+        }
+    }
+
+    fn local(&self) -> bool {
+        false
+    }
+}
+
+/// Percent-encodes one segment of a URL's path.
+fn url_escape(segment: &str) -> String {
+    let mut escaped = String::new();
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                escaped.push(byte as char)
+            }
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
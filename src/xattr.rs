@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io;
+
+/// Copies the extended attributes of `from`, like the Finder tags and comments macOS keeps, onto
+/// `to`. On Linux only the `user` namespace is copied; the others are the system's to set.
+/// Sources on filesystems without extended attributes have none to copy.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn copy(from: &File, to: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let names = match sys::list(from.as_raw_fd()) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        result => result?,
+    };
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        if cfg!(target_os = "linux") && !name.starts_with(b"user.") {
+            continue;
+        }
+        let name = std::ffi::CString::new(name).map_err(io::Error::other)?;
+        let value = sys::get(from.as_raw_fd(), &name)?;
+        sys::set(to.as_raw_fd(), &name, &value)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn copy(_from: &File, _to: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// The syscalls, which take extra position and option arguments on macOS.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use std::ffi::CStr;
+    use std::io;
+    use std::os::raw::{c_char, c_void};
+    use std::os::unix::io::RawFd;
+
+    /// Names of the attributes on `fd`, each followed by a NUL.
+    pub fn list(fd: RawFd) -> io::Result<Vec<u8>> {
+        read_sized(|buf, len| unsafe {
+            #[cfg(target_os = "linux")]
+            let n = libc::flistxattr(fd, buf as *mut c_char, len);
+            #[cfg(target_os = "macos")]
+            let n = libc::flistxattr(fd, buf as *mut c_char, len, 0);
+            n
+        })
+    }
+
+    pub fn get(fd: RawFd, name: &CStr) -> io::Result<Vec<u8>> {
+        read_sized(|buf, len| unsafe {
+            #[cfg(target_os = "linux")]
+            let n = libc::fgetxattr(fd, name.as_ptr(), buf as *mut c_void, len);
+            #[cfg(target_os = "macos")]
+            let n = libc::fgetxattr(fd, name.as_ptr(), buf as *mut c_void, len, 0, 0);
+            n
+        })
+    }
+
+    pub fn set(fd: RawFd, name: &CStr, value: &[u8]) -> io::Result<()> {
+        let value_ptr = value.as_ptr() as *const c_void;
+        // Safety: `name` is NUL-terminated and `value` is valid for its length.
+        #[cfg(target_os = "linux")]
+        let result = unsafe { libc::fsetxattr(fd, name.as_ptr(), value_ptr, value.len(), 0) };
+        #[cfg(target_os = "macos")]
+        let result = unsafe { libc::fsetxattr(fd, name.as_ptr(), value_ptr, value.len(), 0, 0) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Calls `read` once with no buffer to learn the size, then with a buffer that size. The
+    /// value can grow in between, in which case it's tried again. `read` is only ever handed a
+    /// null pointer with a length of 0, or a buffer valid for the length it's given.
+    fn read_sized(read: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let len = read(std::ptr::null_mut(), 0);
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buf = vec![0u8; len as usize];
+            let n = read(buf.as_mut_ptr(), buf.len());
+            if n >= 0 {
+                buf.truncate(n as usize);
+                return Ok(buf);
+            }
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ERANGE) {
+                return Err(e);
+            }
+        }
+    }
+}